@@ -0,0 +1,200 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Python module `cryptobox` exposing file-backed boxes and their sessions.
+// Every `CBoxError` is raised as a subclass of `cryptobox.CBoxError` chosen
+// by its `ErrorKind`, with `(message, code)` as arguments.
+//
+// Calls which may wait for a session lock or do I/O release the GIL, so
+// that the thread holding the lock can go on and release it.
+
+use cryptobox::store::file::FileStore;
+use cryptobox::{CBox, CBoxAnyError, CBoxSession, ErrorKind};
+use proteus::keys::PreKeyId;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+// Exceptions ///////////////////////////////////////////////////////////////
+
+create_exception!(cryptobox, CBoxError, PyException);
+create_exception!(cryptobox, StorageError, CBoxError);
+create_exception!(cryptobox, MalformedEnvelopeError, CBoxError);
+create_exception!(cryptobox, RemoteIdentityChangedError, CBoxError);
+create_exception!(cryptobox, InvalidSignatureError, CBoxError);
+create_exception!(cryptobox, CorruptedSessionError, CBoxError);
+create_exception!(cryptobox, DuplicateMessageError, CBoxError);
+create_exception!(cryptobox, TooDistantFutureError, CBoxError);
+create_exception!(cryptobox, OutdatedMessageError, CBoxError);
+create_exception!(cryptobox, UnknownPreKeyError, CBoxError);
+create_exception!(cryptobox, IdentityMismatchError, CBoxError);
+
+fn to_pyerr<E: Into<CBoxAnyError>>(e: E) -> PyErr {
+    let e    = e.into();
+    let args = (format!("{}", e), e.kind().code());
+    match e.kind() {
+        ErrorKind::StorageFailure        => StorageError::new_err(args),
+        ErrorKind::MalformedEnvelope     => MalformedEnvelopeError::new_err(args),
+        ErrorKind::RemoteIdentityChanged => RemoteIdentityChangedError::new_err(args),
+        ErrorKind::InvalidSignature      => InvalidSignatureError::new_err(args),
+        ErrorKind::CorruptedSession      => CorruptedSessionError::new_err(args),
+        ErrorKind::DuplicateMessage      => DuplicateMessageError::new_err(args),
+        ErrorKind::TooDistantFuture      => TooDistantFutureError::new_err(args),
+        ErrorKind::OutdatedMessage       => OutdatedMessageError::new_err(args),
+        ErrorKind::UnknownPreKey         => UnknownPreKeyError::new_err(args),
+        ErrorKind::IdentityMismatch      => IdentityMismatchError::new_err(args),
+        _                                => CBoxError::new_err(args)
+    }
+}
+
+// CBox /////////////////////////////////////////////////////////////////////
+
+#[pyclass(name = "CBox")]
+struct PyCBox {
+    cbox: CBox<FileStore>
+}
+
+#[pymethods]
+impl PyCBox {
+    /// Open (or create) the box stored in directory `path`.
+    #[staticmethod]
+    fn file_open(py: Python, path: &str) -> PyResult<PyCBox> {
+        py.allow_threads(|| CBox::file_open(path)).map(|b| PyCBox { cbox: b }).map_err(to_pyerr)
+    }
+
+    fn fingerprint(&self) -> String {
+        self.cbox.fingerprint()
+    }
+
+    /// Generate and store a prekey, returning its serialised bundle.
+    fn new_prekey(&self, py: Python, id: u16) -> PyResult<Py<PyBytes>> {
+        let bundle = py.allow_threads(|| self.cbox.new_prekey(PreKeyId::new(id))).map_err(to_pyerr)?;
+        let bytes  = bundle.serialise().map_err(to_pyerr)?;
+        Ok(PyBytes::new(py, &bytes).into())
+    }
+
+    fn session_from_prekey(&self, py: Python, sid: String, bundle: &[u8]) -> PyResult<PySession> {
+        py.allow_threads(|| self.cbox.session_from_prekey(sid, bundle)).map(PySession::new).map_err(to_pyerr)
+    }
+
+    /// Returns the new session and the decrypted message.
+    fn session_from_message(&self, py: Python, sid: String, envelope: &[u8]) -> PyResult<(PySession, Py<PyBytes>)> {
+        let (s, plain) = py.allow_threads(|| self.cbox.session_from_message(sid, envelope)).map_err(to_pyerr)?;
+        Ok((PySession::new(s), PyBytes::new(py, &plain).into()))
+    }
+
+    /// Returns `None` if there is no session `sid`.
+    fn session_load(&self, py: Python, sid: String) -> PyResult<Option<PySession>> {
+        py.allow_threads(|| self.cbox.session_load(sid)).map(|s| s.map(PySession::new)).map_err(to_pyerr)
+    }
+
+    fn session_save(&self, py: Python, mut session: PyRefMut<PySession>) -> PyResult<()> {
+        let s = session.get()?;
+        py.allow_threads(|| self.cbox.session_save(s)).map_err(to_pyerr)
+    }
+
+    fn session_delete(&self, py: Python, sid: &str) -> PyResult<()> {
+        py.allow_threads(|| self.cbox.session_delete(sid)).map_err(to_pyerr)
+    }
+
+    fn random_bytes(&self, py: Python, n: usize) -> Py<PyBytes> {
+        PyBytes::new(py, &self.cbox.random_bytes(n)).into()
+    }
+}
+
+// CBoxSession //////////////////////////////////////////////////////////////
+
+/// A session holds its box's lock for the session ID until it is closed
+/// (or garbage collected). Loading or creating a session with the same ID
+/// blocks until then: another thread waits for the holder to release it,
+/// while the same thread would wait forever. Use `close` or a `with` block.
+#[pyclass(name = "CBoxSession")]
+struct PySession {
+    session: Option<CBoxSession<FileStore>>
+}
+
+impl PySession {
+    fn new(s: CBoxSession<FileStore>) -> PySession {
+        PySession { session: Some(s) }
+    }
+
+    fn get(&mut self) -> PyResult<&mut CBoxSession<FileStore>> {
+        self.session.as_mut().ok_or_else(|| PyValueError::new_err("session is closed"))
+    }
+}
+
+#[pymethods]
+impl PySession {
+    #[getter]
+    fn identifier(&mut self) -> PyResult<String> {
+        Ok(self.get()?.identifier().to_string())
+    }
+
+    fn encrypt(&mut self, py: Python, plain: &[u8]) -> PyResult<Py<PyBytes>> {
+        let cipher = self.get()?.encrypt(plain).map_err(to_pyerr)?;
+        Ok(PyBytes::new(py, &cipher).into())
+    }
+
+    // Decrypting may load a prekey.
+    fn decrypt(&mut self, py: Python, cipher: &[u8]) -> PyResult<Py<PyBytes>> {
+        let s     = self.get()?;
+        let plain = py.allow_threads(|| s.decrypt(cipher)).map_err(to_pyerr)?;
+        Ok(PyBytes::new(py, &plain).into())
+    }
+
+    fn fingerprint_local(&mut self) -> PyResult<String> {
+        Ok(self.get()?.fingerprint_local())
+    }
+
+    fn fingerprint_remote(&mut self) -> PyResult<String> {
+        Ok(self.get()?.fingerprint_remote())
+    }
+
+    /// Release the session without saving it.
+    fn close(&mut self) {
+        self.session = None
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __exit__(&mut self, _ty: PyObject, _value: PyObject, _tb: PyObject) -> bool {
+        self.close();
+        false
+    }
+}
+
+// Module ///////////////////////////////////////////////////////////////////
+
+#[pymodule]
+#[pyo3(name = "cryptobox")]
+fn init(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyCBox>()?;
+    m.add_class::<PySession>()?;
+    m.add("CBoxError", py.get_type::<CBoxError>())?;
+    m.add("StorageError", py.get_type::<StorageError>())?;
+    m.add("MalformedEnvelopeError", py.get_type::<MalformedEnvelopeError>())?;
+    m.add("RemoteIdentityChangedError", py.get_type::<RemoteIdentityChangedError>())?;
+    m.add("InvalidSignatureError", py.get_type::<InvalidSignatureError>())?;
+    m.add("CorruptedSessionError", py.get_type::<CorruptedSessionError>())?;
+    m.add("DuplicateMessageError", py.get_type::<DuplicateMessageError>())?;
+    m.add("TooDistantFutureError", py.get_type::<TooDistantFutureError>())?;
+    m.add("OutdatedMessageError", py.get_type::<OutdatedMessageError>())?;
+    m.add("UnknownPreKeyError", py.get_type::<UnknownPreKeyError>())?;
+    m.add("IdentityMismatchError", py.get_type::<IdentityMismatchError>())?;
+    Ok(())
+}
@@ -0,0 +1,166 @@
+# Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+#
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU General Public License as published by
+# the Free Software Foundation, either version 3 of the License, or
+# (at your option) any later version.
+#
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU General Public License for more details.
+#
+# You should have received a copy of the GNU General Public License
+# along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+import threading
+
+import pytest
+
+import cryptobox
+
+
+@pytest.fixture
+def alice(tmp_path):
+    return cryptobox.CBox.file_open(str(tmp_path / "alice"))
+
+
+@pytest.fixture
+def bob(tmp_path):
+    return cryptobox.CBox.file_open(str(tmp_path / "bob"))
+
+
+def establish(alice, bob, prekey=1):
+    """Alice starts a session with one of bob's prekeys, bob answers."""
+    bundle = bob.new_prekey(prekey)
+    a = alice.session_from_prekey("bob", bundle)
+    m = a.encrypt(b"hello")
+    alice.session_save(a)
+
+    b, plain = bob.session_from_message("alice", m)
+    assert plain == b"hello"
+    r = b.encrypt(b"hi")
+    bob.session_save(b)
+
+    assert a.decrypt(r) == b"hi"
+    alice.session_save(a)
+    return a, b
+
+
+def test_fingerprints(tmp_path, alice, bob):
+    assert alice.fingerprint() != bob.fingerprint()
+    again = cryptobox.CBox.file_open(str(tmp_path / "alice"))
+    assert again.fingerprint() == alice.fingerprint()
+
+
+def test_session_fingerprints(alice, bob):
+    a, b = establish(alice, bob)
+    assert a.identifier == "bob"
+    assert a.fingerprint_local() == alice.fingerprint()
+    assert a.fingerprint_remote() == bob.fingerprint()
+    assert b.fingerprint_remote() == alice.fingerprint()
+
+
+def test_conversation(alice, bob):
+    a, b = establish(alice, bob)
+    for i in range(10):
+        msg = "message {}".format(i).encode()
+        assert b.decrypt(a.encrypt(msg)) == msg
+        assert a.decrypt(b.encrypt(msg)) == msg
+    alice.session_save(a)
+    bob.session_save(b)
+
+
+def test_session_persists(tmp_path, alice, bob):
+    a, b = establish(alice, bob)
+    m = a.encrypt(b"later")
+    alice.session_save(a)
+    bob.session_save(b)
+    a.close()
+    b.close()
+
+    bob = cryptobox.CBox.file_open(str(tmp_path / "bob"))
+    with bob.session_load("alice") as b:
+        assert b.decrypt(m) == b"later"
+        bob.session_save(b)
+    assert bob.session_load("nobody") is None
+
+
+def test_closed_session(alice, bob):
+    a, _ = establish(alice, bob)
+    a.close()
+    with pytest.raises(ValueError):
+        a.encrypt(b"x")
+
+
+def test_session_delete(alice, bob):
+    a, _ = establish(alice, bob)
+    a.close()
+    alice.session_delete("bob")
+    assert alice.session_load("bob") is None
+
+
+def test_duplicate_message(alice, bob):
+    a, b = establish(alice, bob)
+    m = a.encrypt(b"once")
+    assert b.decrypt(m) == b"once"
+    with pytest.raises(cryptobox.DuplicateMessageError) as e:
+        b.decrypt(m)
+    assert isinstance(e.value, cryptobox.CBoxError)
+    assert e.value.args[1] == 7
+
+
+def test_malformed_envelope(alice, bob):
+    _, b = establish(alice, bob)
+    with pytest.raises(cryptobox.MalformedEnvelopeError) as e:
+        b.decrypt(b"not an envelope")
+    assert e.value.args[1] == 3
+
+
+def test_prekey_consumed(alice, bob):
+    bundle = bob.new_prekey(1)
+    a = alice.session_from_prekey("bob", bundle)
+    m = a.encrypt(b"hello")
+    b, _ = bob.session_from_message("alice", m)
+    bob.session_save(b)
+    with pytest.raises(cryptobox.UnknownPreKeyError):
+        bob.session_from_message("alice-again", m)
+
+
+def test_prekey_id_range(bob):
+    with pytest.raises(OverflowError):
+        bob.new_prekey(70000)
+
+
+def test_random_bytes(alice):
+    r = alice.random_bytes(32)
+    assert isinstance(r, bytes)
+    assert len(r) == 32
+
+
+
+def test_session_lock_across_threads(alice, bob):
+    """A thread waiting for a session does not keep the thread holding it
+    from going on and releasing it."""
+    a, b = establish(alice, bob)
+    started = threading.Event()
+    result = []
+
+    def load():
+        started.set()
+        with alice.session_load("bob") as s:
+            result.append(s.encrypt(b"from worker"))
+            alice.session_save(s)
+
+    with a:
+        worker = threading.Thread(target=load, daemon=True)
+        worker.start()
+        assert started.wait(5)
+        worker.join(0.2)
+        assert worker.is_alive()
+        first = a.encrypt(b"from main")
+        alice.session_save(a)
+    worker.join(5)
+    assert not worker.is_alive()
+    assert b.decrypt(first) == b"from main"
+    assert b.decrypt(result[0]) == b"from worker"
@@ -0,0 +1,163 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Address of a remote client, usable as session ID.
+///
+/// The canonical encoding is `<user>_<client>` or, with a domain,
+/// `<user>@<domain>_<client>`. User and client IDs consist of ASCII
+/// letters, digits and `-`; a domain may additionally contain `.` and is
+/// lower-cased. Encoded addresses are thus valid file names.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SessionAddress {
+    encoded: String,
+    user:    usize, // end of the user ID
+    client:  usize  // start of the client ID
+}
+
+impl SessionAddress {
+    pub fn new(user: &str, client: &str) -> Result<SessionAddress, AddressError> {
+        try!(check_id(user, AddressError::InvalidUser));
+        try!(check_id(client, AddressError::InvalidClient));
+        Ok(SessionAddress {
+            encoded: format!("{}_{}", user, client),
+            user:    user.len(),
+            client:  user.len() + 1
+        })
+    }
+
+    pub fn with_domain(user: &str, domain: &str, client: &str) -> Result<SessionAddress, AddressError> {
+        try!(check_id(user, AddressError::InvalidUser));
+        try!(check_id(client, AddressError::InvalidClient));
+        let domain = domain.to_ascii_lowercase();
+        let valid  = !domain.is_empty()
+            && domain.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'.');
+        if !valid {
+            return Err(AddressError::InvalidDomain)
+        }
+        Ok(SessionAddress {
+            encoded: format!("{}@{}_{}", user, domain, client),
+            user:    user.len(),
+            client:  user.len() + domain.len() + 2
+        })
+    }
+
+    /// Parse a canonical encoding, as produced by `as_str`.
+    pub fn parse(s: &str) -> Result<SessionAddress, AddressError> {
+        let i = match s.rfind('_') {
+            Some(i) => i,
+            None    => return Err(AddressError::Malformed)
+        };
+        let (head, client) = (&s[.. i], &s[i + 1 ..]);
+        let a = match head.find('@') {
+            Some(j) => try!(SessionAddress::with_domain(&head[.. j], &head[j + 1 ..], client)),
+            None    => try!(SessionAddress::new(head, client))
+        };
+        // Reject anything `with_domain` would have normalised.
+        if a.encoded != s {
+            return Err(AddressError::Malformed)
+        }
+        Ok(a)
+    }
+
+    pub fn user(&self) -> &str {
+        &self.encoded[.. self.user]
+    }
+
+    pub fn client(&self) -> &str {
+        &self.encoded[self.client ..]
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        if self.client > self.user + 1 {
+            Some(&self.encoded[self.user + 1 .. self.client - 1])
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.encoded
+    }
+}
+
+fn check_id(s: &str, e: AddressError) -> Result<(), AddressError> {
+    if s.is_empty() || !s.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-') {
+        return Err(e)
+    }
+    Ok(())
+}
+
+impl AsRef<str> for SessionAddress {
+    fn as_ref(&self) -> &str {
+        &self.encoded
+    }
+}
+
+impl From<SessionAddress> for String {
+    fn from(a: SessionAddress) -> String {
+        a.encoded
+    }
+}
+
+impl<'a> From<&'a SessionAddress> for String {
+    fn from(a: &'a SessionAddress) -> String {
+        a.encoded.clone()
+    }
+}
+
+impl FromStr for SessionAddress {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<SessionAddress, AddressError> {
+        SessionAddress::parse(s)
+    }
+}
+
+impl fmt::Display for SessionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(&self.encoded)
+    }
+}
+
+// AddressError /////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressError {
+    InvalidUser,
+    InvalidClient,
+    InvalidDomain,
+    Malformed
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            AddressError::InvalidUser   => write!(f, "AddressError: invalid user id"),
+            AddressError::InvalidClient => write!(f, "AddressError: invalid client id"),
+            AddressError::InvalidDomain => write!(f, "AddressError: invalid domain"),
+            AddressError::Malformed     => write!(f, "AddressError: malformed session address")
+        }
+    }
+}
+
+impl Error for AddressError {
+    fn description(&self) -> &str {
+        "AddressError"
+    }
+}
@@ -0,0 +1,480 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
+use identity::Identity;
+use proteus;
+use proteus::keys::{self, IdentityKeyPair, PreKey, PreKeyBundle, PreKeyId};
+use proteus::message::{Envelope, Message};
+use proteus::session::{PreKeyStore, Session};
+use std::borrow::Cow;
+use std::error::Error;
+use std::mem;
+use std::sync::Arc;
+use store::{ConsumedPreKeys, Generation};
+use store::async_store::AsyncStore;
+use store::meta::SessionMeta;
+use super::{proteus_kind, CBoxAnyError, Clock, SystemClock};
+
+pub type CBoxFuture<'a, A> = BoxFuture<'a, Result<A, CBoxAnyError>>;
+
+// AsyncCBox ////////////////////////////////////////////////////////////////
+
+/// Asynchronous counterpart of `CBox` over an `AsyncStore`.
+///
+/// Unlike `CBox` it does not hold per-session locks.
+pub struct AsyncCBox<S> {
+    ident: Arc<IdentityKeyPair>,
+    store: Arc<S>,
+    clock: Arc<Clock>
+}
+
+impl<S> Clone for AsyncCBox<S> {
+    fn clone(&self) -> AsyncCBox<S> {
+        AsyncCBox {
+            ident: self.ident.clone(),
+            store: self.store.clone(),
+            clock: self.clock.clone()
+        }
+    }
+}
+
+impl<S: AsyncStore + Send + Sync + 'static> AsyncCBox<S> {
+    pub fn open(store: S) -> CBoxFuture<'static, AsyncCBox<S>> {
+        if !proteus::init() {
+            return future::err(CBoxAnyError::InitError).boxed()
+        }
+        let store = Arc::new(store);
+        let saver = store.clone();
+        store.load_identity()
+            .map_err(storage_error)
+            .and_then(move |ident| match ident {
+                Some(Identity::Sec(i)) => future::ok(i.into_owned()).boxed(),
+                Some(Identity::Pub(_)) => future::err(CBoxAnyError::IdentityError).boxed(),
+                None => {
+                    let ident = IdentityKeyPair::new();
+                    saver.save_identity(&Identity::Sec(Cow::Borrowed(&ident)))
+                        .map_err(storage_error)
+                        .map_ok(move |()| ident)
+                        .boxed()
+                }
+            })
+            .and_then(move |ident| {
+                let cbox = AsyncCBox {
+                    ident: Arc::new(ident),
+                    store: store,
+                    clock: Arc::new(SystemClock)
+                };
+                finish_commits(cbox.store.clone(), cbox.ident.clone(), None).map_ok(move |()| cbox)
+            })
+            .boxed()
+    }
+
+    /// Use `clock` instead of the system clock for session metadata.
+    /// Sessions obtained from the box afterwards use it, too.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> AsyncCBox<S> {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn session_from_prekey<I: Into<String>>(&self, sid: I, key: &[u8]) -> Result<AsyncCBoxSession<S>, CBoxAnyError> {
+        let prekey  = try!(PreKeyBundle::deserialise(key));
+        let session = try!(Session::init_from_prekey(self.ident.clone(), prekey).map_err(proteus_error));
+        let meta    = SessionMeta::new(self.clock.now());
+        Ok(AsyncCBoxSession::new(sid.into(), self.store.clone(), session, Generation::none(), meta, self.clock.clone()))
+    }
+
+    pub fn session_from_message<I: Into<String>>(&self, sid: I, envelope: &[u8]) -> CBoxFuture<'static, (AsyncCBoxSession<S>, Vec<u8>)> {
+        let sid = sid.into();
+        let env = match Envelope::deserialise(envelope) {
+            Ok(env) => env,
+            Err(e)  => return future::err(decrypt_error(sid, CBoxAnyError::from(e))).boxed()
+        };
+        let esid  = sid.clone();
+        let ident = self.ident.clone();
+        let store = self.store.clone();
+        let clock = self.clock.clone();
+        fetch_prekey(&*self.store, &env, &[])
+            .map(move |pk| -> Result<(AsyncCBoxSession<S>, Vec<u8>), CBoxAnyError> {
+                let mut st = PreFetched::new(try!(pk));
+                let (s, p) = try!(Session::init_from_message(ident, &mut st, &env).map_err(proteus_error));
+                let now = clock.now();
+                let mut meta = SessionMeta::new(now);
+                meta.record_decrypt(now);
+                let mut session = AsyncCBoxSession::new(sid, store, s, Generation::none(), meta, clock);
+                session.removed = st.removed;
+                Ok((session, p))
+            })
+            .map_err(move |e| decrypt_error(esid, e))
+            .boxed()
+    }
+
+    pub fn session_load<I: Into<String>>(&self, sid: I) -> CBoxFuture<'static, Option<AsyncCBoxSession<S>>> {
+        let sid   = sid.into();
+        let store = self.store.clone();
+        let ident = self.ident.clone();
+        let clock = self.clock.clone();
+        finish_commits(self.store.clone(), self.ident.clone(), Some(sid.clone()))
+            .and_then(move |()| store.load_session(ident, &sid).map_err(storage_error).map_ok(move |s| (store, sid, s)))
+            .and_then(move |(store, sid, s)| match s {
+                // Unreadable metadata is treated as missing, like `CBox` does.
+                Some((s, g)) => store.load_session_meta(&sid)
+                    .map(move |m| {
+                        let m = m.ok().and_then(|m| m).unwrap_or_default();
+                        Ok(Some(AsyncCBoxSession::new(sid, store, s, g, m, clock)))
+                    })
+                    .boxed(),
+                None => future::ok(None).boxed()
+            })
+            .boxed()
+    }
+
+    /// Persist the session and its metadata and delete the prekeys it
+    /// consumed.
+    ///
+    /// Fails with `CBoxAnyError::ConflictError` if the stored session has
+    /// been saved by someone else since `s` was loaded. A new session
+    /// replaces any stored session with the same ID. As with
+    /// `CBox::session_save`, metadata is written last and failing to write
+    /// it does not fail the save, and consumed prekeys which could not be
+    /// deleted stay pending on `s` until the next save or load.
+    pub fn session_save<'a>(&'a self, s: &'a mut AsyncCBoxSession<S>) -> CBoxFuture<'a, ()> {
+        let store   = self.store.clone();
+        let recover = if s.gen == Generation::none() {
+            finish_commits(self.store.clone(), self.ident.clone(), Some(s.sident.clone()))
+        } else {
+            future::ok(()).boxed()
+        };
+        recover
+            .and_then(move |()| delete_pending(store, s))
+            .and_then(move |(store, s)| commit(store, s))
+            .boxed()
+    }
+
+    pub fn session_delete<I: AsRef<str>>(&self, sid: I) -> CBoxFuture<'static, ()> {
+        self.store.delete_session(sid.as_ref()).map_err(storage_error).boxed()
+    }
+
+    /// Like `CBox::new_prekey`, the creation time is recorded before the
+    /// prekey is stored.
+    pub fn new_prekey(&self, id: PreKeyId) -> CBoxFuture<'static, PreKeyBundle> {
+        let pk     = PreKey::new(id);
+        let bundle = PreKeyBundle::new(self.ident.as_ref().public_key.clone(), &pk);
+        let store  = self.store.clone();
+        self.store.save_prekey_created(id, self.clock.now())
+            .and_then(move |()| store.add_prekey(&pk))
+            .map_err(storage_error)
+            .map_ok(move |()| bundle)
+            .boxed()
+    }
+
+    pub fn identity(&self) -> &IdentityKeyPair {
+        self.ident.as_ref()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        self.ident.as_ref().public_key.fingerprint()
+    }
+
+    pub fn random_bytes(&self, n: usize) -> Vec<u8> {
+        keys::rand_bytes(n)
+    }
+}
+
+// AsyncCBoxSession /////////////////////////////////////////////////////////
+
+pub struct AsyncCBoxSession<S> {
+    sident:  String,
+    store:   Arc<S>,
+    session: Session<Arc<IdentityKeyPair>>,
+    removed: Vec<PreKeyId>,
+    pending: Vec<PreKeyId>,
+    gen:     Generation,
+    meta:    SessionMeta,
+    clock:   Arc<Clock>
+}
+
+impl<S: AsyncStore + Send + Sync + 'static> AsyncCBoxSession<S> {
+    fn new(sid: String, store: Arc<S>, s: Session<Arc<IdentityKeyPair>>, g: Generation, meta: SessionMeta, clock: Arc<Clock>) -> AsyncCBoxSession<S> {
+        AsyncCBoxSession {
+            sident:  sid,
+            store:   store,
+            session: s,
+            removed: Vec::new(),
+            pending: Vec::new(),
+            gen:     g,
+            meta:    meta,
+            clock:   clock
+        }
+    }
+
+    pub fn encrypt(&mut self, plain: &[u8]) -> Result<Vec<u8>, CBoxAnyError> {
+        let cipher = try!(self.session.encrypt(plain).and_then(|m| m.serialise()));
+        self.meta.record_encrypt(self.clock.now());
+        Ok(cipher)
+    }
+
+    pub fn decrypt<'a>(&'a mut self, cipher: &[u8]) -> CBoxFuture<'a, Vec<u8>> {
+        let sid = self.sident.clone();
+        let env = match Envelope::deserialise(cipher) {
+            Ok(env) => env,
+            Err(e)  => return future::err(decrypt_error(sid, CBoxAnyError::from(e))).boxed()
+        };
+        let consumed = if self.pending.is_empty() {
+            self.removed.clone()
+        } else {
+            self.removed.iter().chain(self.pending.iter()).cloned().collect()
+        };
+        fetch_prekey(&*self.store, &env, &consumed)
+            .map(move |pk| -> Result<Vec<u8>, CBoxAnyError> {
+                let mut st = PreFetched::new(try!(pk));
+                let plain  = try!(self.session.decrypt(&mut st, &env).map_err(proteus_error));
+                self.removed.extend(st.removed);
+                self.meta.record_decrypt(self.clock.now());
+                Ok(plain)
+            })
+            .map_err(move |e| decrypt_error(sid, e))
+            .boxed()
+    }
+
+    pub fn removed_prekeys(&mut self) -> Vec<PreKeyId> {
+        mem::replace(&mut self.removed, Vec::new())
+    }
+
+    pub fn identifier(&self) -> &str {
+        &self.sident
+    }
+
+    pub fn fingerprint_local(&self) -> String {
+        self.session.local_identity().fingerprint()
+    }
+
+    pub fn fingerprint_remote(&self) -> String {
+        self.session.remote_identity().fingerprint()
+    }
+
+    /// Metadata as of the last operation. It is persisted on save.
+    pub fn meta(&self) -> &SessionMeta {
+        &self.meta
+    }
+
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.meta.labels.get(key).map(|v| v.as_str())
+    }
+
+    pub fn set_label<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.meta.labels.insert(key.into(), value.into());
+    }
+
+    pub fn remove_label(&mut self, key: &str) -> Option<String> {
+        self.meta.labels.remove(key)
+    }
+}
+
+// Commit ///////////////////////////////////////////////////////////////////
+
+// The asynchronous counterpart of `Store::commit_session` followed by the
+// bookkeeping of `CBox::session_save`.
+
+// Delete the prekeys consumed by an earlier, saved generation of `s`.
+fn delete_pending<'a, S>(store: Arc<S>, s: &'a mut AsyncCBoxSession<S>) -> CBoxFuture<'a, (Arc<S>, &'a mut AsyncCBoxSession<S>)>
+    where S: AsyncStore + Send + Sync + 'static
+{
+    if s.pending.is_empty() {
+        return future::ok((store, s)).boxed()
+    }
+    let pending = mem::replace(&mut s.pending, Vec::new());
+    delete_prekeys(store.clone(), pending)
+        .then(move |(left, e)| match e {
+            Some(e) => {
+                s.pending = left;
+                future::err(e).boxed()
+            }
+            None => forget_consumed(&*store, &s.sident, true)
+                .then(move |()| future::ok((store, s)))
+                .boxed()
+        })
+        .boxed()
+}
+
+fn commit<'a, S>(store: Arc<S>, s: &'a mut AsyncCBoxSession<S>) -> CBoxFuture<'a, ()>
+    where S: AsyncStore + Send + Sync + 'static
+{
+    let record = if s.removed.is_empty() {
+        future::ok(()).boxed()
+    } else {
+        let c = ConsumedPreKeys {
+            session:  s.sident.clone(),
+            expected: s.gen,
+            prekeys:  s.removed.clone()
+        };
+        store.save_consumed_prekeys(&c).map_err(storage_error).boxed()
+    };
+    record
+        .and_then(move |()| {
+            let save = store.save_session(&s.sident, &s.session, s.gen).map_err(storage_error);
+            save.map_ok(move |g| (store, s, g))
+        })
+        .and_then(move |(store, s, g)| match g {
+            Some(g) => {
+                s.gen = g;
+                let removed = mem::replace(&mut s.removed, Vec::new());
+                let recorded = !removed.is_empty();
+                delete_prekeys(store.clone(), removed)
+                    .then(move |(left, e)| {
+                        // The record stays until all prekeys are gone.
+                        let forget = forget_consumed(&*store, &s.sident, recorded && left.is_empty());
+                        let meta   = store.save_session_meta(&s.sident, &s.meta);
+                        s.pending  = left;
+                        forget.then(move |()| meta).map(move |_| match e {
+                            Some(e) => Err(e),
+                            None    => Ok(())
+                        })
+                    })
+                    .boxed()
+            }
+            None => {
+                let forget = forget_consumed(&*store, &s.sident, !s.removed.is_empty());
+                forget.map(|()| Err(CBoxAnyError::ConflictError)).boxed()
+            }
+        })
+        .boxed()
+}
+
+// Removing a record is best-effort, a stale one only causes its prekeys to
+// be deleted again.
+fn forget_consumed<S: AsyncStore>(store: &S, sid: &str, recorded: bool) -> BoxFuture<'static, ()> {
+    if recorded {
+        store.delete_consumed_prekeys(sid).map(|_| ()).boxed()
+    } else {
+        future::ready(()).boxed()
+    }
+}
+
+// Delete all of `ids`, returning those which could not be deleted and the
+// first error.
+fn delete_prekeys<S>(store: Arc<S>, ids: Vec<PreKeyId>) -> BoxFuture<'static, (Vec<PreKeyId>, Option<CBoxAnyError>)>
+    where S: AsyncStore + Send + Sync + 'static
+{
+    let deletes = ids.into_iter().map(|p| store.delete_prekey(p).map(move |r| (p, r)));
+    future::join_all(deletes)
+        .map(|results| {
+            let mut left  = Vec::new();
+            let mut error = None;
+            for (p, r) in results {
+                if let Err(e) = r {
+                    left.push(p);
+                    error = error.or(Some(storage_error(e)))
+                }
+            }
+            (left, error)
+        })
+        .boxed()
+}
+
+// Resolve the records of interrupted commits like `CBox` does, either of
+// all sessions or of `sid`.
+fn finish_commits<S>(store: Arc<S>, ident: Arc<IdentityKeyPair>, sid: Option<String>) -> CBoxFuture<'static, ()>
+    where S: AsyncStore + Send + Sync + 'static
+{
+    let records = store.consumed_prekeys().map_err(storage_error);
+    records
+        .and_then(move |records| {
+            let commits = records.into_iter()
+                .filter(|c| sid.as_ref().map(|i| *i == c.session).unwrap_or(true))
+                .map(|c| finish_commit(store.clone(), ident.clone(), c));
+            future::try_join_all(commits).map_ok(|_| ())
+        })
+        .boxed()
+}
+
+fn finish_commit<S>(store: Arc<S>, ident: Arc<IdentityKeyPair>, c: ConsumedPreKeys) -> CBoxFuture<'static, ()>
+    where S: AsyncStore + Send + Sync + 'static
+{
+    let load = store.load_session(ident, &c.session);
+    load.then(move |r| {
+            let stored = match r {
+                Ok(Some((_, g))) => g,
+                Ok(None)         => Generation::none(),
+                Err(_)           => return future::ok(()).boxed()
+            };
+            let prekeys = if stored != c.expected { c.prekeys.clone() } else { Vec::new() };
+            let deletes = prekeys.into_iter().map(|p| store.delete_prekey(p));
+            future::try_join_all(deletes)
+                .and_then(move |_| store.delete_consumed_prekeys(&c.session))
+                .map_err(storage_error)
+                .boxed()
+        })
+        .boxed()
+}
+
+// PreFetched ///////////////////////////////////////////////////////////////
+
+// proteus looks up prekeys synchronously, so the prekey an envelope refers
+// to (if any) is loaded before the envelope is handed over.
+struct PreFetched {
+    prekey:  Option<PreKey>,
+    removed: Vec<PreKeyId>
+}
+
+impl PreFetched {
+    fn new(k: Option<PreKey>) -> PreFetched {
+        PreFetched {
+            prekey:  k,
+            removed: Vec::new()
+        }
+    }
+}
+
+impl PreKeyStore for PreFetched {
+    type Error = CBoxAnyError;
+
+    fn prekey(&mut self, id: PreKeyId) -> Result<Option<PreKey>, CBoxAnyError> {
+        if self.prekey.as_ref().map(|k| k.key_id == id).unwrap_or(false) {
+            Ok(self.prekey.take())
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn remove(&mut self, id: PreKeyId) -> Result<(), CBoxAnyError> {
+        self.removed.push(id);
+        Ok(())
+    }
+}
+
+fn fetch_prekey<S: AsyncStore>(store: &S, env: &Envelope, removed: &[PreKeyId]) -> CBoxFuture<'static, Option<PreKey>> {
+    match *env.message() {
+        Message::Keyed(ref m) if !removed.contains(&m.prekey_id) =>
+            store.load_prekey(m.prekey_id).map_err(storage_error).boxed(),
+        _ => future::ok(None).boxed()
+    }
+}
+
+fn storage_error<E: Error + Send + Sync + 'static>(e: E) -> CBoxAnyError {
+    CBoxAnyError::StorageError(Box::new(e))
+}
+
+fn decrypt_error(sid: String, e: CBoxAnyError) -> CBoxAnyError {
+    CBoxAnyError::DecryptError(sid, Box::new(e))
+}
+
+fn proteus_error(e: proteus::session::Error<CBoxAnyError>) -> CBoxAnyError {
+    let kind = proteus_kind(&e);
+    match e {
+        proteus::session::Error::PreKeyStoreError(e) => e,
+        e => CBoxAnyError::ProteusError(kind, Box::new(e))
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate cryptobox;
+
+use cryptobox::CBox;
+use cryptobox::daemon::server::{self, Server};
+use std::env;
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+const USAGE: &'static str = "\
+Usage: cryptobox-daemon <dir> <socket>
+
+Serves the box in <dir> on the Unix socket <socket>.
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        let _ = io::stderr().write_all(USAGE.as_bytes());
+        process::exit(2)
+    }
+    if let Err(e) = run(Path::new(&args[0]), Path::new(&args[1])) {
+        let _ = writeln!(io::stderr(), "cryptobox-daemon: {}", e);
+        process::exit(1)
+    }
+}
+
+fn run(dir: &Path, socket: &Path) -> Result<(), Box<Error>> {
+    if !dir.is_dir() {
+        return Err(From::from(format!("{} is not a directory", dir.display())))
+    }
+    let cbox     = try!(CBox::file_open(dir));
+    let listener = try!(server::bind(socket));
+    try!(Server::new(cbox).serve(listener));
+    Ok(())
+}
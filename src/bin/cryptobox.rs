@@ -0,0 +1,238 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate cryptobox;
+extern crate proteus;
+
+use cryptobox::{CBox, Identity};
+use cryptobox::store::Store;
+use cryptobox::store::file::FileStore;
+use proteus::keys::{MAX_PREKEY_ID, PreKeyId};
+use std::env;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+
+const USAGE: &'static str = "\
+Usage: cryptobox <dir> <command> [<args>]
+
+Commands:
+    info                          Print store version and local fingerprint
+    check                         Report broken entries of the store
+    sessions                      List sessions with remote fingerprints
+    prekeys                       List prekey IDs
+    new-prekeys <first> <count>   Generate prekeys, print bundles as hex.
+                                  IDs must be below the last resort ID.
+    encrypt <session> [<bundle>]  Encrypt stdin to stdout. A hex prekey
+                                  bundle creates the session if needed.
+    decrypt <session>             Decrypt stdin to stdout, creating the
+                                  session from a prekey message if needed
+    delete-session <session>      Delete a session
+";
+
+type CmdResult = Result<(), Box<Error>>;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        usage()
+    }
+    let dir  = Path::new(&args[0]);
+    let rest = &args[2..];
+    let res  = match args[1].as_str() {
+        "info"           if rest.is_empty() => info(dir),
+        "check"          if rest.is_empty() => check(dir),
+        "sessions"       if rest.is_empty() => sessions(dir),
+        "prekeys"        if rest.is_empty() => prekeys(dir),
+        "new-prekeys"    if rest.len() == 2 => new_prekeys(dir, &rest[0], &rest[1]),
+        "encrypt"        if rest.len() == 1 => encrypt(dir, &rest[0], None),
+        "encrypt"        if rest.len() == 2 => encrypt(dir, &rest[0], Some(&rest[1])),
+        "decrypt"        if rest.len() == 1 => decrypt(dir, &rest[0]),
+        "delete-session" if rest.len() == 1 => delete_session(dir, &rest[0]),
+        _                                   => usage()
+    };
+    if let Err(e) = res {
+        let _ = writeln!(io::stderr(), "cryptobox: {}", e);
+        process::exit(1)
+    }
+}
+
+fn usage() -> ! {
+    let _ = io::stderr().write_all(USAGE.as_bytes());
+    process::exit(2)
+}
+
+// Commands /////////////////////////////////////////////////////////////////
+
+// `info`, `check`, `sessions` and `prekeys` only read the store; they
+// neither migrate it nor create an identity.
+
+fn info(dir: &Path) -> CmdResult {
+    let store = try!(FileStore::inspect(dir));
+    match try!(store.version()) {
+        Some(v) => println!("version:     {}", v),
+        None    => println!("version:     none")
+    }
+    match try!(store.load_identity()) {
+        Some(Identity::Sec(i)) => println!("fingerprint: {}", i.public_key.fingerprint()),
+        Some(Identity::Pub(i)) => println!("fingerprint: {}", i.fingerprint()),
+        None                   => println!("fingerprint: none")
+    }
+    Ok(())
+}
+
+fn check(dir: &Path) -> CmdResult {
+    let store    = try!(FileStore::inspect(dir));
+    let problems = try!(store.check(None));
+    for p in &problems {
+        println!("{:?} {}", p.kind, p.path.display())
+    }
+    if !problems.is_empty() {
+        return Err(From::from(format!("{} problem(s) found", problems.len())))
+    }
+    Ok(())
+}
+
+fn sessions(dir: &Path) -> CmdResult {
+    let store = try!(inspect(dir));
+    let ident = match try!(store.load_identity()) {
+        Some(Identity::Sec(i)) => i.into_owned(),
+        Some(Identity::Pub(_)) => return Err(From::from("no local identity keypair")),
+        None                   => return Ok(())
+    };
+    let mut ids = try!(store.session_ids());
+    ids.sort();
+    for id in ids {
+        match store.load_session(&ident, &id) {
+            Ok(Some((s, _))) => println!("{} {}", id, s.remote_identity().fingerprint()),
+            Ok(None)         => (),
+            Err(e)           => println!("{} <error: {}>", id, e)
+        }
+    }
+    Ok(())
+}
+
+fn prekeys(dir: &Path) -> CmdResult {
+    let store = try!(inspect(dir));
+    let mut ids: Vec<u16> = try!(store.prekey_ids()).iter().map(|k| k.value()).collect();
+    ids.sort();
+    for id in ids {
+        println!("{}", id)
+    }
+    Ok(())
+}
+
+fn new_prekeys(dir: &Path, first: &str, count: &str) -> CmdResult {
+    let first: u16 = try!(first.parse());
+    let count: u16 = try!(count.parse());
+    // The last resort prekey is never handed out this way.
+    if first as u32 + count as u32 > MAX_PREKEY_ID.value() as u32 {
+        return Err(From::from(format!("prekey IDs must be below {}", MAX_PREKEY_ID.value())))
+    }
+    let cbox = try!(open(dir));
+    for id in first .. first + count {
+        let bundle = try!(cbox.new_prekey(PreKeyId::new(id)));
+        println!("{} {}", id, to_hex(&try!(bundle.serialise())))
+    }
+    Ok(())
+}
+
+fn encrypt(dir: &Path, sid: &str, bundle: Option<&String>) -> CmdResult {
+    let cbox = try!(open(dir));
+    let mut session = match try!(cbox.session_load(sid.to_string())) {
+        Some(s) => s,
+        None    => match bundle {
+            Some(b) => try!(cbox.session_from_prekey(sid.to_string(), &try!(from_hex(b)))),
+            None    => return Err(From::from(format!("no session {}", sid)))
+        }
+    };
+    let cipher = try!(session.encrypt(&try!(read_stdin())));
+    try!(cbox.session_save(&mut session));
+    try!(io::stdout().write_all(&cipher));
+    Ok(())
+}
+
+fn decrypt(dir: &Path, sid: &str) -> CmdResult {
+    let cbox  = try!(open(dir));
+    let input = try!(read_stdin());
+    let plain = match try!(cbox.session_load(sid.to_string())) {
+        Some(mut s) => {
+            let p = try!(s.decrypt(&input));
+            try!(cbox.session_save(&mut s));
+            p
+        }
+        None => {
+            let (mut s, p) = try!(cbox.session_from_message(sid.to_string(), &input));
+            try!(cbox.session_save(&mut s));
+            p
+        }
+    };
+    try!(io::stdout().write_all(&plain));
+    Ok(())
+}
+
+fn delete_session(dir: &Path, sid: &str) -> CmdResult {
+    let cbox = try!(open(dir));
+    try!(cbox.session_delete(sid));
+    Ok(())
+}
+
+// Helpers //////////////////////////////////////////////////////////////////
+
+// Refuse to create a box in a directory which does not exist, which most
+// likely is a typo.
+fn open(dir: &Path) -> Result<CBox<FileStore>, Box<Error>> {
+    if !dir.is_dir() {
+        return Err(From::from(format!("{} is not a directory", dir.display())))
+    }
+    Ok(try!(CBox::file_open(dir)))
+}
+
+// Open a store for reading. Its layout is only known for the current
+// version; older stores are migrated by the first writing command.
+fn inspect(dir: &Path) -> Result<FileStore, Box<Error>> {
+    let store = try!(FileStore::inspect(dir));
+    if !try!(store.is_current()) {
+        return Err(From::from(format!("{} is not a store of the current version", dir.display())))
+    }
+    Ok(store)
+}
+
+fn read_stdin() -> io::Result<Vec<u8>> {
+    let mut b = Vec::new();
+    try!(io::stdin().read_to_end(&mut b));
+    Ok(b)
+}
+
+fn to_hex(b: &[u8]) -> String {
+    let mut s = String::with_capacity(b.len() * 2);
+    for x in b {
+        s.push_str(&format!("{:02x}", x))
+    }
+    s
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, Box<Error>> {
+    let s = s.trim();
+    if s.len() % 2 != 0 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(From::from("invalid hex string"))
+    }
+    let mut b = Vec::with_capacity(s.len() / 2);
+    for i in 0 .. s.len() / 2 {
+        b.push(try!(u8::from_str_radix(&s[2 * i .. 2 * i + 2], 16)))
+    }
+    Ok(b)
+}
@@ -0,0 +1,156 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use proteus::{DecodeError, EncodeError};
+use proteus::keys::PreKeyId;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use super::protocol::{self, Request, Response};
+
+/// Connection to a daemon. Every call is one request/response round trip.
+#[derive(Debug)]
+pub struct Client {
+    stream: UnixStream
+}
+
+impl Client {
+    pub fn connect(path: &Path) -> ClientResult<Client> {
+        Ok(Client { stream: try!(UnixStream::connect(path)) })
+    }
+
+    pub fn fingerprint(&mut self) -> ClientResult<String> {
+        self.call(&Request::Fingerprint).and_then(text)
+    }
+
+    /// Generate and store a prekey, returning the serialised bundle.
+    pub fn new_prekey(&mut self, id: PreKeyId) -> ClientResult<Vec<u8>> {
+        self.call(&Request::NewPreKey(id.value())).and_then(bytes)
+    }
+
+    pub fn session_from_prekey(&mut self, sid: &str, bundle: &[u8]) -> ClientResult<()> {
+        self.call(&Request::SessionFromPreKey(sid.to_string(), bundle.to_vec())).and_then(done)
+    }
+
+    pub fn encrypt(&mut self, sid: &str, plain: &[u8]) -> ClientResult<Vec<u8>> {
+        self.call(&Request::Encrypt(sid.to_string(), plain.to_vec())).and_then(bytes)
+    }
+
+    pub fn decrypt(&mut self, sid: &str, cipher: &[u8]) -> ClientResult<Vec<u8>> {
+        self.call(&Request::Decrypt(sid.to_string(), cipher.to_vec())).and_then(bytes)
+    }
+
+    pub fn session_delete(&mut self, sid: &str) -> ClientResult<()> {
+        self.call(&Request::SessionDelete(sid.to_string())).and_then(done)
+    }
+
+    pub fn fingerprint_remote(&mut self, sid: &str) -> ClientResult<String> {
+        self.call(&Request::RemoteFingerprint(sid.to_string())).and_then(text)
+    }
+
+    fn call(&mut self, req: &Request) -> ClientResult<Response> {
+        try!(protocol::write_frame(&mut self.stream, &try!(req.serialise())));
+        match try!(protocol::read_frame(&mut self.stream)) {
+            Some(b) => match try!(Response::deserialise(&b)) {
+                Response::Error(c, m) => Err(ClientError::Remote(c, m)),
+                r                     => Ok(r)
+            },
+            None => Err(ClientError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")))
+        }
+    }
+}
+
+fn done(r: Response) -> ClientResult<()> {
+    match r {
+        Response::Done => Ok(()),
+        _              => Err(ClientError::UnexpectedResponse)
+    }
+}
+
+fn bytes(r: Response) -> ClientResult<Vec<u8>> {
+    match r {
+        Response::Bytes(b) => Ok(b),
+        _                  => Err(ClientError::UnexpectedResponse)
+    }
+}
+
+fn text(r: Response) -> ClientResult<String> {
+    match r {
+        Response::Text(t) => Ok(t),
+        _                 => Err(ClientError::UnexpectedResponse)
+    }
+}
+
+// ClientError //////////////////////////////////////////////////////////////
+
+pub type ClientResult<A> = Result<A, ClientError>;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Decode(DecodeError),
+    Encode(EncodeError),
+    /// Error reported by the daemon, with its `ErrorKind::code` or
+    /// `protocol::SESSION_NOT_FOUND`.
+    Remote(u16, String),
+    UnexpectedResponse
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ClientError::Io(ref e)        => write!(f, "ClientError: I/O error: {}", e),
+            ClientError::Decode(ref e)    => write!(f, "ClientError: Decode error: {}", e),
+            ClientError::Encode(ref e)    => write!(f, "ClientError: Encode error: {}", e),
+            ClientError::Remote(c, ref m) => write!(f, "ClientError: Daemon error {}: {}", c, m),
+            ClientError::UnexpectedResponse => write!(f, "ClientError: Unexpected response")
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn description(&self) -> &str {
+        "ClientError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ClientError::Io(ref e)     => Some(e),
+            ClientError::Decode(ref e) => Some(e),
+            ClientError::Encode(ref e) => Some(e),
+            _                          => None
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
+
+impl From<DecodeError> for ClientError {
+    fn from(e: DecodeError) -> ClientError {
+        ClientError::Decode(e)
+    }
+}
+
+impl From<EncodeError> for ClientError {
+    fn from(e: EncodeError) -> ClientError {
+        ClientError::Encode(e)
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Wire protocol between daemon and clients. The format is documented on
+// `Request`, `Response` and `write_frame`.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use cbor::{Decoder, Encoder, Config};
+use cbor::skip::Skip;
+use proteus::{DecodeError, EncodeError};
+use std::io::{self, Read, Write, ErrorKind};
+
+/// Largest frame accepted in either direction.
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// Error code of requests for a session which is not stored. It lies in
+/// the range of `ErrorKind::code`, which never returns it.
+pub const SESSION_NOT_FOUND: u16 = 2;
+
+/// A request from client to daemon.
+///
+/// Requests and responses are serialised as a CBOR u8 tag followed by a
+/// CBOR object with u8 keys, of which unknown ones are skipped:
+///
+/// ```text
+/// 0  session ID (text)
+/// 1  data (bytes)
+/// 2  prekey ID (u16)
+/// 3  error code (u16, see `ErrorKind::code` and `SESSION_NOT_FOUND`)
+/// 4  message (text)
+/// ```
+///
+/// Each variant lists its tag, its keys and the response on success.
+/// Failures are answered with `Response::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Tag 1, no keys, answered with `Text` holding the box's fingerprint.
+    Fingerprint,
+    /// Tag 2, key 2, answered with `Bytes` holding the prekey bundle.
+    NewPreKey(u16),
+    /// Tag 3, keys 0 and 1 (a prekey bundle), answered with `Done`.
+    SessionFromPreKey(String, Vec<u8>),
+    /// Tag 4, keys 0 and 1 (plain text), answered with `Bytes` holding the
+    /// cipher text. Fails with code 2 (session not found) if the session
+    /// does not exist.
+    Encrypt(String, Vec<u8>),
+    /// Tag 5, keys 0 and 1 (cipher text), answered with `Bytes` holding
+    /// the plain text. A prekey message for a session which does not
+    /// exist creates it; any other message fails with code 2.
+    Decrypt(String, Vec<u8>),
+    /// Tag 6, key 0, answered with `Done`.
+    SessionDelete(String),
+    /// Tag 7, key 0, answered with `Text` holding the fingerprint of the
+    /// session's remote identity or failing with code 2.
+    RemoteFingerprint(String)
+}
+
+/// A response from daemon to client. See `Request` for the encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Tag 0, no keys.
+    Done,
+    /// Tag 1, key 1.
+    Bytes(Vec<u8>),
+    /// Tag 2, key 4.
+    Text(String),
+    /// Tag 3, keys 3 and 4.
+    Error(u16, String)
+}
+
+// Union of all fields a message may have.
+#[derive(Default)]
+struct Fields {
+    sid:     Option<String>,
+    data:    Option<Vec<u8>>,
+    prekey:  Option<u16>,
+    code:    Option<u16>,
+    message: Option<String>
+}
+
+impl Fields {
+    fn encode<W: io::Write>(&self, e: &mut Encoder<W>) -> Result<(), EncodeError> {
+        let n = self.sid.is_some() as usize
+              + self.data.is_some() as usize
+              + self.prekey.is_some() as usize
+              + self.code.is_some() as usize
+              + self.message.is_some() as usize;
+        try!(e.object(n));
+        if let Some(ref s) = self.sid     { try!(e.u8(0)); try!(e.text(s)) }
+        if let Some(ref b) = self.data    { try!(e.u8(1)); try!(e.bytes(b)) }
+        if let Some(p)     = self.prekey  { try!(e.u8(2)); try!(e.u16(p)) }
+        if let Some(c)     = self.code    { try!(e.u8(3)); try!(e.u16(c)) }
+        if let Some(ref m) = self.message { try!(e.u8(4)); try!(e.text(m)) }
+        Ok(())
+    }
+
+    fn decode<R: io::Read + Skip>(d: &mut Decoder<R>) -> Result<Fields, DecodeError> {
+        let n = try!(d.object());
+        let mut f = Fields::default();
+        for _ in 0 .. n {
+            match try!(d.u8()) {
+                0 =>
+                    if f.sid.is_some() {
+                        return Err(DecodeError::DuplicateField("session id"))
+                    } else {
+                        f.sid = Some(try!(d.text()))
+                    },
+                1 =>
+                    if f.data.is_some() {
+                        return Err(DecodeError::DuplicateField("data"))
+                    } else {
+                        f.data = Some(try!(d.bytes()))
+                    },
+                2 =>
+                    if f.prekey.is_some() {
+                        return Err(DecodeError::DuplicateField("prekey id"))
+                    } else {
+                        f.prekey = Some(try!(d.u16()))
+                    },
+                3 =>
+                    if f.code.is_some() {
+                        return Err(DecodeError::DuplicateField("error code"))
+                    } else {
+                        f.code = Some(try!(d.u16()))
+                    },
+                4 =>
+                    if f.message.is_some() {
+                        return Err(DecodeError::DuplicateField("message"))
+                    } else {
+                        f.message = Some(try!(d.text()))
+                    },
+                _ => try!(d.skip())
+            }
+        }
+        Ok(f)
+    }
+
+    fn sid(&mut self) -> Result<String, DecodeError> {
+        self.sid.take().ok_or(DecodeError::MissingField("session id"))
+    }
+
+    fn data(&mut self) -> Result<Vec<u8>, DecodeError> {
+        self.data.take().ok_or(DecodeError::MissingField("data"))
+    }
+
+    fn message(&mut self) -> Result<String, DecodeError> {
+        self.message.take().ok_or(DecodeError::MissingField("message"))
+    }
+}
+
+impl Request {
+    pub fn serialise(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut f = Fields::default();
+        let tag = match *self {
+            Request::Fingerprint                     => 1,
+            Request::NewPreKey(id)                   => { f.prekey = Some(id); 2 }
+            Request::SessionFromPreKey(ref s, ref b) => { f.sid = Some(s.clone()); f.data = Some(b.clone()); 3 }
+            Request::Encrypt(ref s, ref b)           => { f.sid = Some(s.clone()); f.data = Some(b.clone()); 4 }
+            Request::Decrypt(ref s, ref b)           => { f.sid = Some(s.clone()); f.data = Some(b.clone()); 5 }
+            Request::SessionDelete(ref s)            => { f.sid = Some(s.clone()); 6 }
+            Request::RemoteFingerprint(ref s)        => { f.sid = Some(s.clone()); 7 }
+        };
+        let mut e = Encoder::new(io::Cursor::new(Vec::new()));
+        try!(e.u8(tag));
+        try!(f.encode(&mut e));
+        Ok(e.into_writer().into_inner())
+    }
+
+    pub fn deserialise(b: &[u8]) -> Result<Request, DecodeError> {
+        let mut d = Decoder::new(Config::default(), io::Cursor::new(b));
+        let tag   = try!(d.u8());
+        let mut f = try!(Fields::decode(&mut d));
+        match tag {
+            1 => Ok(Request::Fingerprint),
+            2 => Ok(Request::NewPreKey(try!(f.prekey.ok_or(DecodeError::MissingField("prekey id"))))),
+            3 => Ok(Request::SessionFromPreKey(try!(f.sid()), try!(f.data()))),
+            4 => Ok(Request::Encrypt(try!(f.sid()), try!(f.data()))),
+            5 => Ok(Request::Decrypt(try!(f.sid()), try!(f.data()))),
+            6 => Ok(Request::SessionDelete(try!(f.sid()))),
+            7 => Ok(Request::RemoteFingerprint(try!(f.sid()))),
+            t => Err(DecodeError::InvalidType(t, "unknown request"))
+        }
+    }
+}
+
+impl Response {
+    pub fn serialise(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut f = Fields::default();
+        let tag = match *self {
+            Response::Done            => 0,
+            Response::Bytes(ref b)    => { f.data = Some(b.clone()); 1 }
+            Response::Text(ref t)     => { f.message = Some(t.clone()); 2 }
+            Response::Error(c, ref m) => { f.code = Some(c); f.message = Some(m.clone()); 3 }
+        };
+        let mut e = Encoder::new(io::Cursor::new(Vec::new()));
+        try!(e.u8(tag));
+        try!(f.encode(&mut e));
+        Ok(e.into_writer().into_inner())
+    }
+
+    pub fn deserialise(b: &[u8]) -> Result<Response, DecodeError> {
+        let mut d = Decoder::new(Config::default(), io::Cursor::new(b));
+        let tag   = try!(d.u8());
+        let mut f = try!(Fields::decode(&mut d));
+        match tag {
+            0 => Ok(Response::Done),
+            1 => Ok(Response::Bytes(try!(f.data()))),
+            2 => Ok(Response::Text(try!(f.message()))),
+            3 => {
+                let c = try!(f.code.ok_or(DecodeError::MissingField("error code")));
+                Ok(Response::Error(c, f.message.unwrap_or_default()))
+            }
+            t => Err(DecodeError::InvalidType(t, "unknown response"))
+        }
+    }
+}
+
+// Frames ///////////////////////////////////////////////////////////////////
+
+/// Write `b` as one frame: its length as u32 (big-endian) followed by the
+/// bytes themselves.
+///
+/// A client sends one request frame at a time and reads exactly one
+/// response frame for it before sending the next. A connection may carry
+/// any number of requests. Encrypt and decrypt requests load the session
+/// and save it again before responding.
+pub fn write_frame<W: Write>(w: &mut W, b: &[u8]) -> io::Result<()> {
+    if b.len() > MAX_FRAME {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "frame too large"))
+    }
+    try!(w.write_u32::<BigEndian>(b.len() as u32));
+    try!(w.write_all(b));
+    w.flush()
+}
+
+/// Read the next frame, returning `None` if the stream ends before it.
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut n   = 0;
+    while n < len.len() {
+        match r.read(&mut len[n ..]) {
+            Ok(0) if n == 0 => return Ok(None),
+            Ok(0)           => return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated frame")),
+            Ok(k)           => n += k,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e)          => return Err(e)
+        }
+    }
+    let len = try!(io::Cursor::new(&len[..]).read_u32::<BigEndian>()) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(ErrorKind::InvalidData, "frame too large"))
+    }
+    let mut b = vec![0; len];
+    try!(r.read_exact(&mut b));
+    Ok(Some(b))
+}
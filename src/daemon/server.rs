@@ -0,0 +1,169 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use libc;
+use proteus::keys::PreKeyId;
+use proteus::message::{Envelope, Message};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use store::Store;
+use super::protocol::{self, Request, Response};
+use {CBox, CBoxError, ErrorKind};
+
+/// Bind a socket at `path`, accessible to the current user only.
+///
+/// A stale socket file left by a daemon which is no longer running is
+/// replaced. Fails if another daemon is listening at `path`.
+///
+/// The socket is created with a umask which denies access to anybody
+/// else, so it is never connectable by others, not even briefly. As the
+/// umask is process-wide, files created by other threads in the meantime
+/// are private to the user, too.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "daemon already running"))
+        }
+        try!(fs::remove_file(path))
+    }
+    let old = unsafe { libc::umask(0o077) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old) };
+    let listener = try!(listener);
+    try!(fs::set_permissions(path, fs::Permissions::from_mode(0o600)));
+    Ok(listener)
+}
+
+/// Serves a `CBox` to clients, one thread per connection.
+///
+/// Requests for the same session are serialised by the box's session
+/// locks, requests for different sessions run concurrently.
+pub struct Server<S> {
+    cbox: CBox<S>
+}
+
+impl<S> Clone for Server<S> {
+    fn clone(&self) -> Server<S> {
+        Server { cbox: self.cbox.clone() }
+    }
+}
+
+impl<S: Store + Send + Sync + 'static> Server<S> {
+    pub fn new(cbox: CBox<S>) -> Server<S> {
+        Server { cbox: cbox }
+    }
+
+    /// Accept connections until the listener fails.
+    pub fn serve(&self, listener: UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s)  => s,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            };
+            let server = self.clone();
+            thread::spawn(move || {
+                let _ = server.connection(stream);
+            });
+        }
+        Ok(())
+    }
+
+    fn connection(&self, mut stream: UnixStream) -> io::Result<()> {
+        while let Some(frame) = try!(protocol::read_frame(&mut stream)) {
+            let res = match Request::deserialise(&frame) {
+                Ok(req) => self.handle(req),
+                Err(e)  => Response::Error(ErrorKind::Other.code(), format!("invalid request: {}", e))
+            };
+            let out = try!(res.serialise().map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+            try!(protocol::write_frame(&mut stream, &out))
+        }
+        Ok(())
+    }
+
+    pub fn handle(&self, req: Request) -> Response {
+        match self.execute(req) {
+            Ok(r)  => r,
+            Err(e) => Response::Error(e.kind().code(), format!("{}", e))
+        }
+    }
+
+    fn execute(&self, req: Request) -> Result<Response, CBoxError<S>> {
+        match req {
+            Request::Fingerprint =>
+                Ok(Response::Text(self.cbox.fingerprint())),
+            Request::NewPreKey(id) => {
+                let bundle = try!(self.cbox.new_prekey(PreKeyId::new(id)));
+                Ok(Response::Bytes(try!(bundle.serialise())))
+            }
+            Request::SessionFromPreKey(sid, bundle) => {
+                let mut s = try!(self.cbox.session_from_prekey(sid, &bundle));
+                try!(self.cbox.session_save(&mut s));
+                Ok(Response::Done)
+            }
+            Request::Encrypt(sid, plain) => match try!(self.cbox.session_load(sid.clone())) {
+                Some(mut s) => {
+                    let c = try!(s.encrypt(&plain));
+                    try!(self.cbox.session_save(&mut s));
+                    Ok(Response::Bytes(c))
+                }
+                None => Ok(not_found(&sid))
+            },
+            Request::Decrypt(sid, cipher) => match try!(self.cbox.session_load(sid.clone())) {
+                Some(mut s) => {
+                    let p = try!(s.decrypt(&cipher));
+                    try!(self.cbox.session_save(&mut s));
+                    Ok(Response::Bytes(p))
+                }
+                None => {
+                    if !is_prekey_message(&cipher) {
+                        return Ok(not_found(&sid))
+                    }
+                    let (mut s, p) = try!(self.cbox.session_from_message(sid, &cipher));
+                    try!(self.cbox.session_save(&mut s));
+                    Ok(Response::Bytes(p))
+                }
+            },
+            Request::SessionDelete(sid) => {
+                try!(self.cbox.session_delete(&sid));
+                Ok(Response::Done)
+            }
+            Request::RemoteFingerprint(sid) => match try!(self.cbox.session_load(sid.clone())) {
+                Some(s) => Ok(Response::Text(s.fingerprint_remote())),
+                None    => Ok(not_found(&sid))
+            }
+        }
+    }
+}
+
+// Malformed envelopes count as prekey messages so that decrypting them
+// reports them as malformed.
+fn is_prekey_message(cipher: &[u8]) -> bool {
+    match Envelope::deserialise(cipher) {
+        Ok(env) => match *env.message() {
+            Message::Keyed(_) => true,
+            Message::Plain(_) => false
+        },
+        Err(_) => true
+    }
+}
+
+fn not_found(sid: &str) -> Response {
+    Response::Error(protocol::SESSION_NOT_FOUND, format!("session not found: {}", sid))
+}
@@ -0,0 +1,89 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use lock::SessionLocks;
+use std::time::Duration;
+use store::Store;
+use {CBox, CBoxError};
+
+/// What `CBox::session_gc` does with expired sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcMode {
+    /// Only report them.
+    DryRun,
+    /// Delete them and their metadata with `Store::delete_session`.
+    Delete,
+    /// Move them out of the store with `Store::archive_session`.
+    Archive
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GcReport {
+    /// Sessions unused for longer than the maximum age, which have been
+    /// deleted or archived unless in `GcMode::DryRun`.
+    pub expired: Vec<String>,
+    /// Sessions without recorded creation or use time. These are not
+    /// collected. Unless in `GcMode::DryRun` their creation time is set
+    /// to the current time, so they expire `max_age` later.
+    pub unknown: Vec<String>
+}
+
+impl<S: Store> CBox<S> {
+    /// Collect sessions whose last use, according to their metadata, lies
+    /// more than `max_age` before the box's clock.
+    ///
+    /// Sessions without any recorded time, e.g. from stores written before
+    /// metadata existed, are stamped with the current time as their
+    /// creation time (except in `GcMode::DryRun`) and thus collected once
+    /// they stay unused for `max_age` from now on.
+    ///
+    /// Sessions which are currently loaded through this box or one of its
+    /// clones are in use and skipped. Other boxes over the same store, in
+    /// this or other processes, are not taken into account.
+    pub fn session_gc(&self, max_age: Duration, mode: GcMode) -> Result<GcReport, CBoxError<S>> {
+        let now    = self.clock.now();
+        let cutoff = now.saturating_sub(max_age.as_secs());
+        let mut report = GcReport::default();
+        let mut ids = try!(self.store.session_ids().map_err(CBoxError::StorageError));
+        ids.sort();
+        for id in ids {
+            let _lock = match SessionLocks::try_acquire(&self.locks, &id) {
+                Some(l) => l,
+                None    => continue
+            };
+            let meta = try!(self.store.load_session_meta(&id).map_err(CBoxError::StorageError));
+            match meta.as_ref().and_then(|m| m.last_used()) {
+                Some(t) if t < cutoff => (),
+                Some(_)               => continue,
+                None                  => {
+                    if mode != GcMode::DryRun {
+                        let mut m = meta.unwrap_or_default();
+                        m.created = Some(now);
+                        try!(self.store.save_session_meta(&id, &m).map_err(CBoxError::StorageError))
+                    }
+                    report.unknown.push(id);
+                    continue
+                }
+            }
+            match mode {
+                GcMode::DryRun  => (),
+                GcMode::Delete  => try!(self.store.delete_session(&id).map_err(CBoxError::StorageError)),
+                GcMode::Archive => try!(self.store.archive_session(&id).map_err(CBoxError::StorageError))
+            }
+            report.expired.push(id)
+        }
+        Ok(report)
+    }
+}
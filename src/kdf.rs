@@ -0,0 +1,122 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use proteus::keys::IdentityKeyPair;
+use sodiumoxide::crypto::auth::hmacsha256::{self, Key, Tag};
+use sodiumoxide::crypto::hash::sha256;
+
+/// Largest output length supported by HKDF-SHA256.
+pub const MAX_LENGTH: usize = 255 * 32;
+
+// Context prefix of all keys derived from an identity. Any change
+// invalidates every key previously handed out to applications.
+const CONTEXT: &'static str = "cryptobox application key v1";
+
+/// Derives `len` bytes labelled with `label` from the identity secret.
+///
+/// The input keying material is the Diffie-Hellman value of the identity
+/// key pair with itself, which only the holder of the secret key can
+/// compute. Returns `None` if `len` is 0 or exceeds `MAX_LENGTH`.
+pub fn derive(ident: &IdentityKeyPair, label: &str, len: usize) -> Option<Vec<u8>> {
+    if len == 0 || len > MAX_LENGTH {
+        return None
+    }
+    let ikm = ident.secret_key.shared_secret(&ident.public_key.public_key);
+    let sha256::Digest(salt) = sha256::hash(CONTEXT.as_bytes());
+    let prk = extract(&salt, &ikm);
+
+    // Length-prefix the label so that no label is a prefix of another.
+    let mut info = Vec::with_capacity(CONTEXT.len() + label.len() + 8);
+    info.extend_from_slice(CONTEXT.as_bytes());
+    info.write_u64::<BigEndian>(label.len() as u64).unwrap();
+    info.extend_from_slice(label.as_bytes());
+
+    Some(expand(&prk, &info, len))
+}
+
+fn extract(salt: &[u8; 32], ikm: &[u8]) -> Key {
+    let Tag(prk) = hmacsha256::authenticate(ikm, &Key(*salt));
+    Key(prk)
+}
+
+fn expand(prk: &Key, info: &[u8], len: usize) -> Vec<u8> {
+    let mut okm   = Vec::with_capacity(len);
+    let mut block = Vec::new();
+    let mut i     = 1u8;
+    while okm.len() < len {
+        let mut input = block;
+        input.extend_from_slice(info);
+        input.push(i);
+        let Tag(t) = hmacsha256::authenticate(&input, prk);
+        let n = ::std::cmp::min(t.len(), len - okm.len());
+        okm.extend_from_slice(&t[.. n]);
+        block = t.to_vec();
+        i = i.wrapping_add(1);
+    }
+    okm
+}
+
+// HKDF-SHA256 test vectors of RFC 5869, appendix A. `extract` only takes
+// salts of up to 32 bytes, which HMAC pads with zeros anyway, so case 2
+// (80 byte salt) only checks `expand`.
+#[cfg(test)]
+mod tests {
+    use super::{expand, extract};
+    use sodiumoxide::crypto::auth::hmacsha256::Key;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0 .. s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i .. 2 * i + 2], 16).unwrap()).collect()
+    }
+
+    fn salt(s: &[u8]) -> [u8; 32] {
+        let mut salt = [0; 32];
+        salt[.. s.len()].copy_from_slice(s);
+        salt
+    }
+
+    fn key(s: &str) -> Key {
+        Key::from_slice(&hex(s)).unwrap()
+    }
+
+    fn check_extract(salt: &[u8; 32], ikm: &[u8], prk: &str) {
+        assert_eq!(&extract(salt, ikm).0[..], &hex(prk)[..])
+    }
+
+    #[test]
+    fn rfc5869_case_1() {
+        let ikm  = [0x0b; 22];
+        let salt = salt(&hex("000102030405060708090a0b0c"));
+        let info = hex("f0f1f2f3f4f5f6f7f8f9");
+        let prk  = "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5";
+        check_extract(&salt, &ikm, prk);
+        assert_eq!(expand(&key(prk), &info, 42), hex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"))
+    }
+
+    #[test]
+    fn rfc5869_case_2() {
+        let info: Vec<u8> = (0xb0 .. 0x100).map(|x| x as u8).collect();
+        let prk = key("06a6b88c5853361a06104c9ceb35b45cef760014904671014a193f40c15fc244");
+        assert_eq!(expand(&prk, &info, 82), hex("b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c59045a99cac7827271cb41c65e590e09da3275600c2f09b8367793a9aca3db71cc30c58179ec3e87c14c01d5c1f3434f1d87"))
+    }
+
+    #[test]
+    fn rfc5869_case_3() {
+        let ikm = [0x0b; 22];
+        let prk = "19ef24a32c717b167f33a91d6f648bdf96596776afdb6377ac434c1c293ccb04";
+        check_extract(&[0; 32], &ikm, prk);
+        assert_eq!(expand(&key(prk), &[], 42), hex("8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8"))
+    }
+}
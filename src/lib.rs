@@ -13,34 +13,86 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+#[cfg(feature = "async")]
+extern crate blocking;
 extern crate byteorder;
 extern crate cbor;
+#[cfg(feature = "async")]
+extern crate futures;
+extern crate libc;
 extern crate proteus;
+#[cfg(feature = "sled")]
+extern crate sled;
+extern crate sodiumoxide;
 
+pub mod address;
 pub mod store;
+#[cfg(feature = "async")]
+pub mod async_box;
+#[cfg(all(unix, feature = "daemon"))]
+pub mod daemon;
+pub mod otr;
+pub mod payload;
+mod clock;
+mod gc;
 mod identity;
+mod kdf;
+mod lock;
+mod rotation;
+mod sealed;
 
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::error::Error;
 use std::ffi::OsStr;
 use std::fmt;
 use std::mem;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
+pub use address::{AddressError, SessionAddress};
+pub use clock::{Clock, SystemClock};
+pub use gc::{GcMode, GcReport};
 pub use identity::{Identity, IdentityMode};
-use proteus::keys::{self, IdentityKeyPair, PreKey, PreKeyBundle, PreKeyId};
+pub use kdf::MAX_LENGTH as MAX_DERIVED_KEY_LENGTH;
+pub use rotation::{PreKeyRotation, RotationError};
+use lock::{SessionLock, SessionLocks};
+use proteus::keys::{self, IdentityKey, IdentityKeyPair, PreKey, PreKeyBundle, PreKeyId};
 use proteus::message::Envelope;
 use proteus::session::{PreKeyStore, Session};
 use proteus::{DecodeError, EncodeError};
-use store::Store;
+use sealed::SealedBox;
+use store::{Commit, Generation, Store};
+use store::meta::SessionMeta;
 use store::file::{FileStore, FileStoreError};
 
 // CBox /////////////////////////////////////////////////////////////////////
 
+/// A `CBox` is `Send` and `Sync` if its store is and clones of a box share
+/// identity, store and session locks.
+///
+/// At most one `CBoxSession` per session ID exists at any time across all
+/// clones of a box: `session_from_prekey`, `session_from_message` and
+/// `session_load` block while another `CBoxSession` with the same ID is
+/// alive. Consequently a thread must drop a session before it obtains the
+/// same session again.
 pub struct CBox<S> {
     ident: Arc<IdentityKeyPair>,
-    store: Arc<S>
+    store: Arc<S>,
+    locks: Arc<SessionLocks>,
+    clock: Arc<Clock>
+}
+
+impl<S> Clone for CBox<S> {
+    fn clone(&self) -> CBox<S> {
+        CBox {
+            ident: self.ident.clone(),
+            store: self.store.clone(),
+            locks: self.locks.clone(),
+            clock: self.clock.clone()
+        }
+    }
 }
 
 impl CBox<FileStore> {
@@ -49,19 +101,7 @@ impl CBox<FileStore> {
             return Err(CBoxError::InitError)
         }
         let store = try!(FileStore::new(Path::new(path.as_ref())));
-        let ident = match try!(store.load_identity()) {
-            Some(Identity::Sec(i)) => i.into_owned(),
-            Some(Identity::Pub(_)) => return Err(CBoxError::IdentityError),
-            None => {
-                let ident = IdentityKeyPair::new();
-                try!(store.save_identity(&Identity::Sec(Cow::Borrowed(&ident))));
-                ident
-            }
-        };
-        Ok(CBox {
-            ident: Arc::new(ident),
-            store: Arc::new(store)
-        })
+        CBox::from_store(store)
     }
 
     pub fn file_open_with<P: AsRef<OsStr>>(path: P, ident: IdentityKeyPair, mode: IdentityMode) -> Result<CBox<FileStore>, CBoxError<FileStore>> {
@@ -93,62 +133,316 @@ impl CBox<FileStore> {
                     try!(store.save_identity(&Identity::Sec(Cow::Borrowed(&ident))))
             }
         }
-        Ok(CBox {
+        let cbox = CBox {
             ident: Arc::new(ident),
-            store: Arc::new(store)
-        })
+            store: Arc::new(store),
+            locks: Arc::new(SessionLocks::new()),
+            clock: Arc::new(SystemClock)
+        };
+        try!(cbox.finish_commits(None));
+        Ok(cbox)
     }
 }
 
 impl<S: Store> CBox<S> {
-    pub fn session_from_prekey(&self, sid: String, key: &[u8]) -> Result<CBoxSession<S>, CBoxError<S>> {
+    /// Open a box over an arbitrary store, creating a new identity if the
+    /// store does not contain one yet.
+    pub fn from_store(store: S) -> Result<CBox<S>, CBoxError<S>> {
+        if !proteus::init() {
+            return Err(CBoxError::InitError)
+        }
+        let ident = match try!(store.load_identity().map_err(CBoxError::StorageError)) {
+            Some(Identity::Sec(i)) => i.into_owned(),
+            Some(Identity::Pub(_)) => return Err(CBoxError::IdentityError),
+            None => {
+                let ident = IdentityKeyPair::new();
+                try!(store.save_identity(&Identity::Sec(Cow::Borrowed(&ident))).map_err(CBoxError::StorageError));
+                ident
+            }
+        };
+        let cbox = CBox {
+            ident: Arc::new(ident),
+            store: Arc::new(store),
+            locks: Arc::new(SessionLocks::new()),
+            clock: Arc::new(SystemClock)
+        };
+        try!(cbox.finish_commits(None));
+        Ok(cbox)
+    }
+
+    /// Use `clock` instead of the system clock for session metadata and
+    /// expiry. Sessions obtained from the box afterwards use it, too.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> CBox<S> {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn session_from_prekey<I: Into<String>>(&self, sid: I, key: &[u8]) -> Result<CBoxSession<S>, CBoxError<S>> {
+        let sid     = sid.into();
         let prekey  = try!(PreKeyBundle::deserialise(key));
+        let lock    = SessionLocks::acquire(&self.locks, &sid);
         let session = CBoxSession {
             sident:  sid,
             store:   ReadOnlyStore::new(self.store.clone()),
-            session: Session::init_from_prekey(self.ident.clone(), prekey)?
+            session: Session::init_from_prekey(self.ident.clone(), prekey)?,
+            gen:     Generation::none(),
+            meta:    SessionMeta::new(self.clock.now()),
+            clock:   self.clock.clone(),
+            _lock:   Some(lock)
         };
         Ok(session)
     }
 
-    pub fn session_from_message(&self, sid: String, envelope: &[u8]) -> Result<(CBoxSession<S>, Vec<u8>), CBoxError<S>> {
-        let env    = try!(Envelope::deserialise(envelope));
+    pub fn session_from_message<I: Into<String>>(&self, sid: I, envelope: &[u8]) -> Result<(CBoxSession<S>, Vec<u8>), DecryptError<S>> {
+        let sid = sid.into();
+        let env = match Envelope::deserialise(envelope) {
+            Ok(env) => env,
+            Err(e)  => return Err(DecryptError::new(sid, CBoxError::from(e)))
+        };
+        let lock   = SessionLocks::acquire(&self.locks, &sid);
         let mut st = ReadOnlyStore::new(self.store.clone());
-        let (s, p) = try!(Session::init_from_message(self.ident.clone(), &mut st, &env));
-        Ok((CBoxSession { sident: sid, store: st, session: s }, p))
-    }
-
-    pub fn session_load(&self, sid: String) -> Result<Option<CBoxSession<S>>, CBoxError<S>> {
-        match self.store.load_session(self.ident.clone(), &sid) {
-            Ok(None)    => Ok(None),
-            Ok(Some(s)) => Ok(Some(CBoxSession {
-                sident:  sid,
-                store:   ReadOnlyStore::new(self.store.clone()),
-                session: s
-            })),
-            Err(e) => Err(CBoxError::StorageError(e))
+        match Session::init_from_message(self.ident.clone(), &mut st, &env) {
+            Ok((s, p)) => {
+                let now = self.clock.now();
+                let mut meta = SessionMeta::new(now);
+                meta.record_decrypt(now);
+                let session = CBoxSession {
+                    sident:  sid,
+                    store:   st,
+                    session: s,
+                    gen:     Generation::none(),
+                    meta:    meta,
+                    clock:   self.clock.clone(),
+                    _lock:   Some(lock)
+                };
+                Ok((session, p))
+            }
+            Err(e)     => Err(DecryptError::new(sid, CBoxError::from(e)))
         }
     }
 
+    pub fn session_load<I: Into<String>>(&self, sid: I) -> Result<Option<CBoxSession<S>>, CBoxError<S>> {
+        let sid  = sid.into();
+        let lock = SessionLocks::acquire(&self.locks, &sid);
+        try!(self.finish_commits(Some(&sid)));
+        let (s, g) = match try!(self.store.load_session(self.ident.clone(), &sid).map_err(CBoxError::StorageError)) {
+            Some(x) => x,
+            None    => return Ok(None)
+        };
+        // Metadata is only written on a best-effort basis, so it is read
+        // the same way. Unreadable metadata is replaced on the next save.
+        let meta = self.store.load_session_meta(&sid).ok().and_then(|m| m);
+        Ok(Some(CBoxSession {
+            sident:  sid,
+            store:   ReadOnlyStore::new(self.store.clone()),
+            session: s,
+            gen:     g,
+            meta:    meta.unwrap_or_default(),
+            clock:   self.clock.clone(),
+            _lock:   Some(lock)
+        }))
+    }
+
+    /// Persist the session and its metadata and delete the prekeys it
+    /// consumed.
+    ///
+    /// Fails with `CBoxError::ConflictError` if the stored session has been
+    /// saved by someone else since `s` was loaded. The session then needs
+    /// to be reloaded and the operation retried. A session obtained from
+    /// `session_from_prekey` or `session_from_message` replaces any stored
+    /// session with the same ID.
+    ///
+    /// Metadata is written after the session has been committed and only
+    /// on a best-effort basis: if writing it fails, the save still
+    /// succeeds and the previously stored metadata stays in place.
+    ///
+    /// If the session has been saved but deleting a consumed prekey fails,
+    /// the error is returned and the prekey stays pending on `s`. It is
+    /// deleted by the next `session_save` of `s` or, should `s` be dropped,
+    /// when the session is loaded again.
     pub fn session_save(&self, s: &mut CBoxSession<S>) -> Result<(), CBoxError<S>> {
-        try!(self.store.save_session(&s.sident, &s.session).map_err(CBoxError::StorageError));
-        for p in s.removed_prekeys() {
+        if s.gen == Generation::none() {
+            try!(self.finish_commits(Some(&s.sident)))
+        }
+        try!(self.delete_pending(s));
+        let r = self.store.commit_session(&s.sident, &s.session, s.gen, &s.store.removed);
+        match try!(r.map_err(CBoxError::StorageError)) {
+            Commit::Saved(g) => {
+                s.gen = g;
+                s.store.removed.clear();
+                let _ = self.store.save_session_meta(&s.sident, &s.meta);
+                Ok(())
+            }
+            Commit::Pending(g, left, e) => {
+                s.gen = g;
+                s.store.removed.clear();
+                s.store.pending = left;
+                let _ = self.store.save_session_meta(&s.sident, &s.meta);
+                Err(CBoxError::StorageError(e))
+            }
+            Commit::Conflict => Err(CBoxError::ConflictError)
+        }
+    }
+
+    // Prekeys consumed by an already saved generation of `s`. The record
+    // of the commit is only removed once all of them are gone.
+    fn delete_pending(&self, s: &mut CBoxSession<S>) -> Result<(), CBoxError<S>> {
+        if s.store.pending.is_empty() {
+            return Ok(())
+        }
+        while let Some(&p) = s.store.pending.first() {
             try!(self.store.delete_prekey(p).map_err(CBoxError::StorageError));
+            s.store.pending.remove(0);
         }
+        let _ = self.store.delete_consumed_prekeys(&s.sident);
         Ok(())
     }
 
-    pub fn session_delete(&self, sid: &str) -> Result<(), CBoxError<S>> {
-        try!(self.store.delete_session(sid).map_err(CBoxError::StorageError));
+    // Resolve the records of commits which have been interrupted before all
+    // consumed prekeys were deleted, either of all sessions or of `sid`.
+    // The caller must hold the lock of `sid`. Records of sessions which
+    // can not be loaded are left alone.
+    fn finish_commits(&self, sid: Option<&str>) -> Result<(), CBoxError<S>> {
+        let records = try!(self.store.consumed_prekeys().map_err(CBoxError::StorageError));
+        for c in records {
+            if sid.map(|i| i != c.session).unwrap_or(false) {
+                continue
+            }
+            let stored = match self.store.load_session(self.ident.clone(), &c.session) {
+                Ok(Some((_, g))) => g,
+                Ok(None)         => Generation::none(),
+                Err(_)           => continue
+            };
+            if stored != c.expected {
+                for p in &c.prekeys {
+                    try!(self.store.delete_prekey(*p).map_err(CBoxError::StorageError))
+                }
+            }
+            try!(self.store.delete_consumed_prekeys(&c.session).map_err(CBoxError::StorageError))
+        }
         Ok(())
     }
 
+    /// Load the session `sid`, apply `f` to it and save the session and
+    /// any consumed prekeys if `f` succeeds.
+    ///
+    /// The session stays locked while `f` runs. If `f` fails, all changes
+    /// it made to the session are discarded. Returns `None` if no session
+    /// `sid` exists.
+    pub fn session_update<I, A, E, F>(&self, sid: I, f: F) -> Result<Option<A>, E>
+        where I: AsRef<str>,
+              F: FnOnce(&mut CBoxSession<S>) -> Result<A, E>,
+              E: From<CBoxError<S>>
+    {
+        let mut s = match try!(self.session_load(sid.as_ref())) {
+            Some(s) => s,
+            None    => return Ok(None)
+        };
+        let a = try!(f(&mut s));
+        try!(self.session_save(&mut s));
+        Ok(Some(a))
+    }
+
+    /// Decrypt a sequence of envelopes of one session.
+    ///
+    /// A failing envelope does not abort the batch; its error is reported
+    /// at the corresponding position of the result. The session and any
+    /// consumed prekeys are persisted once after all envelopes have been
+    /// processed.
+    pub fn session_decrypt_batch<B>(&self, s: &mut CBoxSession<S>, envelopes: &[B]) -> Result<Vec<Result<Vec<u8>, DecryptError<S>>>, CBoxError<S>>
+        where B: AsRef<[u8]>
+    {
+        let results = envelopes.iter().map(|e| s.decrypt(e.as_ref())).collect();
+        try!(self.session_save(s));
+        Ok(results)
+    }
+
+    /// Begin a transaction on the given session.
+    ///
+    /// Messages are encrypted and decrypted against a staged copy of the
+    /// session which only replaces `s` and is persisted (together with any
+    /// consumed prekeys) on `CBoxTransaction::commit`.
+    pub fn session_transaction<'r>(&'r self, s: &'r mut CBoxSession<S>) -> Result<CBoxTransaction<'r, S>, CBoxError<S>> {
+        let session = try!(Session::deserialise(self.ident.clone(), &try!(s.session.serialise())));
+        let mut store = ReadOnlyStore::new(self.store.clone());
+        store.removed = s.store.removed.clone();
+        store.pending = s.store.pending.clone();
+        let staged = CBoxSession {
+            sident:  s.sident.clone(),
+            store:   store,
+            session: session,
+            gen:     s.gen,
+            meta:    s.meta.clone(),
+            clock:   s.clock.clone(),
+            _lock:   None
+        };
+        Ok(CBoxTransaction { cbox: self, target: s, staged: staged })
+    }
+
+    pub fn session_delete<I: AsRef<str>>(&self, sid: I) -> Result<(), CBoxError<S>> {
+        try!(self.store.delete_session(sid.as_ref()).map_err(CBoxError::StorageError));
+        Ok(())
+    }
+
+    /// Metadata of the stored session `sid`, read without loading (and
+    /// locking) the session.
+    pub fn session_meta<I: AsRef<str>>(&self, sid: I) -> Result<Option<SessionMeta>, CBoxError<S>> {
+        self.store.load_session_meta(sid.as_ref()).map_err(CBoxError::StorageError)
+    }
+
+    /// IDs of the stored sessions whose remote identity has the given
+    /// fingerprint.
+    pub fn sessions_by_identity(&self, fingerprint: &str) -> Result<Vec<String>, CBoxError<S>> {
+        self.store.sessions_by_identity(fingerprint).map_err(CBoxError::StorageError)
+    }
+
+    /// Fingerprint of the remote identity of the stored session `sid`,
+    /// looked up without loading (and locking) the session.
+    pub fn session_identity<I: AsRef<str>>(&self, sid: I) -> Result<Option<String>, CBoxError<S>> {
+        self.store.session_identity(sid.as_ref()).map_err(CBoxError::StorageError)
+    }
+
+    /// IDs of the other stored sessions with the same remote identity as
+    /// `s`, e.g. because the remote device registered again under a new
+    /// client ID.
+    pub fn sessions_sharing_identity(&self, s: &CBoxSession<S>) -> Result<Vec<String>, CBoxError<S>> {
+        let mut ids = try!(self.sessions_by_identity(&s.fingerprint_remote()));
+        ids.retain(|id| *id != s.sident);
+        Ok(ids)
+    }
+
+    /// Generate and store a prekey, recording its creation time.
     pub fn new_prekey(&self, id: PreKeyId) -> Result<PreKeyBundle, CBoxError<S>> {
         let pk = PreKey::new(id);
+        // Without the prekey a dangling creation time is harmless.
+        try!(self.store.save_prekey_created(id, self.clock.now()).map_err(CBoxError::StorageError));
         try!(self.store.add_prekey(&pk).map_err(CBoxError::StorageError));
         Ok(PreKeyBundle::new(self.ident.as_ref().public_key.clone(), &pk))
     }
 
+    pub fn seal(&self, recipient: &IdentityKey, plain: &[u8]) -> Result<Vec<u8>, CBoxError<S>> {
+        let sealed = try!(SealedBox::seal(recipient, plain));
+        Ok(try!(sealed.serialise()))
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, CBoxError<S>> {
+        let sealed = try!(SealedBox::deserialise(sealed));
+        match try!(sealed.open(self.ident.as_ref())) {
+            Some(plain) => Ok(plain),
+            None        => Err(CBoxError::SealedBoxError)
+        }
+    }
+
+    /// Derive an application key of `len` bytes from the identity secret.
+    ///
+    /// Distinct labels yield independent keys. The same label always yields
+    /// the same key for as long as the identity exists. Fails with
+    /// `CBoxError::KeyLengthError` if `len` is 0 or exceeds
+    /// `MAX_DERIVED_KEY_LENGTH`.
+    pub fn derive_key(&self, label: &str, len: usize) -> Result<Vec<u8>, CBoxError<S>> {
+        kdf::derive(self.ident.as_ref(), label, len).ok_or(CBoxError::KeyLengthError(len))
+    }
+
     pub fn identity(&self) -> &IdentityKeyPair {
         self.ident.as_ref()
     }
@@ -162,22 +456,103 @@ impl<S: Store> CBox<S> {
     }
 }
 
+impl<S: Store + Send + Sync + 'static> CBox<S> where DecryptError<S>: Send {
+    /// Decrypt batches of envelopes of different sessions in parallel.
+    ///
+    /// Each element of `batches` pairs a session ID with its envelopes in
+    /// order. Sessions which do not exist yet are initialised from their
+    /// first envelope. Batches are distributed over at most `threads`
+    /// threads and each session is saved once its batch is done.
+    ///
+    /// The result contains one entry per batch in input order. It is an
+    /// error if the session could not be loaded or saved, otherwise it holds
+    /// the individual result of every envelope.
+    pub fn decrypt_parallel(&self, batches: Vec<(String, Vec<Vec<u8>>)>, threads: usize) -> Vec<BatchResult<S>> {
+        let size    = batches.len();
+        let queue   = Arc::new(Mutex::new(batches.into_iter().enumerate().collect::<Vec<_>>()));
+        let (tx, rx) = mpsc::channel();
+        let mut workers = Vec::new();
+        for _ in 0 .. ::std::cmp::max(1, ::std::cmp::min(threads, size)) {
+            let cbox  = self.clone();
+            let queue = queue.clone();
+            let tx    = tx.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    let next = queue.lock().unwrap_or_else(|e| e.into_inner()).pop();
+                    match next {
+                        Some((i, (sid, envs))) => {
+                            let _ = tx.send((i, cbox.decrypt_session(sid, envs)));
+                        }
+                        None => break
+                    }
+                }
+            }))
+        }
+        drop(tx);
+        let mut results: HashMap<usize, BatchResult<S>> = rx.iter().collect();
+        for w in workers {
+            let _ = w.join();
+        }
+        (0 .. size).map(|i| results.remove(&i).expect("worker thread panicked")).collect()
+    }
+
+    fn decrypt_session(&self, sid: String, envelopes: Vec<Vec<u8>>) -> BatchResult<S> {
+        let mut session = match self.session_load(sid.clone()) {
+            Ok(s)  => s,
+            Err(e) => return Err(DecryptError::new(sid, e))
+        };
+        let mut results = Vec::with_capacity(envelopes.len());
+        for env in &envelopes {
+            let r = match session {
+                Some(ref mut s) => s.decrypt(env),
+                None => match self.session_from_message(sid.clone(), env) {
+                    Ok((s, plain)) => { session = Some(s); Ok(plain) }
+                    Err(e)         => Err(e)
+                }
+            };
+            results.push(r)
+        }
+        if let Some(ref mut s) = session {
+            if let Err(e) = self.session_save(s) {
+                return Err(DecryptError::new(sid, e))
+            }
+        }
+        Ok(results)
+    }
+}
+
+pub type BatchResult<S> = Result<Vec<Result<Vec<u8>, DecryptError<S>>>, DecryptError<S>>;
+
 // Session //////////////////////////////////////////////////////////////////
 
 pub struct CBoxSession<S> {
     sident:  String,
     store:   ReadOnlyStore<S>,
-    session: Session<Arc<IdentityKeyPair>>
+    session: Session<Arc<IdentityKeyPair>>,
+    gen:     Generation,
+    meta:    SessionMeta,
+    clock:   Arc<Clock>,
+    _lock:   Option<SessionLock>
 }
 
 impl<S: Store> CBoxSession<S> {
     pub fn encrypt(&mut self, plain: &[u8]) -> Result<Vec<u8>, CBoxError<S>> {
-        Ok(try!(self.session.encrypt(plain).and_then(|m| m.serialise())))
+        let cipher = try!(self.session.encrypt(plain).and_then(|m| m.serialise()));
+        self.meta.record_encrypt(self.clock.now());
+        Ok(cipher)
+    }
+
+    pub fn decrypt(&mut self, cipher: &[u8]) -> Result<Vec<u8>, DecryptError<S>> {
+        match self.decrypt_envelope(cipher) {
+            Ok(txt) => Ok(txt),
+            Err(e)  => Err(DecryptError::new(self.sident.clone(), e))
+        }
     }
 
-    pub fn decrypt(&mut self, cipher: &[u8]) -> Result<Vec<u8>, CBoxError<S>> {
+    fn decrypt_envelope(&mut self, cipher: &[u8]) -> Result<Vec<u8>, CBoxError<S>> {
         let env = try!(Envelope::deserialise(cipher));
         let txt = try!(self.session.decrypt(&mut self.store, &env));
+        self.meta.record_decrypt(self.clock.now());
         Ok(txt)
     }
 
@@ -196,20 +571,81 @@ impl<S: Store> CBoxSession<S> {
     pub fn fingerprint_remote(&self) -> String {
         self.session.remote_identity().fingerprint()
     }
+
+    /// Metadata as of the last operation. It is persisted on save.
+    pub fn meta(&self) -> &SessionMeta {
+        &self.meta
+    }
+
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.meta.labels.get(key).map(|v| v.as_str())
+    }
+
+    pub fn set_label<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.meta.labels.insert(key.into(), value.into());
+    }
+
+    pub fn remove_label(&mut self, key: &str) -> Option<String> {
+        self.meta.labels.remove(key)
+    }
+}
+
+// Transaction //////////////////////////////////////////////////////////////
+
+pub struct CBoxTransaction<'r, S: Store + 'r> {
+    cbox:   &'r CBox<S>,
+    target: &'r mut CBoxSession<S>,
+    staged: CBoxSession<S>
+}
+
+impl<'r, S: Store> CBoxTransaction<'r, S> {
+    pub fn encrypt(&mut self, plain: &[u8]) -> Result<Vec<u8>, CBoxError<S>> {
+        self.staged.encrypt(plain)
+    }
+
+    pub fn decrypt(&mut self, cipher: &[u8]) -> Result<Vec<u8>, DecryptError<S>> {
+        self.staged.decrypt(cipher)
+    }
+
+    /// Persist the staged session and delete the prekeys it consumed.
+    ///
+    /// The original session is only replaced if the staged session has
+    /// been saved, which may be the case even if an error is returned (see
+    /// `CBox::session_save`).
+    pub fn commit(mut self) -> Result<(), CBoxError<S>> {
+        let r = self.cbox.session_save(&mut self.staged);
+        if r.is_ok() || self.staged.gen != self.target.gen {
+            mem::swap(&mut self.target.session, &mut self.staged.session);
+            mem::swap(&mut self.target.store, &mut self.staged.store);
+            mem::swap(&mut self.target.meta, &mut self.staged.meta);
+            self.target.gen = self.staged.gen;
+        } else {
+            mem::swap(&mut self.target.store.pending, &mut self.staged.store.pending)
+        }
+        r
+    }
+
+    /// Discard the staged session. Dropping a transaction without
+    /// committing it has the same effect.
+    pub fn rollback(self) {}
 }
 
 // ReadOnlyStore ////////////////////////////////////////////////////////////
 
+// `removed` holds the prekeys consumed since the last save, `pending` those
+// consumed by a saved generation which could not be deleted yet.
 struct ReadOnlyStore<S> {
     store:   Arc<S>,
-    removed: Vec<PreKeyId>
+    removed: Vec<PreKeyId>,
+    pending: Vec<PreKeyId>
 }
 
 impl<S> ReadOnlyStore<S> {
     fn new(s: Arc<S>) -> ReadOnlyStore<S> {
         ReadOnlyStore {
             store:   s,
-            removed: Vec::new()
+            removed: Vec::new(),
+            pending: Vec::new()
         }
     }
 }
@@ -218,7 +654,7 @@ impl<S: Store> PreKeyStore for ReadOnlyStore<S> {
     type Error = S::Error;
 
     fn prekey(&mut self, id: PreKeyId) -> Result<Option<PreKey>, S::Error> {
-        if self.removed.contains(&id) {
+        if self.removed.contains(&id) || self.pending.contains(&id) {
             Ok(None)
         } else {
             self.store.load_prekey(id)
@@ -240,9 +676,28 @@ pub enum CBoxError<S: Store> {
     DecodeError(DecodeError),
     EncodeError(EncodeError),
     IdentityError,
+    SealedBoxError,
+    KeyLengthError(usize),
+    ConflictError,
     InitError
 }
 
+impl<S: Store> CBoxError<S> {
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            CBoxError::ProteusError(ref e) => proteus_kind(e),
+            CBoxError::StorageError(_)   => ErrorKind::StorageFailure,
+            CBoxError::DecodeError(_)    => ErrorKind::MalformedEnvelope,
+            CBoxError::EncodeError(_)    => ErrorKind::EncodeFailure,
+            CBoxError::IdentityError     => ErrorKind::IdentityMismatch,
+            CBoxError::InitError         => ErrorKind::InitFailure,
+            CBoxError::SealedBoxError    => ErrorKind::Other,
+            CBoxError::KeyLengthError(_) => ErrorKind::Other,
+            CBoxError::ConflictError     => ErrorKind::Conflict
+        }
+    }
+}
+
 impl<S: Store> fmt::Display for CBoxError<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
@@ -251,6 +706,9 @@ impl<S: Store> fmt::Display for CBoxError<S> {
             CBoxError::DecodeError(ref e)  => write!(f, "CBoxError: decode error: {}", *e),
             CBoxError::EncodeError(ref e)  => write!(f, "CBoxError: encode error: {}", *e),
             CBoxError::IdentityError       => write!(f, "CBoxError: identity error"),
+            CBoxError::SealedBoxError      => write!(f, "CBoxError: sealed box can not be opened"),
+            CBoxError::KeyLengthError(n)   => write!(f, "CBoxError: invalid derived key length: {}", n),
+            CBoxError::ConflictError       => write!(f, "CBoxError: session has been modified concurrently"),
             CBoxError::InitError           => write!(f, "CBoxError: initialisation error")
         }
     }
@@ -268,11 +726,20 @@ impl<S: Store + fmt::Debug> Error for CBoxError<S> {
             CBoxError::DecodeError(ref e)  => Some(e),
             CBoxError::EncodeError(ref e)  => Some(e),
             CBoxError::IdentityError       => None,
+            CBoxError::SealedBoxError      => None,
+            CBoxError::KeyLengthError(_)   => None,
+            CBoxError::ConflictError       => None,
             CBoxError::InitError           => None
         }
     }
 }
 
+impl<S: Store> From<DecryptError<S>> for CBoxError<S> {
+    fn from(e: DecryptError<S>) -> CBoxError<S> {
+        e.error
+    }
+}
+
 impl From<FileStoreError> for CBoxError<FileStore> {
     fn from(e: FileStoreError) -> CBoxError<FileStore> {
         CBoxError::StorageError(e)
@@ -296,3 +763,261 @@ impl<S: Store> From<EncodeError> for CBoxError<S> {
         CBoxError::EncodeError(e)
     }
 }
+
+// ErrorKind ////////////////////////////////////////////////////////////////
+
+/// Classification of `CBoxError`s by what a client should do about them.
+///
+/// The numeric codes returned by `ErrorKind::code` are stable and will not
+/// be reassigned, so they can be used in telemetry and across FFI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    StorageFailure,
+    MalformedEnvelope,
+    RemoteIdentityChanged,
+    InvalidSignature,
+    CorruptedSession,
+    DuplicateMessage,
+    TooDistantFuture,
+    OutdatedMessage,
+    EncodeFailure,
+    IdentityMismatch,
+    UnknownPreKey,
+    InitFailure,
+    Conflict,
+    Other
+}
+
+impl ErrorKind {
+    /// The stable numeric code of this kind.
+    ///
+    /// Codes follow the error codes of cryptobox-c. Codes 10 (invalid
+    /// UTF-8), 11 (NUL byte), 15 (panic) and 17 (degenerated key) are
+    /// reserved for errors which only occur in those bindings and are
+    /// never returned here. Code 2 (session not found) is not an error
+    /// of the box, but the daemon reports it as
+    /// `daemon::protocol::SESSION_NOT_FOUND`.
+    pub fn code(&self) -> u16 {
+        match *self {
+            ErrorKind::StorageFailure        => 1,
+            ErrorKind::MalformedEnvelope     => 3,
+            ErrorKind::RemoteIdentityChanged => 4,
+            ErrorKind::InvalidSignature      => 5,
+            ErrorKind::CorruptedSession      => 6,
+            ErrorKind::DuplicateMessage      => 7,
+            ErrorKind::TooDistantFuture      => 8,
+            ErrorKind::OutdatedMessage       => 9,
+            ErrorKind::EncodeFailure         => 12,
+            ErrorKind::IdentityMismatch      => 13,
+            ErrorKind::UnknownPreKey         => 14,
+            ErrorKind::InitFailure           => 16,
+            ErrorKind::Conflict              => 18,
+            ErrorKind::Other                 => 255
+        }
+    }
+}
+
+fn proteus_kind<E>(e: &proteus::session::Error<E>) -> ErrorKind {
+    match *e {
+        proteus::session::Error::RemoteIdentityChanged => ErrorKind::RemoteIdentityChanged,
+        proteus::session::Error::InvalidSignature      => ErrorKind::InvalidSignature,
+        proteus::session::Error::InvalidMessage        => ErrorKind::CorruptedSession,
+        proteus::session::Error::DuplicateMessage      => ErrorKind::DuplicateMessage,
+        proteus::session::Error::TooDistantFuture      => ErrorKind::TooDistantFuture,
+        proteus::session::Error::OutdatedMessage       => ErrorKind::OutdatedMessage,
+        proteus::session::Error::PreKeyNotFound(_)     => ErrorKind::UnknownPreKey,
+        proteus::session::Error::PreKeyStoreError(_)   => ErrorKind::StorageFailure,
+        _                                              => ErrorKind::Other
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let s = match *self {
+            ErrorKind::StorageFailure        => "storage failure",
+            ErrorKind::MalformedEnvelope     => "malformed envelope",
+            ErrorKind::RemoteIdentityChanged => "remote identity changed",
+            ErrorKind::InvalidSignature      => "invalid signature",
+            ErrorKind::CorruptedSession      => "corrupted session",
+            ErrorKind::DuplicateMessage      => "duplicate message",
+            ErrorKind::TooDistantFuture      => "too distant future",
+            ErrorKind::OutdatedMessage       => "outdated message",
+            ErrorKind::EncodeFailure         => "encode failure",
+            ErrorKind::IdentityMismatch      => "identity mismatch",
+            ErrorKind::UnknownPreKey         => "unknown prekey",
+            ErrorKind::InitFailure           => "initialisation failure",
+            ErrorKind::Conflict              => "conflict",
+            ErrorKind::Other                 => "other"
+        };
+        f.write_str(s)
+    }
+}
+
+// DecryptError /////////////////////////////////////////////////////////////
+
+/// A `CBoxError` raised while decrypting a message of a particular session.
+#[derive(Debug)]
+pub struct DecryptError<S: Store> {
+    sid:   String,
+    error: CBoxError<S>
+}
+
+impl<S: Store> DecryptError<S> {
+    fn new(sid: String, error: CBoxError<S>) -> DecryptError<S> {
+        DecryptError { sid: sid, error: error }
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.sid
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.error.kind()
+    }
+
+    pub fn error(&self) -> &CBoxError<S> {
+        &self.error
+    }
+
+    pub fn into_error(self) -> CBoxError<S> {
+        self.error
+    }
+}
+
+impl<S: Store> fmt::Display for DecryptError<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "DecryptError: session {} ({}): {}", self.sid, self.error.kind(), self.error)
+    }
+}
+
+impl<S: Store + fmt::Debug> Error for DecryptError<S> {
+    fn description(&self) -> &str {
+        "DecryptError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        Some(&self.error)
+    }
+}
+
+// CBoxAnyError /////////////////////////////////////////////////////////////
+
+/// A `CBoxError` which is independent of the `Store` that produced it.
+///
+/// Storage errors (including those raised by a `PreKeyStore` during
+/// decryption) are boxed as trait objects so that errors of boxes with
+/// different backends can be combined and passed around freely.
+#[derive(Debug)]
+pub enum CBoxAnyError {
+    ProteusError(ErrorKind, Box<Error + Send + Sync>),
+    StorageError(Box<Error + Send + Sync>),
+    DecodeError(DecodeError),
+    EncodeError(EncodeError),
+    IdentityError,
+    SealedBoxError,
+    KeyLengthError(usize),
+    ConflictError,
+    InitError,
+    /// An error raised while decrypting a message of the given session.
+    DecryptError(String, Box<CBoxAnyError>)
+}
+
+impl CBoxAnyError {
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            CBoxAnyError::ProteusError(k, _)     => k,
+            CBoxAnyError::StorageError(_)        => ErrorKind::StorageFailure,
+            CBoxAnyError::DecodeError(_)         => ErrorKind::MalformedEnvelope,
+            CBoxAnyError::EncodeError(_)         => ErrorKind::EncodeFailure,
+            CBoxAnyError::IdentityError          => ErrorKind::IdentityMismatch,
+            CBoxAnyError::InitError              => ErrorKind::InitFailure,
+            CBoxAnyError::SealedBoxError         => ErrorKind::Other,
+            CBoxAnyError::KeyLengthError(_)      => ErrorKind::Other,
+            CBoxAnyError::ConflictError          => ErrorKind::Conflict,
+            CBoxAnyError::DecryptError(_, ref e) => e.kind()
+        }
+    }
+
+    /// The ID of the session the error has been raised for, if known.
+    pub fn session_id(&self) -> Option<&str> {
+        match *self {
+            CBoxAnyError::DecryptError(ref sid, _) => Some(&sid[..]),
+            _                                      => None
+        }
+    }
+}
+
+impl fmt::Display for CBoxAnyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CBoxAnyError::ProteusError(_, ref e)     => write!(f, "CBoxError: proteus error: {}", e),
+            CBoxAnyError::StorageError(ref e)        => write!(f, "CBoxError: storage error: {}", e),
+            CBoxAnyError::DecodeError(ref e)         => write!(f, "CBoxError: decode error: {}", *e),
+            CBoxAnyError::EncodeError(ref e)         => write!(f, "CBoxError: encode error: {}", *e),
+            CBoxAnyError::IdentityError              => write!(f, "CBoxError: identity error"),
+            CBoxAnyError::SealedBoxError             => write!(f, "CBoxError: sealed box can not be opened"),
+            CBoxAnyError::KeyLengthError(n)          => write!(f, "CBoxError: invalid derived key length: {}", n),
+            CBoxAnyError::ConflictError              => write!(f, "CBoxError: session has been modified concurrently"),
+            CBoxAnyError::InitError                  => write!(f, "CBoxError: initialisation error"),
+            CBoxAnyError::DecryptError(ref s, ref e) => write!(f, "DecryptError: session {} ({}): {}", s, e.kind(), e)
+        }
+    }
+}
+
+impl Error for CBoxAnyError {
+    fn description(&self) -> &str {
+        "CBoxError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            CBoxAnyError::ProteusError(_, ref e) => Some(e.as_ref()),
+            CBoxAnyError::StorageError(ref e)    => Some(e.as_ref()),
+            CBoxAnyError::DecodeError(ref e)     => Some(e),
+            CBoxAnyError::EncodeError(ref e)     => Some(e),
+            CBoxAnyError::IdentityError          => None,
+            CBoxAnyError::SealedBoxError         => None,
+            CBoxAnyError::KeyLengthError(_)      => None,
+            CBoxAnyError::ConflictError          => None,
+            CBoxAnyError::InitError              => None,
+            CBoxAnyError::DecryptError(_, ref e) => Some(e.as_ref())
+        }
+    }
+}
+
+impl<S: Store> From<CBoxError<S>> for CBoxAnyError where S::Error: Send + Sync + 'static {
+    fn from(e: CBoxError<S>) -> CBoxAnyError {
+        let kind = e.kind();
+        match e {
+            CBoxError::ProteusError(proteus::session::Error::PreKeyStoreError(e)) =>
+                CBoxAnyError::StorageError(Box::new(e)),
+            CBoxError::ProteusError(e)   => CBoxAnyError::ProteusError(kind, Box::new(e)),
+            CBoxError::StorageError(e)   => CBoxAnyError::StorageError(Box::new(e)),
+            CBoxError::DecodeError(e)    => CBoxAnyError::DecodeError(e),
+            CBoxError::EncodeError(e)    => CBoxAnyError::EncodeError(e),
+            CBoxError::IdentityError     => CBoxAnyError::IdentityError,
+            CBoxError::SealedBoxError    => CBoxAnyError::SealedBoxError,
+            CBoxError::KeyLengthError(n) => CBoxAnyError::KeyLengthError(n),
+            CBoxError::ConflictError     => CBoxAnyError::ConflictError,
+            CBoxError::InitError         => CBoxAnyError::InitError
+        }
+    }
+}
+
+impl<S: Store> From<DecryptError<S>> for CBoxAnyError where S::Error: Send + Sync + 'static {
+    fn from(e: DecryptError<S>) -> CBoxAnyError {
+        CBoxAnyError::DecryptError(e.sid, Box::new(CBoxAnyError::from(e.error)))
+    }
+}
+
+impl From<DecodeError> for CBoxAnyError {
+    fn from(e: DecodeError) -> CBoxAnyError {
+        CBoxAnyError::DecodeError(e)
+    }
+}
+
+impl From<EncodeError> for CBoxAnyError {
+    fn from(e: EncodeError) -> CBoxAnyError {
+        CBoxAnyError::EncodeError(e)
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex};
+
+// Set of session IDs currently held by some `CBoxSession`.
+pub struct SessionLocks {
+    held: Mutex<HashSet<String>>,
+    cond: Condvar
+}
+
+impl SessionLocks {
+    pub fn new() -> SessionLocks {
+        SessionLocks {
+            held: Mutex::new(HashSet::new()),
+            cond: Condvar::new()
+        }
+    }
+
+    // Block until no other lock for `sid` is held.
+    pub fn acquire(this: &Arc<SessionLocks>, sid: &str) -> SessionLock {
+        let mut held = this.held.lock().unwrap_or_else(|e| e.into_inner());
+        while held.contains(sid) {
+            held = this.cond.wait(held).unwrap_or_else(|e| e.into_inner());
+        }
+        held.insert(String::from(sid));
+        SessionLock { locks: this.clone(), sid: String::from(sid) }
+    }
+
+    // Like `acquire` but returns `None` instead of blocking.
+    pub fn try_acquire(this: &Arc<SessionLocks>, sid: &str) -> Option<SessionLock> {
+        let mut held = this.held.lock().unwrap_or_else(|e| e.into_inner());
+        if !held.insert(String::from(sid)) {
+            return None
+        }
+        Some(SessionLock { locks: this.clone(), sid: String::from(sid) })
+    }
+}
+
+pub struct SessionLock {
+    locks: Arc<SessionLocks>,
+    sid:   String
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock().unwrap_or_else(|e| e.into_inner());
+        held.remove(&self.sid);
+        self.locks.cond.notify_all()
+    }
+}
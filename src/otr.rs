@@ -0,0 +1,121 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use payload::{base64_encode, write_json_string};
+use std::collections::{BTreeMap, BTreeSet};
+use store::Store;
+use {CBox, CBoxError};
+
+/// Builds the recipient map of an OTR message, i.e. one base64 encoded
+/// envelope per recipient client, keyed by user ID and client ID.
+pub struct OtrBuilder<'r, S: 'r> {
+    cbox:       &'r CBox<S>,
+    recipients: BTreeMap<(String, String), String>
+}
+
+impl<'r, S: Store> OtrBuilder<'r, S> {
+    pub fn new(cbox: &'r CBox<S>) -> OtrBuilder<'r, S> {
+        OtrBuilder {
+            cbox:       cbox,
+            recipients: BTreeMap::new()
+        }
+    }
+
+    /// Add the client `client` of user `user`, whose session with us has
+    /// the ID `sid`. Adding the same client again replaces its session ID.
+    pub fn recipient<U, C, I>(mut self, user: U, client: C, sid: I) -> OtrBuilder<'r, S>
+        where U: Into<String>, C: Into<String>, I: Into<String>
+    {
+        self.recipients.insert((user.into(), client.into()), sid.into());
+        self
+    }
+
+    /// Add all `((user, client), sid)` entries of a recipient map.
+    pub fn recipients<U, C, I, T>(mut self, map: T) -> OtrBuilder<'r, S>
+        where U: Into<String>, C: Into<String>, I: Into<String>,
+              T: IntoIterator<Item = ((U, C), I)>
+    {
+        for ((u, c), i) in map {
+            self.recipients.insert((u.into(), c.into()), i.into());
+        }
+        self
+    }
+
+    /// Encrypt `plain` for every recipient which has a session and save
+    /// the sessions.
+    ///
+    /// Recipients without a session are returned in `OtrMessage::missing`.
+    /// On error, sessions already used for earlier recipients remain
+    /// advanced and saved, which is harmless as the corresponding
+    /// messages are simply never delivered.
+    pub fn encrypt(&self, plain: &[u8]) -> Result<OtrMessage, CBoxError<S>> {
+        let mut msg = OtrMessage::new();
+        for (&(ref user, ref client), sid) in &self.recipients {
+            match try!(self.cbox.session_load(sid.clone())) {
+                Some(mut s) => {
+                    let cipher = try!(s.encrypt(plain));
+                    try!(self.cbox.session_save(&mut s));
+                    msg.recipients.entry(user.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(client.clone(), base64_encode(&cipher));
+                }
+                None => {
+                    msg.missing.insert((user.clone(), client.clone()));
+                }
+            }
+        }
+        Ok(msg)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtrMessage {
+    /// User ID -> client ID -> base64 envelope.
+    pub recipients: BTreeMap<String, BTreeMap<String, String>>,
+    /// `(user, client)` pairs for which no session exists.
+    pub missing: BTreeSet<(String, String)>
+}
+
+impl OtrMessage {
+    fn new() -> OtrMessage {
+        OtrMessage {
+            recipients: BTreeMap::new(),
+            missing:    BTreeSet::new()
+        }
+    }
+
+    /// The recipient map as JSON, i.e. `{"<user>": {"<client>": "<base64>"}}`.
+    pub fn recipients_json(&self) -> String {
+        let mut s = String::from("{");
+        for (i, (user, clients)) in self.recipients.iter().enumerate() {
+            if i > 0 {
+                s.push(',')
+            }
+            write_json_string(&mut s, user);
+            s.push_str(":{");
+            for (j, (client, cipher)) in clients.iter().enumerate() {
+                if j > 0 {
+                    s.push(',')
+                }
+                write_json_string(&mut s, client);
+                s.push(':');
+                write_json_string(&mut s, cipher)
+            }
+            s.push('}')
+        }
+        s.push('}');
+        s
+    }
+}
@@ -0,0 +1,571 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Representations of prekey bundles and envelopes as exchanged with the
+// backend: standard base64 with padding and, for prekeys, JSON objects of
+// the form `{"id": <u16>, "key": <base64 bundle>}`. A prekey upload is
+//
+//     {"prekeys": [<prekey>, ...], "lastkey": <prekey>}
+//
+// where `lastkey` is the last resort prekey (ID `MAX_PREKEY_ID`).
+//
+// Parsing is strict: the JSON must contain exactly the expected fields,
+// base64 must be canonical, bundles and envelopes must decode, a prekey's
+// `id` must match the ID inside its bundle and prekey IDs must be unique.
+
+use proteus::{DecodeError, EncodeError};
+use proteus::keys::{MAX_PREKEY_ID, PreKeyBundle};
+use proteus::message::Envelope;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+// Envelopes ////////////////////////////////////////////////////////////////
+
+pub fn envelope_to_base64(env: &[u8]) -> String {
+    base64_encode(env)
+}
+
+/// Decode a base64 envelope, checking that it is a well-formed envelope.
+pub fn envelope_from_base64(s: &str) -> Result<Vec<u8>, PayloadError> {
+    let b = try!(base64_decode(s));
+    try!(Envelope::deserialise(&b));
+    Ok(b)
+}
+
+// Prekeys //////////////////////////////////////////////////////////////////
+
+pub fn prekey_to_base64(bundle: &PreKeyBundle) -> Result<String, EncodeError> {
+    Ok(base64_encode(&try!(bundle.serialise())))
+}
+
+pub fn prekey_from_base64(s: &str) -> Result<PreKeyBundle, PayloadError> {
+    Ok(try!(PreKeyBundle::deserialise(&try!(base64_decode(s)))))
+}
+
+pub fn prekey_to_json(bundle: &PreKeyBundle) -> Result<String, EncodeError> {
+    let mut s = String::new();
+    try!(write_prekey(&mut s, bundle));
+    Ok(s)
+}
+
+pub fn prekey_from_json(s: &str) -> Result<PreKeyBundle, PayloadError> {
+    read_prekey(&try!(parse(s)))
+}
+
+/// Encode a list of prekeys as a JSON array.
+pub fn prekeys_to_json(bundles: &[PreKeyBundle]) -> Result<String, EncodeError> {
+    let mut s = String::new();
+    try!(write_prekeys(&mut s, bundles));
+    Ok(s)
+}
+
+pub fn prekeys_from_json(s: &str) -> Result<Vec<PreKeyBundle>, PayloadError> {
+    read_prekeys(&try!(parse(s)))
+}
+
+/// Prekeys and the last resort prekey as uploaded for a new client.
+pub struct PreKeyUpload {
+    pub prekeys:     Vec<PreKeyBundle>,
+    pub last_resort: PreKeyBundle
+}
+
+impl PreKeyUpload {
+    /// Fails if `last_resort` does not have ID `MAX_PREKEY_ID` or any
+    /// other prekey does.
+    pub fn new(prekeys: Vec<PreKeyBundle>, last_resort: PreKeyBundle) -> Result<PreKeyUpload, PayloadError> {
+        if last_resort.prekey_id != MAX_PREKEY_ID {
+            return Err(PayloadError::LastResort(last_resort.prekey_id.value()))
+        }
+        if prekeys.iter().any(|p| p.prekey_id == MAX_PREKEY_ID) {
+            return Err(PayloadError::DuplicatePreKey(MAX_PREKEY_ID.value()))
+        }
+        Ok(PreKeyUpload { prekeys: prekeys, last_resort: last_resort })
+    }
+
+    pub fn to_json(&self) -> Result<String, EncodeError> {
+        let mut s = String::from("{\"prekeys\":");
+        try!(write_prekeys(&mut s, &self.prekeys));
+        s.push_str(",\"lastkey\":");
+        try!(write_prekey(&mut s, &self.last_resort));
+        s.push('}');
+        Ok(s)
+    }
+
+    pub fn from_json(s: &str) -> Result<PreKeyUpload, PayloadError> {
+        let v = try!(parse(s));
+        let mut f = try!(Fields::new(&v, "upload", &["prekeys", "lastkey"]));
+        let prekeys = try!(read_prekeys(try!(f.take("prekeys"))));
+        let last    = try!(read_prekey(try!(f.take("lastkey"))));
+        PreKeyUpload::new(prekeys, last)
+    }
+}
+
+fn write_prekey(s: &mut String, bundle: &PreKeyBundle) -> Result<(), EncodeError> {
+    let key = try!(prekey_to_base64(bundle));
+    s.push_str(&format!("{{\"id\":{},\"key\":\"{}\"}}", bundle.prekey_id.value(), key));
+    Ok(())
+}
+
+fn write_prekeys(s: &mut String, bundles: &[PreKeyBundle]) -> Result<(), EncodeError> {
+    s.push('[');
+    for (i, b) in bundles.iter().enumerate() {
+        if i > 0 {
+            s.push(',')
+        }
+        try!(write_prekey(s, b))
+    }
+    s.push(']');
+    Ok(())
+}
+
+fn read_prekey(v: &Value) -> Result<PreKeyBundle, PayloadError> {
+    let mut f  = try!(Fields::new(v, "prekey", &["id", "key"]));
+    let id     = try!(try!(f.take("id")).as_u16("id"));
+    let bundle = try!(prekey_from_base64(try!(try!(f.take("key")).as_str("key"))));
+    if bundle.prekey_id.value() != id {
+        return Err(PayloadError::IdMismatch(id, bundle.prekey_id.value()))
+    }
+    Ok(bundle)
+}
+
+fn read_prekeys(v: &Value) -> Result<Vec<PreKeyBundle>, PayloadError> {
+    let items = match *v {
+        Value::Array(ref a) => a,
+        _                   => return Err(PayloadError::InvalidField("prekeys"))
+    };
+    let mut ids = HashSet::new();
+    let mut out = Vec::with_capacity(items.len());
+    for x in items {
+        let b = try!(read_prekey(x));
+        if !ids.insert(b.prekey_id.value()) {
+            return Err(PayloadError::DuplicatePreKey(b.prekey_id.value()))
+        }
+        out.push(b)
+    }
+    Ok(out)
+}
+
+// Base64 ///////////////////////////////////////////////////////////////////
+
+const ALPHABET: &'static [u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(b: &[u8]) -> String {
+    let mut s = String::with_capacity((b.len() + 2) / 3 * 4);
+    for c in b.chunks(3) {
+        let n = (c[0] as u32) << 16
+              | (*c.get(1).unwrap_or(&0) as u32) << 8
+              | *c.get(2).unwrap_or(&0) as u32;
+        for i in 0 .. 4 {
+            if i <= c.len() {
+                s.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char)
+            } else {
+                s.push('=')
+            }
+        }
+    }
+    s
+}
+
+/// Decode padded base64, rejecting whitespace and non-zero trailing bits.
+pub fn base64_decode(s: &str) -> Result<Vec<u8>, PayloadError> {
+    let s = s.as_bytes();
+    if s.len() % 4 != 0 {
+        return Err(PayloadError::Base64)
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for (k, c) in s.chunks(4).enumerate() {
+        let last = k == s.len() / 4 - 1;
+        let pad  = c.iter().rev().take_while(|&&x| x == b'=').count();
+        if pad > 2 || (pad > 0 && !last) {
+            return Err(PayloadError::Base64)
+        }
+        let mut n = 0u32;
+        for &x in &c[.. 4 - pad] {
+            n = n << 6 | try!(base64_value(x))
+        }
+        n <<= 6 * pad as u32;
+        if pad == 2 && n & 0xFFFF != 0 || pad == 1 && n & 0xFF != 0 {
+            return Err(PayloadError::Base64)
+        }
+        out.push((n >> 16) as u8);
+        if pad < 2 { out.push((n >> 8) as u8) }
+        if pad < 1 { out.push(n as u8) }
+    }
+    Ok(out)
+}
+
+fn base64_value(x: u8) -> Result<u32, PayloadError> {
+    match x {
+        b'A' ... b'Z' => Ok((x - b'A') as u32),
+        b'a' ... b'z' => Ok((x - b'a' + 26) as u32),
+        b'0' ... b'9' => Ok((x - b'0' + 52) as u32),
+        b'+'          => Ok(62),
+        b'/'          => Ok(63),
+        _             => Err(PayloadError::Base64)
+    }
+}
+
+// JSON /////////////////////////////////////////////////////////////////////
+
+// Just enough JSON for the payloads above. Numbers are kept as text and
+// only interpreted where an integer is expected. No field accepts `null`,
+// `true` or `false`, so these are only told apart from other values.
+enum Value {
+    Literal,
+    Number(String),
+    Text(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>)
+}
+
+impl Value {
+    fn as_u16(&self, field: &'static str) -> Result<u16, PayloadError> {
+        match *self {
+            Value::Number(ref n) if n.bytes().all(|c| c.is_ascii_digit()) =>
+                n.parse().map_err(|_| PayloadError::InvalidField(field)),
+            _ => Err(PayloadError::InvalidField(field))
+        }
+    }
+
+    fn as_str(&self, field: &'static str) -> Result<&str, PayloadError> {
+        match *self {
+            Value::Text(ref s) => Ok(s),
+            _                  => Err(PayloadError::InvalidField(field))
+        }
+    }
+}
+
+// The fields of an object, which must all be among `names`.
+struct Fields<'v> {
+    fields: Vec<(&'v str, Option<&'v Value>)>
+}
+
+impl<'v> Fields<'v> {
+    fn new(v: &'v Value, what: &'static str, names: &[&str]) -> Result<Fields<'v>, PayloadError> {
+        let obj = match *v {
+            Value::Object(ref o) => o,
+            _                    => return Err(PayloadError::InvalidField(what))
+        };
+        let mut fields: Vec<(&str, Option<&Value>)> = Vec::with_capacity(obj.len());
+        for &(ref k, ref x) in obj {
+            if !names.contains(&k.as_str()) {
+                return Err(PayloadError::UnknownField(k.clone()))
+            }
+            if fields.iter().any(|&(n, _)| n == k) {
+                return Err(PayloadError::DuplicateField(k.clone()))
+            }
+            fields.push((k, Some(x)))
+        }
+        Ok(Fields { fields: fields })
+    }
+
+    fn take(&mut self, name: &'static str) -> Result<&'v Value, PayloadError> {
+        self.fields.iter_mut()
+            .find(|&&mut (n, _)| n == name)
+            .and_then(|&mut (_, ref mut x)| x.take())
+            .ok_or(PayloadError::MissingField(name))
+    }
+}
+
+fn parse(s: &str) -> Result<Value, PayloadError> {
+    let mut p = Parser { s: s.as_bytes(), i: 0 };
+    let v = try!(p.value(0));
+    p.ws();
+    if p.i != p.s.len() {
+        return Err(PayloadError::Syntax(p.i))
+    }
+    Ok(v)
+}
+
+/// Append `s` to `out` as a JSON string literal.
+pub(crate) fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"'               => out.push_str("\\\""),
+            '\\'              => out.push_str("\\\\"),
+            '\n'              => out.push_str("\\n"),
+            '\r'              => out.push_str("\\r"),
+            '\t'              => out.push_str("\\t"),
+            c if c < '\u{20}' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c                 => out.push(c)
+        }
+    }
+    out.push('"')
+}
+
+const MAX_DEPTH: usize = 16;
+
+struct Parser<'s> {
+    s: &'s [u8],
+    i: usize
+}
+
+impl<'s> Parser<'s> {
+    fn err<A>(&self) -> Result<A, PayloadError> {
+        Err(PayloadError::Syntax(self.i))
+    }
+
+    fn ws(&mut self) {
+        while self.i < self.s.len() && b" \t\r\n".contains(&self.s[self.i]) {
+            self.i += 1
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.i).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), PayloadError> {
+        self.ws();
+        if self.peek() != Some(c) {
+            return self.err()
+        }
+        self.i += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, lit: &[u8]) -> Result<Value, PayloadError> {
+        if !self.s[self.i ..].starts_with(lit) {
+            return self.err()
+        }
+        self.i += lit.len();
+        Ok(Value::Literal)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, PayloadError> {
+        if depth > MAX_DEPTH {
+            return self.err()
+        }
+        self.ws();
+        match self.peek() {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string().map(Value::Text),
+            Some(b'n') => self.literal(b"null"),
+            Some(b't') => self.literal(b"true"),
+            Some(b'f') => self.literal(b"false"),
+            Some(b'-') | Some(b'0' ... b'9') => self.number(),
+            _          => self.err()
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Value, PayloadError> {
+        self.i += 1;
+        let mut fields = Vec::new();
+        self.ws();
+        if self.peek() == Some(b'}') {
+            self.i += 1;
+            return Ok(Value::Object(fields))
+        }
+        loop {
+            self.ws();
+            if self.peek() != Some(b'"') {
+                return self.err()
+            }
+            let k = try!(self.string());
+            try!(self.expect(b':'));
+            let v = try!(self.value(depth + 1));
+            fields.push((k, v));
+            self.ws();
+            match self.peek() {
+                Some(b',') => self.i += 1,
+                Some(b'}') => { self.i += 1; return Ok(Value::Object(fields)) }
+                _          => return self.err()
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value, PayloadError> {
+        self.i += 1;
+        let mut items = Vec::new();
+        self.ws();
+        if self.peek() == Some(b']') {
+            self.i += 1;
+            return Ok(Value::Array(items))
+        }
+        loop {
+            items.push(try!(self.value(depth + 1)));
+            self.ws();
+            match self.peek() {
+                Some(b',') => self.i += 1,
+                Some(b']') => { self.i += 1; return Ok(Value::Array(items)) }
+                _          => return self.err()
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, PayloadError> {
+        let start = self.i;
+        if self.peek() == Some(b'-') {
+            self.i += 1
+        }
+        match self.peek() {
+            Some(b'0')          => self.i += 1,
+            Some(b'1' ... b'9') => self.digits(),
+            _                   => return self.err()
+        }
+        if self.peek() == Some(b'.') {
+            self.i += 1;
+            if !self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                return self.err()
+            }
+            self.digits()
+        }
+        if self.peek() == Some(b'e') || self.peek() == Some(b'E') {
+            self.i += 1;
+            if self.peek() == Some(b'+') || self.peek() == Some(b'-') {
+                self.i += 1
+            }
+            if !self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                return self.err()
+            }
+            self.digits()
+        }
+        let n = String::from_utf8_lossy(&self.s[start .. self.i]).into_owned();
+        Ok(Value::Number(n))
+    }
+
+    fn digits(&mut self) {
+        while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            self.i += 1
+        }
+    }
+
+    fn string(&mut self) -> Result<String, PayloadError> {
+        self.i += 1;
+        let mut out = Vec::new();
+        loop {
+            match self.peek() {
+                None        => return self.err(),
+                Some(b'"')  => { self.i += 1; break }
+                Some(b'\\') => {
+                    self.i += 1;
+                    let c = match self.peek() {
+                        Some(b'"')  => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/')  => '/',
+                        Some(b'b')  => '\u{8}',
+                        Some(b'f')  => '\u{c}',
+                        Some(b'n')  => '\n',
+                        Some(b'r')  => '\r',
+                        Some(b't')  => '\t',
+                        Some(b'u')  => try!(self.unicode()),
+                        _           => return self.err()
+                    };
+                    self.i += 1;
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes())
+                }
+                Some(c) if c < 0x20 => return self.err(),
+                Some(c)     => { out.push(c); self.i += 1 }
+            }
+        }
+        // The input is a `str` and escapes produce valid UTF-8.
+        String::from_utf8(out).or_else(|_| self.err())
+    }
+
+    // Parses `uXXXX` (after the backslash), including surrogate pairs.
+    // Leaves `i` at the last hex digit.
+    fn unicode(&mut self) -> Result<char, PayloadError> {
+        let hi = try!(self.hex4());
+        let cp = if hi >= 0xD800 && hi < 0xDC00 {
+            if !self.s[self.i + 1 ..].starts_with(b"\\u") {
+                return self.err()
+            }
+            self.i += 2;
+            let lo = try!(self.hex4());
+            if lo < 0xDC00 || lo >= 0xE000 {
+                return self.err()
+            }
+            0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00)
+        } else {
+            hi
+        };
+        ::std::char::from_u32(cp).ok_or(PayloadError::Syntax(self.i))
+    }
+
+    fn hex4(&mut self) -> Result<u32, PayloadError> {
+        if self.i + 5 > self.s.len() {
+            return self.err()
+        }
+        let h = &self.s[self.i + 1 .. self.i + 5];
+        let mut n = 0;
+        for &c in h {
+            n = n << 4 | match (c as char).to_digit(16) {
+                Some(d) => d,
+                None    => return self.err()
+            }
+        }
+        self.i += 4;
+        Ok(n)
+    }
+}
+
+// PayloadError /////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub enum PayloadError {
+    /// Malformed JSON at the given byte offset.
+    Syntax(usize),
+    Base64,
+    Decode(DecodeError),
+    MissingField(&'static str),
+    UnknownField(String),
+    DuplicateField(String),
+    InvalidField(&'static str),
+    /// The JSON `id` differs from the ID in the bundle.
+    IdMismatch(u16, u16),
+    DuplicatePreKey(u16),
+    /// The last resort prekey has an ID other than `MAX_PREKEY_ID`.
+    LastResort(u16)
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PayloadError::Syntax(i)             => write!(f, "PayloadError: invalid JSON at offset {}", i),
+            PayloadError::Base64                => write!(f, "PayloadError: invalid base64"),
+            PayloadError::Decode(ref e)         => write!(f, "PayloadError: decode error: {}", e),
+            PayloadError::MissingField(n)       => write!(f, "PayloadError: missing field: {}", n),
+            PayloadError::UnknownField(ref n)   => write!(f, "PayloadError: unknown field: {}", n),
+            PayloadError::DuplicateField(ref n) => write!(f, "PayloadError: duplicate field: {}", n),
+            PayloadError::InvalidField(n)       => write!(f, "PayloadError: invalid field: {}", n),
+            PayloadError::IdMismatch(a, b)      => write!(f, "PayloadError: id {} does not match prekey {}", a, b),
+            PayloadError::DuplicatePreKey(i)    => write!(f, "PayloadError: duplicate prekey: {}", i),
+            PayloadError::LastResort(i)         => write!(f, "PayloadError: prekey {} is not a last resort prekey", i)
+        }
+    }
+}
+
+impl Error for PayloadError {
+    fn description(&self) -> &str {
+        "PayloadError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            PayloadError::Decode(ref e) => Some(e),
+            _                           => None
+        }
+    }
+}
+
+impl From<DecodeError> for PayloadError {
+    fn from(e: DecodeError) -> PayloadError {
+        PayloadError::Decode(e)
+    }
+}
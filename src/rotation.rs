@@ -0,0 +1,171 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use proteus::keys::{MAX_PREKEY_ID, PreKeyBundle, PreKeyId};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use store::Store;
+use {CBox, CBoxAnyError, CBoxError, ErrorKind};
+
+/// Outcome of `CBox::rotate_prekeys`.
+#[derive(Default)]
+pub struct PreKeyRotation {
+    /// Replacements for the removed prekeys, to be uploaded.
+    pub new: Vec<PreKeyBundle>,
+    /// IDs of the deleted prekeys, which must be removed from the server.
+    pub removed: Vec<PreKeyId>
+}
+
+impl fmt::Debug for PreKeyRotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let new: Vec<PreKeyId> = self.new.iter().map(|b| b.prekey_id).collect();
+        f.debug_struct("PreKeyRotation")
+            .field("new", &new)
+            .field("removed", &self.removed)
+            .finish()
+    }
+}
+
+impl<S: Store> CBox<S> {
+    /// Replace all prekeys created more than `max_age` before the box's
+    /// clock by fresh ones. The last resort prekey is never rotated.
+    ///
+    /// Replacements take the IDs following the highest prekey ID in use,
+    /// wrapping around below `MAX_PREKEY_ID` and skipping IDs still in use,
+    /// including those being removed. Prekeys without a recorded creation
+    /// time are considered created now.
+    ///
+    /// Replacements are stored before the expired prekeys are deleted, so
+    /// an error leaves extra prekeys rather than missing ones. The
+    /// `RotationError` carries the replacements stored and the prekeys
+    /// deleted up to that point, which still need to be published.
+    pub fn rotate_prekeys(&self, max_age: Duration) -> Result<PreKeyRotation, RotationError<S>> {
+        let mut rotation = PreKeyRotation::default();
+        match self.rotate(max_age, &mut rotation) {
+            Ok(())  => Ok(rotation),
+            Err(e)  => Err(RotationError::new(rotation, e))
+        }
+    }
+
+    fn rotate(&self, max_age: Duration, rotation: &mut PreKeyRotation) -> Result<(), CBoxError<S>> {
+        let now    = self.clock.now();
+        let cutoff = now.saturating_sub(max_age.as_secs());
+        let ids    = try!(self.store.prekey_ids().map_err(CBoxError::StorageError));
+
+        let mut used    = BTreeSet::new();
+        let mut expired = Vec::new();
+        for id in ids {
+            used.insert(id.value());
+            if id == MAX_PREKEY_ID {
+                continue
+            }
+            match try!(self.store.prekey_created(id).map_err(CBoxError::StorageError)) {
+                Some(t) if t < cutoff => expired.push(id),
+                Some(_)               => (),
+                None                  => try!(self.store.save_prekey_created(id, now).map_err(CBoxError::StorageError))
+            }
+        }
+        expired.sort_by_key(|id| id.value());
+
+        let limit = MAX_PREKEY_ID.value();
+        let mut next = used.iter().cloned().filter(|&i| i != limit).max().map(|i| i + 1).unwrap_or(0);
+        for _ in 0 .. limit {
+            if rotation.new.len() == expired.len() {
+                break
+            }
+            if next >= limit {
+                next = 0
+            }
+            if used.insert(next) {
+                rotation.new.push(try!(self.new_prekey(PreKeyId::new(next))))
+            }
+            next += 1
+        }
+
+        for id in expired {
+            try!(self.store.delete_prekey(id).map_err(CBoxError::StorageError));
+            rotation.removed.push(id)
+        }
+        Ok(())
+    }
+}
+
+// RotationError ////////////////////////////////////////////////////////////
+
+/// A `CBoxError` raised part way through `CBox::rotate_prekeys`, together
+/// with the rotation performed until then.
+#[derive(Debug)]
+pub struct RotationError<S: Store> {
+    rotation: PreKeyRotation,
+    error:    CBoxError<S>
+}
+
+impl<S: Store> RotationError<S> {
+    fn new(rotation: PreKeyRotation, error: CBoxError<S>) -> RotationError<S> {
+        RotationError { rotation: rotation, error: error }
+    }
+
+    /// The prekeys stored and deleted before the error.
+    pub fn rotation(&self) -> &PreKeyRotation {
+        &self.rotation
+    }
+
+    pub fn into_rotation(self) -> PreKeyRotation {
+        self.rotation
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.error.kind()
+    }
+
+    pub fn error(&self) -> &CBoxError<S> {
+        &self.error
+    }
+
+    pub fn into_error(self) -> CBoxError<S> {
+        self.error
+    }
+}
+
+impl<S: Store> fmt::Display for RotationError<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "RotationError: {} new, {} removed ({}): {}",
+               self.rotation.new.len(), self.rotation.removed.len(), self.error.kind(), self.error)
+    }
+}
+
+impl<S: Store + fmt::Debug> Error for RotationError<S> {
+    fn description(&self) -> &str {
+        "RotationError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        Some(&self.error)
+    }
+}
+
+impl<S: Store> From<RotationError<S>> for CBoxError<S> {
+    fn from(e: RotationError<S>) -> CBoxError<S> {
+        e.error
+    }
+}
+
+impl<S: Store> From<RotationError<S>> for CBoxAnyError where S::Error: Send + Sync + 'static {
+    fn from(e: RotationError<S>) -> CBoxAnyError {
+        CBoxAnyError::from(e.error)
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cbor::{Decoder, Encoder, Config};
+use cbor::skip::Skip;
+use proteus::{DecodeError, EncodeError};
+use proteus::keys::{IdentityKey, IdentityKeyPair};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::secretbox;
+use std::io;
+
+const VERSION: u8 = 1;
+
+// Anonymous encryption to the Curve25519 form of an identity key
+// using a fresh ephemeral key pair. No session state is involved.
+pub struct SealedBox {
+    ephemeral: IdentityKey,
+    nonce:     secretbox::Nonce,
+    cipher:    Vec<u8>
+}
+
+impl SealedBox {
+    pub fn seal(recipient: &IdentityKey, plain: &[u8]) -> Result<SealedBox, EncodeError> {
+        let eph   = IdentityKeyPair::new();
+        let key   = try!(derive_key(&eph.secret_key.shared_secret(&recipient.public_key), &eph.public_key, recipient));
+        let nonce = secretbox::gen_nonce();
+        Ok(SealedBox {
+            ephemeral: eph.public_key,
+            cipher:    secretbox::seal(plain, &nonce, &key),
+            nonce:     nonce
+        })
+    }
+
+    /// Returns `None` if the box was not sealed for `ident` or has been
+    /// tampered with.
+    pub fn open(&self, ident: &IdentityKeyPair) -> Result<Option<Vec<u8>>, EncodeError> {
+        let ss  = ident.secret_key.shared_secret(&self.ephemeral.public_key);
+        let key = try!(derive_key(&ss, &self.ephemeral, &ident.public_key));
+        Ok(secretbox::open(&self.cipher, &self.nonce, &key).ok())
+    }
+
+    pub fn serialise(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut e = Encoder::new(io::Cursor::new(Vec::new()));
+        try!(self.encode(&mut e));
+        Ok(e.into_writer().into_inner())
+    }
+
+    pub fn deserialise(b: &[u8]) -> Result<SealedBox, DecodeError> {
+        SealedBox::decode(&mut Decoder::new(Config::default(), io::Cursor::new(b)))
+    }
+
+    fn encode<W: io::Write>(&self, e: &mut Encoder<W>) -> Result<(), EncodeError> {
+        try!(e.object(4));
+        try!(e.u8(0)); try!(e.u8(VERSION));
+        try!(e.u8(1)); try!(self.ephemeral.encode(e));
+        try!(e.u8(2)); try!(e.bytes(&self.nonce.0));
+        try!(e.u8(3)); try!(e.bytes(&self.cipher));
+        Ok(())
+    }
+
+    fn decode<R: io::Read + Skip>(d: &mut Decoder<R>) -> Result<SealedBox, DecodeError> {
+        let n = try!(d.object());
+        let mut version   = None;
+        let mut ephemeral = None;
+        let mut nonce     = None;
+        let mut cipher    = None;
+        for _ in 0 .. n {
+            match try!(d.u8()) {
+                0 =>
+                    if version.is_some() {
+                        return Err(DecodeError::DuplicateField("sealed box version"))
+                    } else {
+                        version = Some(try!(d.u8()))
+                    },
+                1 =>
+                    if ephemeral.is_some() {
+                        return Err(DecodeError::DuplicateField("sealed box ephemeral key"))
+                    } else {
+                        ephemeral = Some(try!(IdentityKey::decode(d)))
+                    },
+                2 =>
+                    if nonce.is_some() {
+                        return Err(DecodeError::DuplicateField("sealed box nonce"))
+                    } else {
+                        let b = try!(d.bytes());
+                        match secretbox::Nonce::from_slice(&b) {
+                            Some(x) => nonce = Some(x),
+                            None    => return Err(DecodeError::InvalidArrayLen(b.len()))
+                        }
+                    },
+                3 =>
+                    if cipher.is_some() {
+                        return Err(DecodeError::DuplicateField("sealed box cipher text"))
+                    } else {
+                        cipher = Some(try!(d.bytes()))
+                    },
+                _ => try!(d.skip())
+            }
+        }
+        match try!(version.ok_or(DecodeError::MissingField("sealed box version"))) {
+            VERSION => (),
+            v       => return Err(DecodeError::InvalidType(v, "unknown sealed box version"))
+        }
+        Ok(SealedBox {
+            ephemeral: try!(ephemeral.ok_or(DecodeError::MissingField("sealed box ephemeral key"))),
+            nonce:     try!(nonce.ok_or(DecodeError::MissingField("sealed box nonce"))),
+            cipher:    try!(cipher.ok_or(DecodeError::MissingField("sealed box cipher text")))
+        })
+    }
+}
+
+// The symmetric key is bound to both the ephemeral and the recipient's
+// public key so that a box cannot be re-targeted at another identity.
+fn derive_key(ss: &[u8], eph: &IdentityKey, recipient: &IdentityKey) -> Result<secretbox::Key, EncodeError> {
+    let mut input = Vec::new();
+    input.extend_from_slice(ss);
+    input.extend_from_slice(&try!(eph.serialise()));
+    input.extend_from_slice(&try!(recipient.serialise()));
+    let sha256::Digest(k) = sha256::hash(&input);
+    Ok(secretbox::Key(k))
+}
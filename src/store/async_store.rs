@@ -0,0 +1,77 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use identity::Identity;
+use proteus::keys::{IdentityKeyPair, PreKey, PreKeyId};
+use proteus::session::Session;
+use std::borrow::Borrow;
+use super::{ConsumedPreKeys, Generation};
+use super::meta::SessionMeta;
+
+pub type StoreFuture<A, E> = BoxFuture<'static, Result<A, E>>;
+
+/// Asynchronous counterpart of `Store`.
+///
+/// The returned futures do not borrow from the store or the arguments, so
+/// implementations typically serialise their input up front and hold their
+/// state in an `Arc`.
+pub trait AsyncStore {
+    type Error: ::std::error::Error + Send + Sync + 'static;
+
+    fn load_session<I>(&self, li: I, id: &str) -> StoreFuture<Option<(Session<I>, Generation)>, Self::Error>
+        where I: Borrow<IdentityKeyPair> + Send + 'static;
+
+    fn save_session<I>(&self, id: &str, s: &Session<I>, expected: Generation) -> StoreFuture<Option<Generation>, Self::Error>
+        where I: Borrow<IdentityKeyPair>;
+
+    fn delete_session(&self, id: &str) -> StoreFuture<(), Self::Error>;
+
+    /// Stores without metadata support can rely on the default, which
+    /// never finds any.
+    fn load_session_meta(&self, _id: &str) -> StoreFuture<Option<SessionMeta>, Self::Error> {
+        future::ok(None).boxed()
+    }
+
+    /// The default discards the metadata.
+    fn save_session_meta(&self, _id: &str, _meta: &SessionMeta) -> StoreFuture<(), Self::Error> {
+        future::ok(()).boxed()
+    }
+
+    fn load_identity(&self) -> StoreFuture<Option<Identity<'static>>, Self::Error>;
+    fn save_identity(&self, id: &Identity) -> StoreFuture<(), Self::Error>;
+
+    fn load_prekey(&self, id: PreKeyId) -> StoreFuture<Option<PreKey>, Self::Error>;
+    fn add_prekey(&self, key: &PreKey) -> StoreFuture<(), Self::Error>;
+
+    /// Record when the prekey `id` was created. The default discards it.
+    fn save_prekey_created(&self, _id: PreKeyId, _time: u64) -> StoreFuture<(), Self::Error> {
+        future::ok(()).boxed()
+    }
+    fn delete_prekey(&self, id: PreKeyId) -> StoreFuture<(), Self::Error>;
+
+    /// Like `Store::consumed_prekeys`, the default keeps no records.
+    fn consumed_prekeys(&self) -> StoreFuture<Vec<ConsumedPreKeys>, Self::Error> {
+        future::ok(Vec::new()).boxed()
+    }
+
+    fn save_consumed_prekeys(&self, _c: &ConsumedPreKeys) -> StoreFuture<(), Self::Error> {
+        future::ok(()).boxed()
+    }
+
+    fn delete_consumed_prekeys(&self, _session: &str) -> StoreFuture<(), Self::Error> {
+        future::ok(()).boxed()
+    }
+}
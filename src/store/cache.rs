@@ -0,0 +1,375 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use identity::Identity;
+use proteus::{DecodeError, EncodeError};
+use proteus::keys::{IdentityKeyPair, PreKey, PreKeyId};
+use proteus::session::Session;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard};
+use super::*;
+use super::meta::SessionMeta;
+
+// CacheStore ///////////////////////////////////////////////////////////////
+
+/// Read-through cache of sessions and the identity in front of another
+/// `Store`.
+///
+/// Entries are kept serialised and replaced on every write going through
+/// the cache. Writes by other processes to the inner store are not seen
+/// until the entry is evicted, but saving a stale session still fails with
+/// a conflict since the inner store checks generations. Prekeys are not
+/// cached since a prekey consumed by another process must not be used
+/// again.
+#[derive(Debug)]
+pub struct CacheStore<S> {
+    inner:    S,
+    capacity: usize,
+    identity: Mutex<Option<Vec<u8>>>,
+    sessions: Mutex<HashMap<String, (Vec<u8>, Generation)>>
+}
+
+impl<S: Store> CacheStore<S> {
+    /// Cache at most `capacity` sessions. A capacity of 0 caches only the
+    /// identity.
+    pub fn new(inner: S, capacity: usize) -> CacheStore<S> {
+        CacheStore {
+            inner:    inner,
+            capacity: capacity,
+            identity: Mutex::new(None),
+            sessions: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Drop all cached entries.
+    pub fn clear(&self) {
+        *lock(&self.identity) = None;
+        lock(&self.sessions).clear()
+    }
+
+    fn cache_session(&self, id: &str, b: Vec<u8>, g: Generation) {
+        let mut m = lock(&self.sessions);
+        if make_room(&mut m, self.capacity, id) {
+            m.insert(id.to_string(), (b, g));
+        }
+    }
+
+    fn forget_session(&self, id: &str) {
+        lock(&self.sessions).remove(id);
+    }
+
+    // The inner store has already been written, so a session which does
+    // not serialise is merely not cached.
+    fn after_save<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, g: Option<Generation>) {
+        match (g, s.serialise()) {
+            (Some(g), Ok(b)) => self.cache_session(id, b, g),
+            _                => self.forget_session(id)
+        }
+    }
+
+    fn after_save_data(&self, id: &str, data: &[u8], g: Option<Generation>) {
+        match g {
+            Some(g) => self.cache_session(id, data.to_vec(), g),
+            None    => self.forget_session(id)
+        }
+    }
+
+    fn after_commit_data(&self, id: &str, data: &[u8], r: Result<Commit<S::Error>, S::Error>) -> CacheResult<Commit<CacheError<S::Error>>, S::Error> {
+        match r {
+            Ok(c)  => {
+                self.after_save_data(id, data, c.generation());
+                Ok(c.map_err(CacheError::Store))
+            }
+            Err(e) => {
+                self.forget_session(id);
+                Err(CacheError::Store(e))
+            }
+        }
+    }
+}
+
+// Evict an arbitrary entry if `k` would exceed the capacity. Returns
+// whether `k` may be inserted.
+fn make_room<K, V, Q: ?Sized>(m: &mut HashMap<K, V>, capacity: usize, k: &Q) -> bool
+    where K: Borrow<Q> + Hash + Eq + Clone,
+          Q: Hash + Eq
+{
+    if capacity == 0 {
+        return false
+    }
+    if m.contains_key(k) || m.len() < capacity {
+        return true
+    }
+    let victim = m.keys().next().cloned();
+    if let Some(v) = victim {
+        m.remove(&v);
+    }
+    true
+}
+
+// A panic while holding a lock leaves the cache consistent, since every
+// update is a single insertion or removal.
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<S: Store> Store for CacheStore<S> {
+    type Error = CacheError<S::Error>;
+
+    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> CacheResult<Option<(Session<I>, Generation)>, S::Error> {
+        let cached = lock(&self.sessions).get(id).cloned();
+        if let Some((b, g)) = cached {
+            return Ok(Some((try!(Session::deserialise(li, &b)), g)))
+        }
+        match try!(self.inner.load_session(li, id).map_err(CacheError::Store)) {
+            Some((s, g)) => {
+                self.cache_session(id, try!(s.serialise()), g);
+                Ok(Some((s, g)))
+            }
+            None => Ok(None)
+        }
+    }
+
+    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation) -> CacheResult<Option<Generation>, S::Error> {
+        match self.inner.save_session(id, s, expected) {
+            Ok(g)  => {
+                self.after_save(id, s, g);
+                Ok(g)
+            }
+            Err(e) => {
+                self.forget_session(id);
+                Err(CacheError::Store(e))
+            }
+        }
+    }
+
+    fn delete_session(&self, id: &str) -> CacheResult<(), S::Error> {
+        self.forget_session(id);
+        self.inner.delete_session(id).map_err(CacheError::Store)
+    }
+
+    fn archive_session(&self, id: &str) -> CacheResult<(), S::Error> {
+        self.forget_session(id);
+        self.inner.archive_session(id).map_err(CacheError::Store)
+    }
+
+    fn session_ids(&self) -> CacheResult<Vec<String>, S::Error> {
+        self.inner.session_ids().map_err(CacheError::Store)
+    }
+
+    fn sessions_by_identity(&self, fingerprint: &str) -> CacheResult<Vec<String>, S::Error> {
+        self.inner.sessions_by_identity(fingerprint).map_err(CacheError::Store)
+    }
+
+    fn session_identity(&self, id: &str) -> CacheResult<Option<String>, S::Error> {
+        self.inner.session_identity(id).map_err(CacheError::Store)
+    }
+
+    fn load_session_meta(&self, id: &str) -> CacheResult<Option<SessionMeta>, S::Error> {
+        self.inner.load_session_meta(id).map_err(CacheError::Store)
+    }
+
+    fn save_session_meta(&self, id: &str, meta: &SessionMeta) -> CacheResult<(), S::Error> {
+        self.inner.save_session_meta(id, meta).map_err(CacheError::Store)
+    }
+
+    fn load_identity<'s>(&self) -> CacheResult<Option<Identity<'s>>, S::Error> {
+        let cached = lock(&self.identity).clone();
+        if let Some(b) = cached {
+            return Ok(Some(try!(Identity::deserialise(&b))))
+        }
+        match try!(self.inner.load_identity().map_err(CacheError::Store)) {
+            Some(i) => {
+                *lock(&self.identity) = Some(try!(i.serialise()));
+                Ok(Some(i))
+            }
+            None => Ok(None)
+        }
+    }
+
+    fn save_identity(&self, id: &Identity) -> CacheResult<(), S::Error> {
+        *lock(&self.identity) = None;
+        try!(self.inner.save_identity(id).map_err(CacheError::Store));
+        *lock(&self.identity) = Some(try!(id.serialise()));
+        Ok(())
+    }
+
+    fn load_prekey(&self, id: PreKeyId) -> CacheResult<Option<PreKey>, S::Error> {
+        self.inner.load_prekey(id).map_err(CacheError::Store)
+    }
+
+    fn add_prekey(&self, key: &PreKey) -> CacheResult<(), S::Error> {
+        self.inner.add_prekey(key).map_err(CacheError::Store)
+    }
+
+    fn delete_prekey(&self, id: PreKeyId) -> CacheResult<(), S::Error> {
+        self.inner.delete_prekey(id).map_err(CacheError::Store)
+    }
+
+    fn prekey_ids(&self) -> CacheResult<Vec<PreKeyId>, S::Error> {
+        self.inner.prekey_ids().map_err(CacheError::Store)
+    }
+
+    fn prekey_created(&self, id: PreKeyId) -> CacheResult<Option<u64>, S::Error> {
+        self.inner.prekey_created(id).map_err(CacheError::Store)
+    }
+
+    fn save_prekey_created(&self, id: PreKeyId, time: u64) -> CacheResult<(), S::Error> {
+        self.inner.save_prekey_created(id, time).map_err(CacheError::Store)
+    }
+
+    fn consumed_prekeys(&self) -> CacheResult<Vec<ConsumedPreKeys>, S::Error> {
+        self.inner.consumed_prekeys().map_err(CacheError::Store)
+    }
+
+    fn save_consumed_prekeys(&self, c: &ConsumedPreKeys) -> CacheResult<(), S::Error> {
+        self.inner.save_consumed_prekeys(c).map_err(CacheError::Store)
+    }
+
+    fn delete_consumed_prekeys(&self, session: &str) -> CacheResult<(), S::Error> {
+        self.inner.delete_consumed_prekeys(session).map_err(CacheError::Store)
+    }
+
+    fn commit_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation, removed: &[PreKeyId]) -> CacheResult<Commit<CacheError<S::Error>>, S::Error> {
+        match self.inner.commit_session(id, s, expected, removed) {
+            Ok(c)  => {
+                self.after_save(id, s, c.generation());
+                Ok(c.map_err(CacheError::Store))
+            }
+            Err(e) => {
+                self.forget_session(id);
+                Err(CacheError::Store(e))
+            }
+        }
+    }
+}
+
+impl<S: RawStore> RawStore for CacheStore<S> {
+    fn load_session_data(&self, id: &str) -> CacheResult<Option<(Vec<u8>, Generation)>, S::Error> {
+        let cached = lock(&self.sessions).get(id).cloned();
+        if cached.is_some() {
+            return Ok(cached)
+        }
+        match try!(self.inner.load_session_data(id).map_err(CacheError::Store)) {
+            Some((b, g)) => {
+                self.cache_session(id, b.clone(), g);
+                Ok(Some((b, g)))
+            }
+            None => Ok(None)
+        }
+    }
+
+    fn save_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation) -> CacheResult<Option<Generation>, S::Error> {
+        match self.inner.save_session_data(id, data, remote, expected) {
+            Ok(g)  => {
+                self.after_save_data(id, data, g);
+                Ok(g)
+            }
+            Err(e) => {
+                self.forget_session(id);
+                Err(CacheError::Store(e))
+            }
+        }
+    }
+
+    fn load_identity_data(&self) -> CacheResult<Option<Vec<u8>>, S::Error> {
+        let cached = lock(&self.identity).clone();
+        if cached.is_some() {
+            return Ok(cached)
+        }
+        let b = try!(self.inner.load_identity_data().map_err(CacheError::Store));
+        *lock(&self.identity) = b.clone();
+        Ok(b)
+    }
+
+    fn save_identity_data(&self, data: &[u8]) -> CacheResult<(), S::Error> {
+        *lock(&self.identity) = None;
+        try!(self.inner.save_identity_data(data).map_err(CacheError::Store));
+        *lock(&self.identity) = Some(data.to_vec());
+        Ok(())
+    }
+
+    fn load_prekey_data(&self, id: PreKeyId) -> CacheResult<Option<Vec<u8>>, S::Error> {
+        self.inner.load_prekey_data(id).map_err(CacheError::Store)
+    }
+
+    fn add_prekey_data(&self, id: PreKeyId, data: &[u8]) -> CacheResult<(), S::Error> {
+        self.inner.add_prekey_data(id, data).map_err(CacheError::Store)
+    }
+
+    fn commit_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation, removed: &[PreKeyId]) -> CacheResult<Commit<CacheError<S::Error>>, S::Error> {
+        let r = self.inner.commit_session_data(id, data, remote, expected, removed);
+        self.after_commit_data(id, data, r)
+    }
+}
+
+// CacheError ///////////////////////////////////////////////////////////////
+
+pub type CacheResult<A, E> = Result<A, CacheError<E>>;
+
+#[derive(Debug)]
+pub enum CacheError<E> {
+    Store(E),
+    Decode(DecodeError),
+    Encode(EncodeError)
+}
+
+impl<E: fmt::Display> fmt::Display for CacheError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CacheError::Store(ref e)  => write!(f, "CacheError: {}", e),
+            CacheError::Decode(ref e) => write!(f, "CacheError: Decode error: {}", e),
+            CacheError::Encode(ref e) => write!(f, "CacheError: Encode error: {}", e)
+        }
+    }
+}
+
+impl<E: Error> Error for CacheError<E> {
+    fn description(&self) -> &str {
+        "CacheError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            CacheError::Store(ref e)  => Some(e),
+            CacheError::Decode(ref e) => Some(e),
+            CacheError::Encode(ref e) => Some(e)
+        }
+    }
+}
+
+impl<E> From<DecodeError> for CacheError<E> {
+    fn from(e: DecodeError) -> CacheError<E> {
+        CacheError::Decode(e)
+    }
+}
+
+impl<E> From<EncodeError> for CacheError<E> {
+    fn from(e: EncodeError) -> CacheError<E> {
+        CacheError::Encode(e)
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use super::kv::{KeyValue, KvStore, Op};
+
+// File layout:
+//
+//   header    MAGIC, u16 version
+//   record*   u32 length, u32 check, payload, sha256(payload)
+//
+// `check` is the start of the SHA-256 digest of the length, so that a
+// damaged length is told apart from a record cut short.
+//
+// A payload is a u32 operation count followed by the operations, each
+// being a tag (PUT or DELETE), a u32 key length and the key and, for PUT,
+// a u32 value length and the value. A record is applied as a whole or not
+// at all.
+//
+// Only the last record can be torn by an interrupted append. On open, an
+// incomplete or corrupt last record is truncated, as is a header cut short
+// while creating the file. A corrupt record followed by others, or one
+// whose length does not match its check, means the file has been damaged
+// otherwise; opening it fails rather than dropping the valid records
+// behind it.
+const MAGIC:   &'static [u8] = b"CBOXCTNR";
+const VERSION: u16 = 1;
+const HEADER:  u64 = 10;
+
+const PUT:    u8 = 0;
+const DELETE: u8 = 1;
+
+// Compaction runs once at least this many bytes are garbage and garbage
+// makes up more than half of the file.
+const COMPACT_MIN: u64 = 64 * 1024;
+
+pub type ContainerStore = KvStore<ContainerFile>;
+
+impl ContainerStore {
+    pub fn open(path: &Path) -> io::Result<ContainerStore> {
+        Ok(KvStore::new(try!(ContainerFile::open(path))))
+    }
+}
+
+// ContainerFile ////////////////////////////////////////////////////////////
+
+/// `KeyValue` engine keeping all entries in one append-only file.
+///
+/// The file is locked exclusively while open. An in-memory index maps
+/// every key to the position of its latest value.
+#[derive(Debug)]
+pub struct ContainerFile {
+    path:      PathBuf,
+    recovered: u64,
+    inner:     Mutex<Inner>
+}
+
+#[derive(Debug)]
+struct Inner {
+    file:  File,
+    index: BTreeMap<Vec<u8>, Slot>,
+    size:  u64,
+    dead:  u64
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    offset: u64,
+    len:    u32
+}
+
+impl ContainerFile {
+    pub fn open(path: &Path) -> io::Result<ContainerFile> {
+        let mut file = try!(OpenOptions::new().read(true).write(true).create(true).open(path));
+        try!(lock_exclusive(&file));
+        let len = try!(file.metadata()).len();
+        if len < HEADER {
+            try!(check_torn_header(&mut file, len));
+            try!(file.set_len(0));
+            try!(write_header(&mut file));
+            try!(file.sync_all());
+        } else {
+            try!(read_header(&mut file));
+        }
+        let mut inner = Inner {
+            file:  file,
+            index: BTreeMap::new(),
+            size:  HEADER,
+            dead:  0
+        };
+        try!(inner.replay());
+        let recovered = len.saturating_sub(inner.size);
+        if recovered > 0 {
+            try!(inner.file.set_len(inner.size));
+            try!(inner.file.sync_all());
+        }
+        Ok(ContainerFile {
+            path:      path.to_path_buf(),
+            recovered: recovered,
+            inner:     Mutex::new(inner)
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of bytes of an incomplete or corrupt last record which were
+    /// truncated when the container was opened.
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// Rewrite the container with only the current values.
+    pub fn compact(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.compact(&self.path)
+    }
+}
+
+impl KeyValue for ContainerFile {
+    type Error = io::Error;
+
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.get(key)
+    }
+
+    fn keys(&self, prefix: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let inner = self.inner.lock().unwrap();
+        let keys = inner.index.range(prefix.to_vec() ..)
+            .take_while(|&(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect();
+        Ok(keys)
+    }
+
+    fn write(&self, guard: Option<(&[u8], Option<&[u8]>)>, ops: &[Op]) -> io::Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((key, expected)) = guard {
+            let current = try!(inner.get(key));
+            if current.as_ref().map(|v| &v[..]) != expected {
+                return Ok(false)
+            }
+        }
+        try!(inner.append(ops));
+        // The write is durable at this point. A failed compaction leaves
+        // the container as it was and is tried again on a later write.
+        if inner.dead >= COMPACT_MIN && inner.dead * 2 > inner.size {
+            let _ = inner.compact(&self.path);
+        }
+        Ok(true)
+    }
+}
+
+impl Inner {
+    fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let slot = match self.index.get(key) {
+            Some(s) => *s,
+            None    => return Ok(None)
+        };
+        let mut v = vec![0; slot.len as usize];
+        try!(self.file.seek(SeekFrom::Start(slot.offset)));
+        try!(self.file.read_exact(&mut v));
+        Ok(Some(v))
+    }
+
+    // Read all records from the start, stopping at an incomplete or invalid
+    // last record. An invalid record elsewhere is an error.
+    fn replay(&mut self) -> io::Result<()> {
+        let end = try!(self.file.metadata()).len();
+        try!(self.file.seek(SeekFrom::Start(HEADER)));
+        let mut r = io::BufReader::new(try!(self.file.try_clone()));
+        let mut pos = HEADER;
+        let mut batch = Vec::new();
+        loop {
+            if end - pos < 8 {
+                break
+            }
+            let len = try!(r.read_u32::<BigEndian>());
+            if try!(r.read_u32::<BigEndian>()) != length_check(len) {
+                return Err(invalid_data(&format!("corrupt container record length at offset {}", pos)))
+            }
+            let len = len as u64;
+            if end - pos - 8 < len + sha256::DIGESTBYTES as u64 {
+                break
+            }
+            let mut payload = vec![0; len as usize];
+            try!(r.read_exact(&mut payload));
+            let mut digest = [0; sha256::DIGESTBYTES];
+            try!(r.read_exact(&mut digest));
+            let next = pos + 8 + len + sha256::DIGESTBYTES as u64;
+            batch.clear();
+            if sha256::hash(&payload).0 != digest || decode_payload(&payload, pos + 8, &mut batch).is_err() {
+                if next == end {
+                    break
+                }
+                return Err(invalid_data(&format!("corrupt container record at offset {}", pos)))
+            }
+            pos = next;
+            self.apply_batch(&batch);
+            self.size = pos
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, ops: &[Op]) -> io::Result<()> {
+        let start   = self.size;
+        let payload = try!(encode_payload(ops));
+        let mut rec = Vec::with_capacity(payload.len() + 8 + sha256::DIGESTBYTES);
+        try!(rec.write_u32::<BigEndian>(payload.len() as u32));
+        try!(rec.write_u32::<BigEndian>(length_check(payload.len() as u32)));
+        rec.extend_from_slice(&payload);
+        rec.extend_from_slice(&sha256::hash(&payload).0);
+
+        fn write(f: &mut File, at: u64, rec: &[u8]) -> io::Result<()> {
+            try!(f.seek(SeekFrom::Start(at)));
+            try!(f.write_all(rec));
+            f.sync_data()
+        }
+        if let Err(e) = write(&mut self.file, start, &rec) {
+            let _ = self.file.set_len(start);
+            return Err(e)
+        }
+        self.size = start + rec.len() as u64;
+
+        let mut batch = Vec::new();
+        try!(decode_payload(&payload, start + 8, &mut batch));
+        self.apply_batch(&batch);
+        Ok(())
+    }
+
+    // Update the index and account for values which are no longer live.
+    fn apply_batch(&mut self, batch: &[(Vec<u8>, Option<Slot>)]) {
+        for &(ref k, s) in batch {
+            if s.is_none() {
+                self.dead += record_overhead(k)
+            }
+            if let Some(old) = apply(&mut self.index, k, s) {
+                self.dead += old.len as u64 + record_overhead(k)
+            }
+        }
+    }
+
+    // Write the live entries to a temporary file which then replaces the
+    // container. The temporary file is locked before it becomes visible.
+    fn compact(&mut self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = try!(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&tmp));
+        try!(lock_exclusive(&file));
+        let rs = self.copy_live(&mut file);
+        let (index, size) = match rs {
+            Ok(x)  => x,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e)
+            }
+        };
+        if let Err(e) = fs::rename(&tmp, path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e)
+        }
+        // From here on `path` is the new file, whether or not the rename
+        // has reached the disk yet.
+        self.file  = file;
+        self.index = index;
+        self.size  = size;
+        self.dead  = 0;
+        sync_dir(path)
+    }
+
+    fn copy_live(&mut self, file: &mut File) -> io::Result<(BTreeMap<Vec<u8>, Slot>, u64)> {
+        try!(write_header(file));
+        let mut index = BTreeMap::new();
+        let mut size  = HEADER;
+        let keys: Vec<Vec<u8>> = self.index.keys().cloned().collect();
+        {
+            let mut w = io::BufWriter::new(&mut *file);
+            for k in keys {
+                let v = match try!(self.get(&k)) {
+                    Some(v) => v,
+                    None    => continue
+                };
+                let payload = try!(encode_payload(&[Op::Put(k.clone(), v)]));
+                try!(w.write_u32::<BigEndian>(payload.len() as u32));
+                try!(w.write_u32::<BigEndian>(length_check(payload.len() as u32)));
+                try!(w.write_all(&payload));
+                try!(w.write_all(&sha256::hash(&payload).0));
+                let mut batch = Vec::new();
+                try!(decode_payload(&payload, size + 8, &mut batch));
+                for (k, s) in batch {
+                    apply(&mut index, &k, s);
+                }
+                size += 8 + payload.len() as u64 + sha256::DIGESTBYTES as u64
+            }
+            try!(w.flush());
+        }
+        try!(file.sync_all());
+        Ok((index, size))
+    }
+}
+
+// Update the index, returning the slot which has been replaced.
+fn apply(index: &mut BTreeMap<Vec<u8>, Slot>, k: &[u8], s: Option<Slot>) -> Option<Slot> {
+    match s {
+        Some(s) => index.insert(k.to_vec(), s),
+        None    => index.remove(k)
+    }
+}
+
+// Approximate share of an operation in the size of its record.
+fn record_overhead(k: &[u8]) -> u64 {
+    1 + 4 + k.len() as u64 + 4 + 8 + sha256::DIGESTBYTES as u64
+}
+
+fn length_check(len: u32) -> u32 {
+    let mut b = [0; 4];
+    BigEndian::write_u32(&mut b, len);
+    BigEndian::read_u32(&sha256::hash(&b).0[.. 4])
+}
+
+fn encode_payload(ops: &[Op]) -> io::Result<Vec<u8>> {
+    let mut b = Vec::new();
+    try!(b.write_u32::<BigEndian>(ops.len() as u32));
+    for op in ops {
+        match *op {
+            Op::Put(ref k, ref v) => {
+                try!(b.write_u8(PUT));
+                try!(b.write_u32::<BigEndian>(k.len() as u32));
+                b.extend_from_slice(k);
+                try!(b.write_u32::<BigEndian>(v.len() as u32));
+                b.extend_from_slice(v)
+            }
+            Op::Delete(ref k) => {
+                try!(b.write_u8(DELETE));
+                try!(b.write_u32::<BigEndian>(k.len() as u32));
+                b.extend_from_slice(k)
+            }
+        }
+    }
+    Ok(b)
+}
+
+// Decode a payload which starts at file offset `base` into keys and the
+// slots of their new values (`None` for deletions).
+fn decode_payload(p: &[u8], base: u64, out: &mut Vec<(Vec<u8>, Option<Slot>)>) -> io::Result<()> {
+    let mut r = io::Cursor::new(p);
+    let n = try!(r.read_u32::<BigEndian>());
+    for _ in 0 .. n {
+        let tag = try!(r.read_u8());
+        let kl  = try!(r.read_u32::<BigEndian>()) as usize;
+        let mut k = vec![0; kl];
+        try!(r.read_exact(&mut k));
+        match tag {
+            PUT => {
+                let vl  = try!(r.read_u32::<BigEndian>());
+                let off = r.position();
+                if off + vl as u64 > p.len() as u64 {
+                    return Err(invalid_data("value exceeds record"))
+                }
+                r.set_position(off + vl as u64);
+                out.push((k, Some(Slot { offset: base + off, len: vl })))
+            }
+            DELETE => out.push((k, None)),
+            _      => return Err(invalid_data("unknown operation"))
+        }
+    }
+    Ok(())
+}
+
+fn write_header(f: &mut File) -> io::Result<()> {
+    try!(f.seek(SeekFrom::Start(0)));
+    try!(f.write_all(MAGIC));
+    try!(f.write_u16::<BigEndian>(VERSION));
+    Ok(())
+}
+
+// A header of `len` bytes is torn if it is a prefix of a valid one.
+fn check_torn_header(f: &mut File, len: u64) -> io::Result<()> {
+    let mut expected = MAGIC.to_vec();
+    try!(expected.write_u16::<BigEndian>(VERSION));
+    let mut actual = vec![0; len as usize];
+    try!(f.seek(SeekFrom::Start(0)));
+    try!(f.read_exact(&mut actual));
+    if !expected.starts_with(&actual) {
+        return Err(invalid_data("not a cryptobox container"))
+    }
+    Ok(())
+}
+
+fn read_header(f: &mut File) -> io::Result<()> {
+    let mut magic = [0; 8];
+    try!(f.seek(SeekFrom::Start(0)));
+    try!(f.read_exact(&mut magic));
+    if &magic[..] != MAGIC {
+        return Err(invalid_data("not a cryptobox container"))
+    }
+    if try!(f.read_u16::<BigEndian>()) != VERSION {
+        return Err(invalid_data("unsupported container version"))
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(unix)]
+fn sync_dir(p: &Path) -> io::Result<()> {
+    match p.parent() {
+        Some(d) if d != Path::new("") => try!(File::open(d)).sync_all(),
+        _                              => try!(File::open(".")).sync_all()
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Another process holding the container fails to open it rather than
+// waiting.
+#[cfg(unix)]
+fn lock_exclusive(f: &File) -> io::Result<()> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_: &File) -> io::Result<()> {
+    Ok(())
+}
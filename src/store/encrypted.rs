@@ -0,0 +1,308 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use identity::Identity;
+use proteus::{DecodeError, EncodeError};
+use proteus::keys::{IdentityKeyPair, PreKey, PreKeyId};
+use proteus::session::Session;
+use sodiumoxide::crypto::auth::hmacsha256::{self, Tag};
+use sodiumoxide::crypto::secretbox;
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use super::*;
+use super::meta::SessionMeta;
+
+const VERSION: u8 = 1;
+
+// EncryptedStore ///////////////////////////////////////////////////////////
+
+/// Encrypts the sessions, the identity and the prekeys of another store.
+///
+/// Encryption needs the serialised entries, so the inner store must be a
+/// `RawStore`. `EncryptedStore` is one itself, with plain text entries,
+/// and can be stacked further.
+///
+/// Every entry is encrypted with a key derived from the master key and
+/// the entry's name, which binds it to that entry. IDs, generations, the
+/// remote identity index, session metadata and prekey creation times are
+/// stored in plain text. Anything reading the inner store directly, e.g.
+/// `FileStore::check`, sees only ciphertext.
+pub struct EncryptedStore<S> {
+    inner: S,
+    key:   secretbox::Key
+}
+
+impl<S: fmt::Debug> fmt::Debug for EncryptedStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "EncryptedStore {{ inner: {:?} }}", self.inner)
+    }
+}
+
+impl<S: RawStore> EncryptedStore<S> {
+    pub fn new(inner: S, key: secretbox::Key) -> EncryptedStore<S> {
+        EncryptedStore { inner: inner, key: key }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn entry_key(&self, name: &[u8]) -> secretbox::Key {
+        let Tag(t) = hmacsha256::authenticate(name, &hmacsha256::Key(self.key.0));
+        secretbox::Key(t)
+    }
+
+    fn seal(&self, name: &[u8], v: &[u8]) -> Vec<u8> {
+        let nonce = secretbox::gen_nonce();
+        let mut b = Vec::with_capacity(1 + secretbox::NONCEBYTES + v.len() + secretbox::MACBYTES);
+        b.push(VERSION);
+        b.extend_from_slice(&nonce.0);
+        b.extend_from_slice(&secretbox::seal(v, &nonce, &self.entry_key(name)));
+        b
+    }
+
+    fn open(&self, name: &[u8], b: &[u8]) -> EncryptedResult<Vec<u8>, S::Error> {
+        if b.len() < 1 + secretbox::NONCEBYTES || b[0] != VERSION {
+            return Err(EncryptedError::Decrypt)
+        }
+        let nonce = match secretbox::Nonce::from_slice(&b[1 .. 1 + secretbox::NONCEBYTES]) {
+            Some(n) => n,
+            None    => return Err(EncryptedError::Decrypt)
+        };
+        secretbox::open(&b[1 + secretbox::NONCEBYTES ..], &nonce, &self.entry_key(name))
+            .map_err(|()| EncryptedError::Decrypt)
+    }
+
+    fn open_opt(&self, name: &[u8], b: Option<Vec<u8>>) -> EncryptedResult<Option<Vec<u8>>, S::Error> {
+        match b {
+            Some(b) => self.open(name, &b).map(Some),
+            None    => Ok(None)
+        }
+    }
+}
+
+// Entry names, as used for key derivation.
+
+fn session_name(id: &str) -> Vec<u8> {
+    let mut n = b"session/".to_vec();
+    n.extend_from_slice(id.as_bytes());
+    n
+}
+
+fn prekey_name(id: PreKeyId) -> Vec<u8> {
+    let mut n = b"prekey/".to_vec();
+    n.write_u16::<BigEndian>(id.value()).unwrap();
+    n
+}
+
+const IDENTITY_NAME: &'static [u8] = b"identity/local";
+
+impl<S: RawStore> Store for EncryptedStore<S> {
+    type Error = EncryptedError<S::Error>;
+
+    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> EncryptedResult<Option<(Session<I>, Generation)>, S::Error> {
+        match try!(self.load_session_data(id)) {
+            Some((b, g)) => Ok(Some((try!(Session::deserialise(li, &b)), g))),
+            None         => Ok(None)
+        }
+    }
+
+    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation) -> EncryptedResult<Option<Generation>, S::Error> {
+        let remote = s.remote_identity().fingerprint();
+        self.save_session_data(id, &try!(s.serialise()), &remote, expected)
+    }
+
+    fn delete_session(&self, id: &str) -> EncryptedResult<(), S::Error> {
+        self.inner.delete_session(id).map_err(EncryptedError::Store)
+    }
+
+    fn archive_session(&self, id: &str) -> EncryptedResult<(), S::Error> {
+        self.inner.archive_session(id).map_err(EncryptedError::Store)
+    }
+
+    fn session_ids(&self) -> EncryptedResult<Vec<String>, S::Error> {
+        self.inner.session_ids().map_err(EncryptedError::Store)
+    }
+
+    fn sessions_by_identity(&self, fingerprint: &str) -> EncryptedResult<Vec<String>, S::Error> {
+        self.inner.sessions_by_identity(fingerprint).map_err(EncryptedError::Store)
+    }
+
+    fn session_identity(&self, id: &str) -> EncryptedResult<Option<String>, S::Error> {
+        self.inner.session_identity(id).map_err(EncryptedError::Store)
+    }
+
+    fn load_session_meta(&self, id: &str) -> EncryptedResult<Option<SessionMeta>, S::Error> {
+        self.inner.load_session_meta(id).map_err(EncryptedError::Store)
+    }
+
+    fn save_session_meta(&self, id: &str, meta: &SessionMeta) -> EncryptedResult<(), S::Error> {
+        self.inner.save_session_meta(id, meta).map_err(EncryptedError::Store)
+    }
+
+    fn load_identity<'s>(&self) -> EncryptedResult<Option<Identity<'s>>, S::Error> {
+        match try!(self.load_identity_data()) {
+            Some(b) => Ok(Some(try!(Identity::deserialise(&b)))),
+            None    => Ok(None)
+        }
+    }
+
+    fn save_identity(&self, id: &Identity) -> EncryptedResult<(), S::Error> {
+        self.save_identity_data(&try!(id.serialise()))
+    }
+
+    fn load_prekey(&self, id: PreKeyId) -> EncryptedResult<Option<PreKey>, S::Error> {
+        match try!(self.load_prekey_data(id)) {
+            Some(b) => Ok(Some(try!(PreKey::deserialise(&b)))),
+            None    => Ok(None)
+        }
+    }
+
+    fn add_prekey(&self, key: &PreKey) -> EncryptedResult<(), S::Error> {
+        self.add_prekey_data(key.key_id, &try!(key.serialise()))
+    }
+
+    fn delete_prekey(&self, id: PreKeyId) -> EncryptedResult<(), S::Error> {
+        self.inner.delete_prekey(id).map_err(EncryptedError::Store)
+    }
+
+    fn prekey_ids(&self) -> EncryptedResult<Vec<PreKeyId>, S::Error> {
+        self.inner.prekey_ids().map_err(EncryptedError::Store)
+    }
+
+    fn prekey_created(&self, id: PreKeyId) -> EncryptedResult<Option<u64>, S::Error> {
+        self.inner.prekey_created(id).map_err(EncryptedError::Store)
+    }
+
+    fn save_prekey_created(&self, id: PreKeyId, time: u64) -> EncryptedResult<(), S::Error> {
+        self.inner.save_prekey_created(id, time).map_err(EncryptedError::Store)
+    }
+
+    // Records of consumed prekeys hold nothing but IDs and are passed on
+    // unencrypted.
+    fn consumed_prekeys(&self) -> EncryptedResult<Vec<ConsumedPreKeys>, S::Error> {
+        self.inner.consumed_prekeys().map_err(EncryptedError::Store)
+    }
+
+    fn save_consumed_prekeys(&self, c: &ConsumedPreKeys) -> EncryptedResult<(), S::Error> {
+        self.inner.save_consumed_prekeys(c).map_err(EncryptedError::Store)
+    }
+
+    fn delete_consumed_prekeys(&self, session: &str) -> EncryptedResult<(), S::Error> {
+        self.inner.delete_consumed_prekeys(session).map_err(EncryptedError::Store)
+    }
+
+    fn commit_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation, removed: &[PreKeyId]) -> EncryptedResult<Commit<EncryptedError<S::Error>>, S::Error> {
+        let remote = s.remote_identity().fingerprint();
+        self.commit_session_data(id, &try!(s.serialise()), &remote, expected, removed)
+    }
+}
+
+impl<S: RawStore> RawStore for EncryptedStore<S> {
+    fn load_session_data(&self, id: &str) -> EncryptedResult<Option<(Vec<u8>, Generation)>, S::Error> {
+        match try!(self.inner.load_session_data(id).map_err(EncryptedError::Store)) {
+            Some((b, g)) => Ok(Some((try!(self.open(&session_name(id), &b)), g))),
+            None         => Ok(None)
+        }
+    }
+
+    fn save_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation) -> EncryptedResult<Option<Generation>, S::Error> {
+        let sealed = self.seal(&session_name(id), data);
+        self.inner.save_session_data(id, &sealed, remote, expected).map_err(EncryptedError::Store)
+    }
+
+    fn load_identity_data(&self) -> EncryptedResult<Option<Vec<u8>>, S::Error> {
+        let b = try!(self.inner.load_identity_data().map_err(EncryptedError::Store));
+        self.open_opt(IDENTITY_NAME, b)
+    }
+
+    fn save_identity_data(&self, data: &[u8]) -> EncryptedResult<(), S::Error> {
+        let sealed = self.seal(IDENTITY_NAME, data);
+        self.inner.save_identity_data(&sealed).map_err(EncryptedError::Store)
+    }
+
+    fn load_prekey_data(&self, id: PreKeyId) -> EncryptedResult<Option<Vec<u8>>, S::Error> {
+        let b = try!(self.inner.load_prekey_data(id).map_err(EncryptedError::Store));
+        self.open_opt(&prekey_name(id), b)
+    }
+
+    fn add_prekey_data(&self, id: PreKeyId, data: &[u8]) -> EncryptedResult<(), S::Error> {
+        let sealed = self.seal(&prekey_name(id), data);
+        self.inner.add_prekey_data(id, &sealed).map_err(EncryptedError::Store)
+    }
+
+    fn commit_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation, removed: &[PreKeyId]) -> EncryptedResult<Commit<EncryptedError<S::Error>>, S::Error> {
+        let sealed = self.seal(&session_name(id), data);
+        self.inner.commit_session_data(id, &sealed, remote, expected, removed)
+            .map(|c| c.map_err(EncryptedError::Store))
+            .map_err(EncryptedError::Store)
+    }
+}
+
+// EncryptedError ///////////////////////////////////////////////////////////
+
+pub type EncryptedResult<A, E> = Result<A, EncryptedError<E>>;
+
+#[derive(Debug)]
+pub enum EncryptedError<E> {
+    Store(E),
+    Decrypt,
+    Decode(DecodeError),
+    Encode(EncodeError)
+}
+
+impl<E: fmt::Display> fmt::Display for EncryptedError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            EncryptedError::Store(ref e)  => write!(f, "EncryptedError: {}", e),
+            EncryptedError::Decrypt       => write!(f, "EncryptedError: Decryption failed"),
+            EncryptedError::Decode(ref e) => write!(f, "EncryptedError: Decode error: {}", e),
+            EncryptedError::Encode(ref e) => write!(f, "EncryptedError: Encode error: {}", e)
+        }
+    }
+}
+
+impl<E: Error> Error for EncryptedError<E> {
+    fn description(&self) -> &str {
+        "EncryptedError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            EncryptedError::Store(ref e)  => Some(e),
+            EncryptedError::Decrypt       => None,
+            EncryptedError::Decode(ref e) => Some(e),
+            EncryptedError::Encode(ref e) => Some(e)
+        }
+    }
+}
+
+impl<E> From<DecodeError> for EncryptedError<E> {
+    fn from(e: DecodeError) -> EncryptedError<E> {
+        EncryptedError::Decode(e)
+    }
+}
+
+impl<E> From<EncodeError> for EncryptedError<E> {
+    fn from(e: EncodeError) -> EncryptedError<E> {
+        EncryptedError::Encode(e)
+    }
+}
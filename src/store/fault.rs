@@ -0,0 +1,294 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use identity::Identity;
+use proteus::keys::{IdentityKeyPair, PreKey, PreKeyId};
+use proteus::session::Session;
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use super::*;
+use super::meta::SessionMeta;
+
+/// When a `FaultStore` injects a failure.
+///
+/// Operations are numbered from 0 in the order they reach the store. The
+/// operation at the given index is not passed on to the inner store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Only the operation at this index fails.
+    Fail(usize),
+    /// The operation at this index and all later ones fail, as if the
+    /// process had died at this point.
+    Crash(usize)
+}
+
+// FaultStore ///////////////////////////////////////////////////////////////
+
+/// `Store` wrapper failing at a configurable operation, for testing.
+///
+/// Faults are controlled through a `FaultHandle`, which stays usable after
+/// the store has been moved into a `CBox`.
+///
+/// `commit_session` is not forwarded to the inner store but uses the
+/// default implementation, so that a failure at any step of it (recording
+/// the consumed prekeys, saving the session, deleting the prekeys) can be
+/// injected.
+#[derive(Debug)]
+pub struct FaultStore<S> {
+    inner: S,
+    state: Arc<Mutex<State>>
+}
+
+#[derive(Debug, Clone)]
+pub struct FaultHandle {
+    state: Arc<Mutex<State>>
+}
+
+#[derive(Debug)]
+struct State {
+    ops:     usize,
+    fault:   Option<Fault>,
+    crashed: bool
+}
+
+impl FaultHandle {
+    /// Inject `f`, counting operations from now on.
+    pub fn set_fault(&self, f: Option<Fault>) {
+        let mut st = self.state.lock().unwrap();
+        st.ops     = 0;
+        st.fault   = f;
+        st.crashed = false
+    }
+
+    /// Number of operations since the last `set_fault`.
+    pub fn ops(&self) -> usize {
+        self.state.lock().unwrap().ops
+    }
+
+    /// Whether a `Fault::Crash` has been triggered.
+    pub fn crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+}
+
+impl<S: Store> FaultStore<S> {
+    pub fn new(inner: S) -> FaultStore<S> {
+        FaultStore {
+            inner: inner,
+            state: Arc::new(Mutex::new(State { ops: 0, fault: None, crashed: false }))
+        }
+    }
+
+    pub fn handle(&self) -> FaultHandle {
+        FaultHandle { state: self.state.clone() }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn check(&self) -> Result<(), FaultError<S::Error>> {
+        let mut st = self.state.lock().unwrap();
+        let n = st.ops;
+        st.ops += 1;
+        if st.crashed {
+            return Err(FaultError::Injected(n))
+        }
+        match st.fault {
+            Some(Fault::Fail(i)) if i == n => Err(FaultError::Injected(n)),
+            Some(Fault::Crash(i)) if i == n => {
+                st.crashed = true;
+                Err(FaultError::Injected(n))
+            }
+            _ => Ok(())
+        }
+    }
+}
+
+impl<S: Store> Store for FaultStore<S> {
+    type Error = FaultError<S::Error>;
+
+    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> FaultResult<Option<(Session<I>, Generation)>, S::Error> {
+        try!(self.check());
+        self.inner.load_session(li, id).map_err(FaultError::Store)
+    }
+
+    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation) -> FaultResult<Option<Generation>, S::Error> {
+        try!(self.check());
+        self.inner.save_session(id, s, expected).map_err(FaultError::Store)
+    }
+
+    fn delete_session(&self, id: &str) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.delete_session(id).map_err(FaultError::Store)
+    }
+
+    fn archive_session(&self, id: &str) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.archive_session(id).map_err(FaultError::Store)
+    }
+
+    fn session_ids(&self) -> FaultResult<Vec<String>, S::Error> {
+        try!(self.check());
+        self.inner.session_ids().map_err(FaultError::Store)
+    }
+
+    fn sessions_by_identity(&self, fingerprint: &str) -> FaultResult<Vec<String>, S::Error> {
+        try!(self.check());
+        self.inner.sessions_by_identity(fingerprint).map_err(FaultError::Store)
+    }
+
+    fn session_identity(&self, id: &str) -> FaultResult<Option<String>, S::Error> {
+        try!(self.check());
+        self.inner.session_identity(id).map_err(FaultError::Store)
+    }
+
+    fn load_session_meta(&self, id: &str) -> FaultResult<Option<SessionMeta>, S::Error> {
+        try!(self.check());
+        self.inner.load_session_meta(id).map_err(FaultError::Store)
+    }
+
+    fn save_session_meta(&self, id: &str, meta: &SessionMeta) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.save_session_meta(id, meta).map_err(FaultError::Store)
+    }
+
+    fn load_identity<'s>(&self) -> FaultResult<Option<Identity<'s>>, S::Error> {
+        try!(self.check());
+        self.inner.load_identity().map_err(FaultError::Store)
+    }
+
+    fn save_identity(&self, id: &Identity) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.save_identity(id).map_err(FaultError::Store)
+    }
+
+    fn load_prekey(&self, id: PreKeyId) -> FaultResult<Option<PreKey>, S::Error> {
+        try!(self.check());
+        self.inner.load_prekey(id).map_err(FaultError::Store)
+    }
+
+    fn add_prekey(&self, key: &PreKey) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.add_prekey(key).map_err(FaultError::Store)
+    }
+
+    fn delete_prekey(&self, id: PreKeyId) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.delete_prekey(id).map_err(FaultError::Store)
+    }
+
+    fn prekey_ids(&self) -> FaultResult<Vec<PreKeyId>, S::Error> {
+        try!(self.check());
+        self.inner.prekey_ids().map_err(FaultError::Store)
+    }
+
+    fn prekey_created(&self, id: PreKeyId) -> FaultResult<Option<u64>, S::Error> {
+        try!(self.check());
+        self.inner.prekey_created(id).map_err(FaultError::Store)
+    }
+
+    fn save_prekey_created(&self, id: PreKeyId, time: u64) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.save_prekey_created(id, time).map_err(FaultError::Store)
+    }
+
+    fn consumed_prekeys(&self) -> FaultResult<Vec<ConsumedPreKeys>, S::Error> {
+        try!(self.check());
+        self.inner.consumed_prekeys().map_err(FaultError::Store)
+    }
+
+    fn save_consumed_prekeys(&self, c: &ConsumedPreKeys) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.save_consumed_prekeys(c).map_err(FaultError::Store)
+    }
+
+    fn delete_consumed_prekeys(&self, session: &str) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.delete_consumed_prekeys(session).map_err(FaultError::Store)
+    }
+}
+
+// As for `Store`, `commit_session_data` uses the default implementation.
+impl<S: RawStore> RawStore for FaultStore<S> {
+    fn load_session_data(&self, id: &str) -> FaultResult<Option<(Vec<u8>, Generation)>, S::Error> {
+        try!(self.check());
+        self.inner.load_session_data(id).map_err(FaultError::Store)
+    }
+
+    fn save_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation) -> FaultResult<Option<Generation>, S::Error> {
+        try!(self.check());
+        self.inner.save_session_data(id, data, remote, expected).map_err(FaultError::Store)
+    }
+
+    fn load_identity_data(&self) -> FaultResult<Option<Vec<u8>>, S::Error> {
+        try!(self.check());
+        self.inner.load_identity_data().map_err(FaultError::Store)
+    }
+
+    fn save_identity_data(&self, data: &[u8]) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.save_identity_data(data).map_err(FaultError::Store)
+    }
+
+    fn load_prekey_data(&self, id: PreKeyId) -> FaultResult<Option<Vec<u8>>, S::Error> {
+        try!(self.check());
+        self.inner.load_prekey_data(id).map_err(FaultError::Store)
+    }
+
+    fn add_prekey_data(&self, id: PreKeyId, data: &[u8]) -> FaultResult<(), S::Error> {
+        try!(self.check());
+        self.inner.add_prekey_data(id, data).map_err(FaultError::Store)
+    }
+}
+
+// FaultError ///////////////////////////////////////////////////////////////
+
+pub type FaultResult<A, E> = Result<A, FaultError<E>>;
+
+#[derive(Debug)]
+pub enum FaultError<E> {
+    Store(E),
+    /// Injected failure of the operation with this index.
+    Injected(usize)
+}
+
+impl<E: fmt::Display> fmt::Display for FaultError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            FaultError::Store(ref e) => write!(f, "FaultError: {}", e),
+            FaultError::Injected(i)  => write!(f, "FaultError: Injected failure of operation {}", i)
+        }
+    }
+}
+
+impl<E: Error> Error for FaultError<E> {
+    fn description(&self) -> &str {
+        "FaultError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            FaultError::Store(ref e) => Some(e),
+            FaultError::Injected(_)  => None
+        }
+    }
+}
@@ -13,7 +13,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use address::SessionAddress;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use identity::Identity;
 use proteus::{DecodeError, EncodeError};
 use proteus::keys::{PreKey, PreKeyId, IdentityKeyPair};
@@ -21,15 +22,32 @@ use proteus::session::Session;
 use std::borrow::{Borrow, Cow};
 use std::error::Error;
 use std::fmt;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write, ErrorKind};
 use std::path::{Path, PathBuf};
 use super::*;
+use super::meta::SessionMeta;
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg(feature = "async")]
+use blocking::unblock;
+#[cfg(feature = "async")]
+use futures::future::{self, FutureExt};
+#[cfg(feature = "async")]
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use super::async_store::{AsyncStore, StoreFuture};
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 struct Version(u16);
 
-const CURRENT_VERSION: Version = Version(1);
+// Version 2 prefixes every session with its generation.
+// Version 3 adds the remote identity index.
+//
+// Session metadata (meta/), prekey creation times (prekeys.created/),
+// records of consumed prekeys (consumed/) and archived sessions (archive/)
+// are optional in every version and their directories are created when
+// first written to.
+const CURRENT_VERSION: Version = Version(3);
 
 // FileStore ////////////////////////////////////////////////////////////////
 
@@ -38,20 +56,20 @@ pub struct FileStore {
     root_dir:     PathBuf,
     session_dir:  PathBuf,
     prekey_dir:   PathBuf,
-    identity_dir: PathBuf
+    created_dir:  PathBuf,
+    identity_dir: PathBuf,
+    index_dir:    PathBuf,
+    meta_dir:     PathBuf,
+    consumed_dir: PathBuf,
+    deleted_dir:  PathBuf
 }
 
 impl FileStore {
     pub fn new(root: &Path) -> FileStoreResult<FileStore> {
-        let fs = FileStore {
-            root_dir:     PathBuf::from(root),
-            session_dir:  root.join("sessions"),
-            prekey_dir:   root.join("prekeys"),
-            identity_dir: root.join("identities")
-        };
+        let fs = FileStore::at(root);
 
         match try!(FileStore::read_version(&fs.root_dir)) {
-            Some(v) => { try!(FileStore::migrate(v)); return Ok(fs) },
+            Some(v) => { try!(fs.migrate(v)); return Ok(fs) },
             None    => ()
         }
 
@@ -61,6 +79,7 @@ impl FileStore {
         if !dir_exists(&fs.prekey_dir) {
             try!(fs::create_dir(&fs.prekey_dir));
         }
+        try!(fs.create_v3_dirs());
         if !dir_exists(&fs.identity_dir) {
             try!(fs::create_dir(&fs.identity_dir));
         } else {
@@ -85,6 +104,30 @@ impl FileStore {
         Ok(fs)
     }
 
+    /// Open the store in `root` without creating, migrating or otherwise
+    /// writing anything, e.g. to inspect it. The store may be of any
+    /// version; use `version` before relying on its layout.
+    pub fn inspect(root: &Path) -> FileStoreResult<FileStore> {
+        if !dir_exists(root) {
+            return Err(FileStoreError::Io(io::Error::new(ErrorKind::NotFound, "store directory not found")))
+        }
+        Ok(FileStore::at(root))
+    }
+
+    fn at(root: &Path) -> FileStore {
+        FileStore {
+            root_dir:     PathBuf::from(root),
+            session_dir:  root.join("sessions"),
+            prekey_dir:   root.join("prekeys"),
+            created_dir:  root.join("prekeys.created"),
+            identity_dir: root.join("identities"),
+            index_dir:    root.join("index"),
+            meta_dir:     root.join("meta"),
+            consumed_dir: root.join("consumed"),
+            deleted_dir:  root.join("sessions.deleted")
+        }
+    }
+
     fn read_version(root: &PathBuf) -> FileStoreResult<Option<Version>> {
         let p = root.join("version");
         match try!(open_file(&p)) {
@@ -103,54 +146,335 @@ impl FileStore {
         write_file(&p, &b, true)
     }
 
-    fn migrate(_: Version) -> io::Result<()> {
+    fn migrate(&self, v: Version) -> FileStoreResult<()> {
+        if v >= CURRENT_VERSION {
+            return Ok(())
+        }
+        if v < Version(2) {
+            // Every file is replaced atomically, but an interrupted
+            // migration leaves some of them prefixed already. A version 1
+            // session is a CBOR map and starts with a byte of major type 5
+            // (0xa0 - 0xbf) while a generation prefix starts with 0.
+            let _lock = try!(self.session_lock());
+            for entry in try!(fs::read_dir(&self.session_dir)) {
+                let path = try!(entry).path();
+                if is_tmp(&path) {
+                    continue
+                }
+                if let Some(b) = try!(load_file(&path)) {
+                    if b.first().map(|&c| c & 0xe0 != 0xa0).unwrap_or(true) {
+                        continue
+                    }
+                    let mut data = Vec::with_capacity(b.len() + 8);
+                    try!(data.write_u64::<BigEndian>(1));
+                    data.extend_from_slice(&b);
+                    try!(write_file(&path, &data, true))
+                }
+            }
+        }
+        if v < Version(3) {
+            // Decoding sessions requires the local identity. Without it
+            // sessions are indexed when they are saved next.
+            try!(self.create_v3_dirs());
+            if let Some(Identity::Sec(i)) = try!(self.load_identity()) {
+                let i = i.into_owned();
+                let _lock = try!(self.session_lock());
+                for id in try!(self.session_ids()) {
+                    if let Some((b, _)) = try!(self.load_session_data(&id)) {
+                        if let Ok(s) = Session::deserialise(&i, &b) {
+                            try!(self.index_session(&id, &s.remote_identity().fingerprint()))
+                        }
+                    }
+                }
+            }
+        }
         // Future migrations for v < CURRENT_VERSION go here
-        Ok(())
+        FileStore::write_version(&self.root_dir, CURRENT_VERSION)
+    }
+
+    /// Format version of the store on disk.
+    pub fn version(&self) -> FileStoreResult<Option<u16>> {
+        Ok(try!(FileStore::read_version(&self.root_dir)).map(|Version(v)| v))
+    }
+
+    /// Whether the store on disk has the current format version.
+    pub fn is_current(&self) -> FileStoreResult<bool> {
+        Ok(try!(FileStore::read_version(&self.root_dir)) == Some(CURRENT_VERSION))
+    }
+
+    pub fn session_ids(&self) -> FileStoreResult<Vec<String>> {
+        let mut ids = Vec::new();
+        for entry in try!(fs::read_dir(&self.session_dir)) {
+            let path = try!(entry).path();
+            if path.extension().map(|e| e == "tmp").unwrap_or(false) {
+                continue
+            }
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                ids.push(String::from(name))
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Sessions of the given user, i.e. those whose IDs are encoded
+    /// `SessionAddress`es with matching user ID and domain. Sessions with
+    /// other IDs are ignored.
+    pub fn user_sessions(&self, user: &str, domain: Option<&str>) -> FileStoreResult<Vec<SessionAddress>> {
+        let domain = domain.map(|d| d.to_ascii_lowercase());
+        let mut addrs = Vec::new();
+        for id in try!(self.session_ids()) {
+            if let Ok(a) = SessionAddress::parse(&id) {
+                if a.user() == user && a.domain() == domain.as_ref().map(|d| d.as_str()) {
+                    addrs.push(a)
+                }
+            }
+        }
+        addrs.sort();
+        Ok(addrs)
+    }
+
+    pub fn prekey_ids(&self) -> FileStoreResult<Vec<PreKeyId>> {
+        let mut ids = Vec::new();
+        for entry in try!(fs::read_dir(&self.prekey_dir)) {
+            let path = try!(entry).path();
+            if let Some(id) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.parse().ok()) {
+                ids.push(PreKeyId::new(id))
+            }
+        }
+        Ok(ids)
+    }
+
+    fn session_lock(&self) -> FileStoreResult<FileLock> {
+        FileLock::acquire(&self.root_dir.join("sessions.lock"))
     }
 }
 
-impl Store for FileStore {
-    type Error = FileStoreError;
+// Remote identity index:
+//
+//   index/sessions/<id>                   fingerprint of session <id>
+//   index/identities/<fingerprint>/<id>   empty
+//
+// The first is authoritative, entries of the second are only returned if
+// they agree with it. All changes are made with the session lock held.
+impl FileStore {
+    fn create_v3_dirs(&self) -> FileStoreResult<()> {
+        try!(fs::create_dir_all(self.index_dir.join("sessions")));
+        try!(fs::create_dir_all(self.index_dir.join("identities")));
+        Ok(())
+    }
+
+    // The generation of session `id` or, if it has been deleted, the one
+    // recorded in its tombstone, so that generations never repeat.
+    fn generation(&self, id: &str) -> FileStoreResult<Generation> {
+        match try!(read_generation(&self.session_dir.join(id))) {
+            g if g != Generation::none() => Ok(g),
+            _                            => read_generation(&self.deleted_dir.join(id))
+        }
+    }
+
+    // Deleting a session advances its generation in a tombstone. Saves
+    // based on the deleted session then conflict, and a new session under
+    // the same ID continues from there. A session whose generation is
+    // unreadable can still be deleted.
+    fn bury_session(&self, id: &str) -> FileStoreResult<()> {
+        let g = read_generation(&self.session_dir.join(id)).unwrap_or(Generation::none());
+        if g == Generation::none() {
+            return Ok(())
+        }
+        let mut b = Vec::with_capacity(8);
+        try!(b.write_u64::<BigEndian>(g.next().value()));
+        try!(fs::create_dir_all(&self.deleted_dir));
+        write_file(&self.deleted_dir.join(id), &b, true)
+    }
+
+    fn read_index(&self, id: &str) -> FileStoreResult<Option<String>> {
+        let b = try!(load_file(&self.index_dir.join("sessions").join(id)));
+        Ok(b.and_then(|b| String::from_utf8(b).ok()))
+    }
+
+    fn index_session(&self, id: &str, fp: &str) -> FileStoreResult<()> {
+        let old = try!(self.read_index(id));
+        if old.as_ref().map(|o| o.as_str()) == Some(fp) {
+            return Ok(())
+        }
+        let dir = self.index_dir.join("identities").join(fp);
+        try!(fs::create_dir_all(&dir));
+        try!(write_file(&dir.join(id), &[], false));
+        try!(write_file(&self.index_dir.join("sessions").join(id), fp.as_bytes(), false));
+        if let Some(o) = old {
+            try!(self.unindex_identity(&o, id))
+        }
+        Ok(())
+    }
+
+    fn unindex_session(&self, id: &str) -> FileStoreResult<()> {
+        if let Some(fp) = try!(self.read_index(id)) {
+            try!(remove_file(&self.index_dir.join("sessions").join(id)));
+            try!(self.unindex_identity(&fp, id))
+        }
+        Ok(())
+    }
+
+    fn unindex_identity(&self, fp: &str, id: &str) -> FileStoreResult<()> {
+        let dir = self.index_dir.join("identities").join(fp);
+        try!(remove_file(&dir.join(id)));
+        let _ = fs::remove_dir(&dir); // Fails unless empty.
+        Ok(())
+    }
+}
 
-    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> FileStoreResult<Option<Session<I>>> {
+impl RawStore for FileStore {
+    // Session files start with the generation, which is not part of the
+    // serialised session.
+    fn load_session_data(&self, id: &str) -> FileStoreResult<Option<(Vec<u8>, Generation)>> {
         let path = self.session_dir.join(id);
         match try!(load_file(&path)) {
-            Some(b) => Ok(Some(try!(Session::deserialise(li, &b)))),
-            None    => Ok(None)
+            Some(mut b) => {
+                let g = try!(io::Cursor::new(&b).read_u64::<BigEndian>());
+                Ok(Some((b.split_off(8), Generation::new(g))))
+            }
+            None => Ok(None)
         }
     }
 
-    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>) -> FileStoreResult<()> {
+    fn save_session_data(&self, id: &str, session: &[u8], remote: &str, expected: Generation) -> FileStoreResult<Option<Generation>> {
         let path = self.session_dir.join(id);
-        write_file(&path, &try!(s.serialise()), false)
+        let mut data = Vec::with_capacity(session.len() + 8);
+        let _lock = try!(self.session_lock());
+        let current = try!(self.generation(id));
+        if expected != Generation::none() && current != expected {
+            return Ok(None)
+        }
+        let next = current.next();
+        try!(data.write_u64::<BigEndian>(next.value()));
+        data.extend_from_slice(session);
+        // The session goes first so that a failure while indexing never
+        // loses it. The index then still lists it under its previous
+        // remote identity until the next save.
+        try!(write_file(&path, &data, false));
+        let _ = remove_file(&self.deleted_dir.join(id)); // Superseded by the session.
+        try!(self.index_session(id, remote));
+        Ok(Some(next))
+    }
+
+    fn load_identity_data(&self) -> FileStoreResult<Option<Vec<u8>>> {
+        load_file(&self.identity_dir.join("local"))
+    }
+
+    fn save_identity_data(&self, data: &[u8]) -> FileStoreResult<()> {
+        write_file(&self.identity_dir.join("local"), data, true)
+    }
+
+    fn load_prekey_data(&self, id: PreKeyId) -> FileStoreResult<Option<Vec<u8>>> {
+        load_file(&self.prekey_dir.join(&id.value().to_string()))
+    }
+
+    fn add_prekey_data(&self, id: PreKeyId, data: &[u8]) -> FileStoreResult<()> {
+        write_file(&self.prekey_dir.join(&id.value().to_string()), data, true)
+    }
+}
+
+impl Store for FileStore {
+    type Error = FileStoreError;
+
+    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> FileStoreResult<Option<(Session<I>, Generation)>> {
+        match try!(self.load_session_data(id)) {
+            Some((b, g)) => Ok(Some((try!(Session::deserialise(li, &b)), g))),
+            None         => Ok(None)
+        }
+    }
+
+    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation) -> FileStoreResult<Option<Generation>> {
+        let remote = s.remote_identity().fingerprint();
+        self.save_session_data(id, &try!(s.serialise()), &remote, expected)
     }
 
     fn delete_session(&self, id: &str) -> FileStoreResult<()> {
         let path = self.session_dir.join(id);
-        remove_file(&path)
+        let _lock = try!(self.session_lock());
+        try!(self.bury_session(id));
+        try!(remove_file(&path));
+        try!(remove_file(&self.meta_dir.join(id)));
+        self.unindex_session(id)
+    }
+
+    // Archived sessions and their metadata are moved below `archive/`
+    // like quarantined files.
+    fn archive_session(&self, id: &str) -> FileStoreResult<()> {
+        let archive = self.root_dir.join("archive");
+        let _lock = try!(self.session_lock());
+        try!(self.bury_session(id));
+        for path in &[self.session_dir.join(id), self.meta_dir.join(id)] {
+            if path.is_file() {
+                try!(self.quarantine(&archive, path));
+            }
+        }
+        self.unindex_session(id)
+    }
+
+    fn session_ids(&self) -> FileStoreResult<Vec<String>> {
+        FileStore::session_ids(self)
+    }
+
+    fn sessions_by_identity(&self, fingerprint: &str) -> FileStoreResult<Vec<String>> {
+        let mut ids = Vec::new();
+        if !is_fingerprint(fingerprint) {
+            return Ok(ids)
+        }
+        let dir = self.index_dir.join("identities").join(fingerprint);
+        if !dir_exists(&dir) {
+            return Ok(ids)
+        }
+        for entry in try!(fs::read_dir(&dir)) {
+            let path = try!(entry).path();
+            if is_tmp(&path) {
+                continue
+            }
+            if let Some(id) = path.file_name().and_then(|n| n.to_str()) {
+                if try!(self.session_identity(id)).as_ref().map(|f| f.as_str()) == Some(fingerprint) {
+                    ids.push(String::from(id))
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    fn session_identity(&self, id: &str) -> FileStoreResult<Option<String>> {
+        if !self.session_dir.join(id).is_file() {
+            return Ok(None)
+        }
+        self.read_index(id)
+    }
+
+    fn load_session_meta(&self, id: &str) -> FileStoreResult<Option<SessionMeta>> {
+        match try!(load_file(&self.meta_dir.join(id))) {
+            Some(b) => SessionMeta::deserialise(&b).map_err(From::from).map(Some),
+            None    => Ok(None)
+        }
+    }
+
+    fn save_session_meta(&self, id: &str, meta: &SessionMeta) -> FileStoreResult<()> {
+        try!(fs::create_dir_all(&self.meta_dir));
+        write_file(&self.meta_dir.join(id), &try!(meta.serialise()), false)
     }
 
     fn load_identity<'s>(&self) -> FileStoreResult<Option<Identity<'s>>> {
-        let path = self.identity_dir.join("local");
-        match try!(load_file(&path)) {
+        match try!(self.load_identity_data()) {
             Some(b) => Identity::deserialise(&b).map_err(From::from).map(Some),
             None    => Ok(None)
         }
     }
 
     fn save_identity(&self, id: &Identity) -> FileStoreResult<()> {
-        let path = self.identity_dir.join("local");
-        write_file(&path, &try!(id.serialise()), true)
+        self.save_identity_data(&try!(id.serialise()))
     }
 
     fn add_prekey(&self, key: &PreKey) -> FileStoreResult<()> {
-        let path = self.prekey_dir.join(&key.key_id.value().to_string());
-        write_file(&path, &try!(key.serialise()), true)
+        self.add_prekey_data(key.key_id, &try!(key.serialise()))
     }
 
     fn load_prekey(&self, id: PreKeyId) -> FileStoreResult<Option<PreKey>> {
-        let path = self.prekey_dir.join(&id.value().to_string());
-        match try!(load_file(&path)) {
+        match try!(self.load_prekey_data(id)) {
             Some(b) => PreKey::deserialise(&b).map_err(From::from).map(Some),
             None    => Ok(None)
         }
@@ -158,7 +482,486 @@ impl Store for FileStore {
 
     fn delete_prekey(&self, id: PreKeyId) -> FileStoreResult<()> {
         let path = self.prekey_dir.join(&id.value().to_string());
-        remove_file(&path)
+        try!(remove_file(&path));
+        remove_file(&self.created_dir.join(&id.value().to_string()))
+    }
+
+    fn prekey_ids(&self) -> FileStoreResult<Vec<PreKeyId>> {
+        FileStore::prekey_ids(self)
+    }
+
+    fn prekey_created(&self, id: PreKeyId) -> FileStoreResult<Option<u64>> {
+        match try!(open_file(&self.created_dir.join(&id.value().to_string()))) {
+            Some(mut f) => Ok(Some(try!(f.read_u64::<BigEndian>()))),
+            None        => Ok(None)
+        }
+    }
+
+    // The directory is created on demand as older stores lack it.
+    fn save_prekey_created(&self, id: PreKeyId, time: u64) -> FileStoreResult<()> {
+        let mut b = [0; 8];
+        try!(b.as_mut().write_u64::<BigEndian>(time));
+        try!(fs::create_dir_all(&self.created_dir));
+        write_file(&self.created_dir.join(&id.value().to_string()), &b, false)
+    }
+
+    // consumed/<id> holds the expected generation followed by the prekey
+    // IDs, all big-endian.
+    fn consumed_prekeys(&self) -> FileStoreResult<Vec<ConsumedPreKeys>> {
+        let mut records = Vec::new();
+        if !dir_exists(&self.consumed_dir) {
+            return Ok(records)
+        }
+        for entry in try!(fs::read_dir(&self.consumed_dir)) {
+            let path = try!(entry).path();
+            if is_tmp(&path) {
+                continue
+            }
+            let id = match path.file_name().and_then(|n| n.to_str()) {
+                Some(id) => id.to_string(),
+                None     => continue
+            };
+            // Malformed records are skipped, `check` reports them.
+            if let Some(c) = try!(load_file(&path)).and_then(|b| decode_consumed(id, &b)) {
+                records.push(c)
+            }
+        }
+        Ok(records)
+    }
+
+    fn save_consumed_prekeys(&self, c: &ConsumedPreKeys) -> FileStoreResult<()> {
+        let mut b = Vec::with_capacity(8 + 2 * c.prekeys.len());
+        try!(b.write_u64::<BigEndian>(c.expected.value()));
+        for p in &c.prekeys {
+            try!(b.write_u16::<BigEndian>(p.value()))
+        }
+        try!(fs::create_dir_all(&self.consumed_dir));
+        write_file(&self.consumed_dir.join(&c.session), &b, true)
+    }
+
+    fn delete_consumed_prekeys(&self, session: &str) -> FileStoreResult<()> {
+        remove_file(&self.consumed_dir.join(session))
+    }
+}
+
+fn decode_consumed(id: String, b: &[u8]) -> Option<ConsumedPreKeys> {
+    if b.len() < 8 || b.len() % 2 != 0 {
+        return None
+    }
+    Some(ConsumedPreKeys {
+        session:  id,
+        expected: Generation::new(BigEndian::read_u64(&b[.. 8])),
+        prekeys:  b[8 ..].chunks(2).map(|p| PreKeyId::new(BigEndian::read_u16(p))).collect()
+    })
+}
+
+// Integrity check //////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// The version file is missing, unreadable or not the current version.
+    Version,
+    /// The local identity does not decode.
+    Identity,
+    /// A session does not decode.
+    Session,
+    /// A prekey does not decode or its file name is not its ID.
+    PreKey,
+    /// Session metadata does not decode.
+    Meta,
+    /// The creation time of a prekey is malformed.
+    PreKeyCreated,
+    /// An entry of the remote identity index is malformed or disagrees
+    /// with the stored sessions.
+    Index,
+    /// A record of consumed prekeys is malformed.
+    ConsumedPreKeys,
+    /// The tombstone of a deleted session is malformed.
+    Tombstone,
+    /// A temporary file left behind by an interrupted write.
+    TempFile,
+    /// A file which does not belong in its directory.
+    Unknown
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub path: PathBuf,
+    /// Where the entry has been moved to, if it was quarantined.
+    pub quarantined: Option<PathBuf>
+}
+
+impl Problem {
+    fn new(kind: ProblemKind, path: PathBuf) -> Problem {
+        Problem { kind: kind, path: path, quarantined: None }
+    }
+}
+
+impl FileStore {
+    /// Check all entries of the store and report those which are broken.
+    ///
+    /// If `quarantine` is given, undecodable sessions, prekeys and the
+    /// entries belonging to them as well as temporary files are moved
+    /// into that directory (below the name of the directory they were
+    /// found in) instead of being left in place. The local identity and
+    /// unknown files are only reported; without a readable identity the
+    /// box can not be opened anyway.
+    ///
+    /// Other processes should not use the store during the check.
+    pub fn check(&self, quarantine: Option<&Path>) -> FileStoreResult<Vec<Problem>> {
+        let _lock = try!(self.session_lock());
+        let mut problems = Vec::new();
+
+        let version = FileStore::read_version(&self.root_dir).ok().and_then(|v| v);
+        if version != Some(CURRENT_VERSION) {
+            problems.push(Problem::new(ProblemKind::Version, self.root_dir.join("version")))
+        }
+        if self.root_dir.join("version.tmp").exists() {
+            problems.push(Problem::new(ProblemKind::TempFile, self.root_dir.join("version.tmp")))
+        }
+
+        let mut ident = None;
+        for entry in try!(fs::read_dir(&self.identity_dir)) {
+            let path = try!(entry).path();
+            let kind = match path.file_name().and_then(|n| n.to_str()) {
+                _ if is_tmp(&path) => Some(ProblemKind::TempFile),
+                Some("local") => match try!(load_file(&path)).map(|b| Identity::deserialise(&b)) {
+                    Some(Ok(Identity::Sec(i))) => { ident = Some(i.into_owned()); None }
+                    Some(Ok(Identity::Pub(_))) => None,
+                    _                          => Some(ProblemKind::Identity)
+                },
+                Some("local_identity") => match try!(load_file(&path)).map(|b| IdentityKeyPair::deserialise(&b)) {
+                    Some(Ok(_)) => None,
+                    _           => Some(ProblemKind::Identity)
+                },
+                _ => Some(ProblemKind::Unknown)
+            };
+            if let Some(k) = kind {
+                problems.push(Problem::new(k, path))
+            }
+        }
+
+        for entry in try!(fs::read_dir(&self.session_dir)) {
+            let path = try!(entry).path();
+            let kind = match path.file_name().and_then(|n| n.to_str()) {
+                _ if is_tmp(&path) => Some(ProblemKind::TempFile),
+                Some(id) => match self.load_session_data(id) {
+                    Ok(Some((ref b, _))) if !b.is_empty() => {
+                        match ident {
+                            Some(ref i) if Session::deserialise(i, b).is_err() => Some(ProblemKind::Session),
+                            _                                                   => None
+                        }
+                    }
+                    _ => Some(ProblemKind::Session)
+                },
+                None => Some(ProblemKind::Unknown)
+            };
+            if let Some(k) = kind {
+                problems.push(Problem::new(k, path))
+            }
+        }
+
+        for entry in try!(fs::read_dir(&self.prekey_dir)) {
+            let path = try!(entry).path();
+            let kind = match path.file_name().and_then(|n| n.to_str()).and_then(|n| n.parse::<u16>().ok()) {
+                _ if is_tmp(&path) => Some(ProblemKind::TempFile),
+                Some(id) => match try!(load_file(&path)).map(|b| PreKey::deserialise(&b)) {
+                    Some(Ok(ref k)) if k.key_id.value() == id => None,
+                    _                                          => Some(ProblemKind::PreKey)
+                },
+                None => Some(ProblemKind::Unknown)
+            };
+            if let Some(k) = kind {
+                problems.push(Problem::new(k, path))
+            }
+        }
+
+        try!(check_dir(&self.meta_dir, &mut problems, |path, name| {
+            match name {
+                Some(_) => match try!(load_file(path)).map(|b| SessionMeta::deserialise(&b)) {
+                    Some(Err(_)) => Ok(Some(ProblemKind::Meta)),
+                    _            => Ok(None)
+                },
+                None => Ok(Some(ProblemKind::Unknown))
+            }
+        }));
+
+        try!(check_dir(&self.created_dir, &mut problems, |path, name| {
+            match name.and_then(|n| n.parse::<u16>().ok()) {
+                Some(_) => match try!(load_file(path)) {
+                    Some(ref b) if b.len() != 8 => Ok(Some(ProblemKind::PreKeyCreated)),
+                    _                           => Ok(None)
+                },
+                None => Ok(Some(ProblemKind::Unknown))
+            }
+        }));
+
+        try!(check_dir(&self.deleted_dir, &mut problems, |path, name| {
+            match name {
+                Some(_) => match try!(load_file(path)) {
+                    Some(ref b) if b.len() != 8 => Ok(Some(ProblemKind::Tombstone)),
+                    _                           => Ok(None)
+                },
+                None => Ok(Some(ProblemKind::Unknown))
+            }
+        }));
+
+        try!(check_dir(&self.consumed_dir, &mut problems, |path, name| {
+            match name {
+                Some(id) => match try!(load_file(path)).map(|b| decode_consumed(id.to_string(), &b)) {
+                    Some(None) => Ok(Some(ProblemKind::ConsumedPreKeys)),
+                    _          => Ok(None)
+                },
+                None => Ok(Some(ProblemKind::Unknown))
+            }
+        }));
+
+        try!(self.check_index(&mut problems));
+
+        if let Some(dir) = quarantine {
+            for p in problems.iter_mut() {
+                match p.kind {
+                    ProblemKind::Version | ProblemKind::Identity | ProblemKind::Unknown => (),
+                    _ => p.quarantined = Some(try!(self.quarantine(dir, &p.path)))
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+
+    // Index entries are problems if they are malformed or refer to a
+    // session which does not exist, is broken itself or has another remote
+    // identity.
+    fn check_index(&self, problems: &mut Vec<Problem>) -> FileStoreResult<()> {
+        let sessions   = self.index_dir.join("sessions");
+        let identities = self.index_dir.join("identities");
+        let broken: Vec<PathBuf> = problems.iter()
+            .filter(|p| p.kind == ProblemKind::Session)
+            .map(|p| p.path.clone())
+            .collect();
+        let live = |id: &str| {
+            let path = self.session_dir.join(id);
+            path.is_file() && !broken.contains(&path)
+        };
+        if dir_exists(&self.index_dir) {
+            for entry in try!(fs::read_dir(&self.index_dir)) {
+                let path = try!(entry).path();
+                if path != sessions && path != identities {
+                    problems.push(Problem::new(ProblemKind::Unknown, path))
+                }
+            }
+        }
+
+        try!(check_dir(&sessions, problems, |path, name| {
+            match name {
+                Some(id) => match try!(load_file(path)).and_then(|b| String::from_utf8(b).ok()) {
+                    Some(ref fp) if is_fingerprint(fp) && live(id) => Ok(None),
+                    _                                              => Ok(Some(ProblemKind::Index))
+                },
+                None => Ok(Some(ProblemKind::Unknown))
+            }
+        }));
+
+        if !dir_exists(&identities) {
+            return Ok(())
+        }
+        for entry in try!(fs::read_dir(&identities)) {
+            let dir = try!(entry).path();
+            let fp  = match dir.file_name().and_then(|n| n.to_str()) {
+                Some(fp) if is_fingerprint(fp) && dir.is_dir() => fp.to_string(),
+                _ => {
+                    problems.push(Problem::new(ProblemKind::Unknown, dir));
+                    continue
+                }
+            };
+            try!(check_dir(&dir, problems, |_, name| {
+                match name {
+                    Some(id) => match try!(self.session_identity(id)) {
+                        Some(ref f) if *f == fp && live(id) => Ok(None),
+                        _                                   => Ok(Some(ProblemKind::Index))
+                    },
+                    None => Ok(Some(ProblemKind::Unknown))
+                }
+            }))
+        }
+        Ok(())
+    }
+
+    // Move `path` below `dir`, keeping its path relative to the store root
+    // and never overwriting earlier quarantined files.
+    fn quarantine(&self, dir: &Path, path: &Path) -> FileStoreResult<PathBuf> {
+        let rel  = path.strip_prefix(&self.root_dir).unwrap_or(path);
+        let base = dir.join(rel);
+        if let Some(parent) = base.parent() {
+            try!(fs::create_dir_all(parent))
+        }
+        let mut dest = base.clone();
+        let mut n = 1;
+        while dest.exists() {
+            dest = PathBuf::from(format!("{}.{}", base.display(), n));
+            n += 1
+        }
+        try!(fs::rename(path, &dest));
+        Ok(dest)
+    }
+}
+
+fn is_tmp(p: &Path) -> bool {
+    p.extension().map(|e| e == "tmp").unwrap_or(false)
+}
+
+fn is_fingerprint(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+// Check every entry of the optional directory `dir` with `f`, which is
+// given the entry's path and name. Temporary files are reported as such.
+fn check_dir<F>(dir: &Path, problems: &mut Vec<Problem>, mut f: F) -> FileStoreResult<()>
+    where F: FnMut(&Path, Option<&str>) -> FileStoreResult<Option<ProblemKind>>
+{
+    if !dir_exists(dir) {
+        return Ok(())
+    }
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let kind = if is_tmp(&path) {
+            Some(ProblemKind::TempFile)
+        } else {
+            try!(f(&path, path.file_name().and_then(|n| n.to_str())))
+        };
+        if let Some(k) = kind {
+            problems.push(Problem::new(k, path))
+        }
+    }
+    Ok(())
+}
+
+// AsyncFileStore ///////////////////////////////////////////////////////////
+
+/// `AsyncStore` over the `FileStore` layout.
+///
+/// Blocking file system calls are moved onto a separate thread pool so
+/// that they never stall the executor.
+#[cfg(feature = "async")]
+#[derive(Debug, Clone)]
+pub struct AsyncFileStore {
+    inner: Arc<FileStore>
+}
+
+#[cfg(feature = "async")]
+impl AsyncFileStore {
+    pub fn new(root: &Path) -> StoreFuture<AsyncFileStore, FileStoreError> {
+        let root = PathBuf::from(root);
+        unblock(move || {
+            let fs = try!(FileStore::new(&root));
+            Ok(AsyncFileStore { inner: Arc::new(fs) })
+        }).boxed()
+    }
+
+    pub fn from_file_store(fs: FileStore) -> AsyncFileStore {
+        AsyncFileStore { inner: Arc::new(fs) }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncStore for AsyncFileStore {
+    type Error = FileStoreError;
+
+    fn load_session<I>(&self, li: I, id: &str) -> StoreFuture<Option<(Session<I>, Generation)>, FileStoreError>
+        where I: Borrow<IdentityKeyPair> + Send + 'static
+    {
+        let fs = self.inner.clone();
+        let id = String::from(id);
+        unblock(move || fs.load_session(li, &id)).boxed()
+    }
+
+    fn save_session<I>(&self, id: &str, s: &Session<I>, expected: Generation) -> StoreFuture<Option<Generation>, FileStoreError>
+        where I: Borrow<IdentityKeyPair>
+    {
+        let data = match s.serialise() {
+            Ok(d)  => d,
+            Err(e) => return future::err(FileStoreError::from(e)).boxed()
+        };
+        let remote = s.remote_identity().fingerprint();
+        let fs = self.inner.clone();
+        let id = String::from(id);
+        unblock(move || fs.save_session_data(&id, &data, &remote, expected)).boxed()
+    }
+
+    fn delete_session(&self, id: &str) -> StoreFuture<(), FileStoreError> {
+        let fs = self.inner.clone();
+        let id = String::from(id);
+        unblock(move || fs.delete_session(&id)).boxed()
+    }
+
+    fn load_session_meta(&self, id: &str) -> StoreFuture<Option<SessionMeta>, FileStoreError> {
+        let fs = self.inner.clone();
+        let id = String::from(id);
+        unblock(move || fs.load_session_meta(&id)).boxed()
+    }
+
+    fn save_session_meta(&self, id: &str, meta: &SessionMeta) -> StoreFuture<(), FileStoreError> {
+        let fs   = self.inner.clone();
+        let id   = String::from(id);
+        let meta = meta.clone();
+        unblock(move || fs.save_session_meta(&id, &meta)).boxed()
+    }
+
+    fn load_identity(&self) -> StoreFuture<Option<Identity<'static>>, FileStoreError> {
+        let fs = self.inner.clone();
+        unblock(move || fs.load_identity()).boxed()
+    }
+
+    fn save_identity(&self, id: &Identity) -> StoreFuture<(), FileStoreError> {
+        let data = match id.serialise() {
+            Ok(d)  => d,
+            Err(e) => return future::err(FileStoreError::from(e)).boxed()
+        };
+        let path = self.inner.identity_dir.join("local");
+        unblock(move || write_file(&path, &data, true)).boxed()
+    }
+
+    fn load_prekey(&self, id: PreKeyId) -> StoreFuture<Option<PreKey>, FileStoreError> {
+        let fs = self.inner.clone();
+        unblock(move || fs.load_prekey(id)).boxed()
+    }
+
+    fn add_prekey(&self, key: &PreKey) -> StoreFuture<(), FileStoreError> {
+        let data = match key.serialise() {
+            Ok(d)  => d,
+            Err(e) => return future::err(FileStoreError::from(e)).boxed()
+        };
+        let path = self.inner.prekey_dir.join(&key.key_id.value().to_string());
+        unblock(move || write_file(&path, &data, true)).boxed()
+    }
+
+    fn save_prekey_created(&self, id: PreKeyId, time: u64) -> StoreFuture<(), FileStoreError> {
+        let fs = self.inner.clone();
+        unblock(move || fs.save_prekey_created(id, time)).boxed()
+    }
+
+    fn delete_prekey(&self, id: PreKeyId) -> StoreFuture<(), FileStoreError> {
+        let fs = self.inner.clone();
+        unblock(move || fs.delete_prekey(id)).boxed()
+    }
+
+    fn consumed_prekeys(&self) -> StoreFuture<Vec<ConsumedPreKeys>, FileStoreError> {
+        let fs = self.inner.clone();
+        unblock(move || fs.consumed_prekeys()).boxed()
+    }
+
+    fn save_consumed_prekeys(&self, c: &ConsumedPreKeys) -> StoreFuture<(), FileStoreError> {
+        let fs = self.inner.clone();
+        let c  = c.clone();
+        unblock(move || fs.save_consumed_prekeys(&c)).boxed()
+    }
+
+    fn delete_consumed_prekeys(&self, session: &str) -> StoreFuture<(), FileStoreError> {
+        let fs = self.inner.clone();
+        let id = String::from(session);
+        unblock(move || fs.delete_consumed_prekeys(&id)).boxed()
     }
 }
 
@@ -198,11 +1001,21 @@ fn write_file(p: &Path, bytes: &[u8], sync: bool) -> FileStoreResult<()> {
             Err(e)
         })
     }
-    let path = p.with_extension("tmp");
+    // Appended rather than replacing the extension, as IDs may contain dots.
+    let mut path = p.as_os_str().to_os_string();
+    path.push(".tmp");
+    let path = PathBuf::from(path);
     try!(write(&path, bytes, sync));
     fs::rename(&path, p).map_err(From::from)
 }
 
+fn read_generation(p: &Path) -> FileStoreResult<Generation> {
+    match try!(open_file(p)) {
+        Some(mut f) => Ok(Generation::new(try!(f.read_u64::<BigEndian>()))),
+        None        => Ok(Generation::none())
+    }
+}
+
 fn remove_file(p: &Path) -> FileStoreResult<()> {
     fs::remove_file(p)
         .or_else(|e|
@@ -218,6 +1031,34 @@ fn dir_exists(p: &Path) -> bool {
     fs::metadata(p).map(|m| m.is_dir()).unwrap_or(false)
 }
 
+// Exclusive advisory lock on a file which is released when dropped. It
+// guards read-compare-write sequences against other processes.
+struct FileLock(File);
+
+impl FileLock {
+    fn acquire(p: &Path) -> FileStoreResult<FileLock> {
+        let f = try!(OpenOptions::new().write(true).create(true).open(p));
+        try!(lock_exclusive(&f));
+        Ok(FileLock(f))
+    }
+}
+
+#[cfg(unix)]
+fn lock_exclusive(f: &File) -> io::Result<()> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_: &File) -> io::Result<()> {
+    Ok(())
+}
+
 // FileStoreError ///////////////////////////////////////////////////////////
 
 pub type FileStoreResult<A> = Result<A, FileStoreError>;
@@ -0,0 +1,506 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use identity::Identity;
+use proteus::{DecodeError, EncodeError};
+use proteus::keys::{IdentityKeyPair, PreKey, PreKeyId};
+use proteus::session::Session;
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use super::*;
+use super::meta::SessionMeta;
+use super::file::{FileStore, FileStoreError};
+
+// Key layout:
+//
+//   identity/local        serialised `Identity`
+//   session/<id>          serialised `Session`
+//   generation/<id>       u64 (big-endian) `Generation` of session <id>
+//   prekey/<id>           serialised `PreKey`, <id> as u16 (big-endian)
+//   prekeytime/<id>       u64 (big-endian) creation time of prekey <id>
+//   meta/<id>             serialised `SessionMeta` of session <id>
+//   remote/<id>           remote identity fingerprint of session <id>
+//   byremote/<fp>/<id>    empty, for every session <id> in remote/
+//   archive/session/<id>  serialised `Session` of archived session <id>
+//   archive/meta/<id>     serialised `SessionMeta` of archived session <id>
+const IDENTITY:    &'static [u8] = b"identity/local";
+const SESSION:     &'static [u8] = b"session/";
+const GENERATION:  &'static [u8] = b"generation/";
+const PREKEY:      &'static [u8] = b"prekey/";
+const PREKEY_TIME: &'static [u8] = b"prekeytime/";
+const META:        &'static [u8] = b"meta/";
+const REMOTE:      &'static [u8] = b"remote/";
+const BY_REMOTE:   &'static [u8] = b"byremote/";
+const ARCHIVE:     &'static [u8] = b"archive/";
+
+// KeyValue /////////////////////////////////////////////////////////////////
+
+pub enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>)
+}
+
+/// Minimal interface of an embedded key-value engine.
+pub trait KeyValue {
+    type Error: Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// All keys starting with `prefix`.
+    fn keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Atomically and durably apply `ops`.
+    ///
+    /// If `guard` is given, the operations are only applied if the current
+    /// value of the guard key equals the expected value (`None` meaning
+    /// absent). Returns whether the operations were applied.
+    fn write(&self, guard: Option<(&[u8], Option<&[u8]>)>, ops: &[Op]) -> Result<bool, Self::Error>;
+}
+
+// KvStore //////////////////////////////////////////////////////////////////
+
+/// `Store` over any `KeyValue` engine with namespaced keys.
+#[derive(Debug)]
+pub struct KvStore<K> {
+    kv: K
+}
+
+impl<K: KeyValue> KvStore<K> {
+    pub fn new(kv: K) -> KvStore<K> {
+        KvStore { kv: kv }
+    }
+
+    pub fn inner(&self) -> &K {
+        &self.kv
+    }
+
+    pub fn session_ids(&self) -> KvStoreResult<Vec<String>, K::Error> {
+        let mut ids = Vec::new();
+        for k in try!(self.kv.keys(SESSION).map_err(KvStoreError::Kv)) {
+            if let Ok(id) = String::from_utf8(k[SESSION.len() ..].to_vec()) {
+                ids.push(id)
+            }
+        }
+        Ok(ids)
+    }
+
+    pub fn prekey_ids(&self) -> KvStoreResult<Vec<PreKeyId>, K::Error> {
+        let mut ids = Vec::new();
+        for k in try!(self.kv.keys(PREKEY).map_err(KvStoreError::Kv)) {
+            if let Ok(id) = io::Cursor::new(&k[PREKEY.len() ..]).read_u16::<BigEndian>() {
+                ids.push(PreKeyId::new(id))
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Copy identity, sessions and prekeys of a `FileStore` into this store.
+    ///
+    /// Sessions are copied as they are, without being decoded, and keep
+    /// their generation, metadata and remote identity index entry. Existing
+    /// entries are overwritten.
+    pub fn import(&self, fs: &FileStore) -> KvStoreResult<(), K::Error> {
+        if let Some(i) = try!(fs.load_identity().map_err(KvStoreError::Import)) {
+            try!(self.save_identity(&i))
+        }
+        for id in try!(fs.session_ids().map_err(KvStoreError::Import)) {
+            if let Some((b, g)) = try!(fs.load_session_data(&id).map_err(KvStoreError::Import)) {
+                let mut ops = vec![
+                    Op::Put(session_key(&id), b),
+                    Op::Put(generation_key(&id), try!(encode_generation(g)))
+                ];
+                if let Some(fp) = try!(fs.session_identity(&id).map_err(KvStoreError::Import)) {
+                    try!(self.index_ops(&id, &fp, &mut ops))
+                }
+                if let Some(m) = try!(fs.load_session_meta(&id).map_err(KvStoreError::Import)) {
+                    ops.push(Op::Put(meta_key(&id), try!(m.serialise())))
+                }
+                try!(self.kv.write(None, &ops).map_err(KvStoreError::Kv));
+            }
+        }
+        for id in try!(fs.prekey_ids().map_err(KvStoreError::Import)) {
+            if let Some(k) = try!(fs.load_prekey(id).map_err(KvStoreError::Import)) {
+                try!(self.add_prekey(&k))
+            }
+            if let Some(t) = try!(fs.prekey_created(id).map_err(KvStoreError::Import)) {
+                try!(self.save_prekey_created(id, t))
+            }
+        }
+        Ok(())
+    }
+
+    fn generation(&self, id: &str) -> KvStoreResult<Generation, K::Error> {
+        match try!(self.kv.get(&generation_key(id)).map_err(KvStoreError::Kv)) {
+            Some(b) => Ok(Generation::new(try!(io::Cursor::new(&b).read_u64::<BigEndian>()))),
+            None    => Ok(Generation::none())
+        }
+    }
+
+    // Operations pointing the index entry of session `id` at `fp`.
+    fn index_ops(&self, id: &str, fp: &str, ops: &mut Vec<Op>) -> KvStoreResult<(), K::Error> {
+        let old = try!(self.kv.get(&remote_key(id)).map_err(KvStoreError::Kv));
+        match old {
+            Some(ref o) if &o[..] == fp.as_bytes() => return Ok(()),
+            Some(ref o) => ops.push(Op::Delete(by_remote_key(&String::from_utf8_lossy(o), id))),
+            None        => ()
+        }
+        ops.push(Op::Put(remote_key(id), fp.as_bytes().to_vec()));
+        ops.push(Op::Put(by_remote_key(fp, id), Vec::new()));
+        Ok(())
+    }
+
+    // Operations removing session `id`, its metadata and index entry. The
+    // generation stays behind as a tombstone, advanced so that saves based
+    // on the removed session conflict and a new session under that ID
+    // continues from there. Returns the current generation to guard the
+    // removal with.
+    fn removal_ops(&self, id: &str, ops: &mut Vec<Op>) -> KvStoreResult<Option<Vec<u8>>, K::Error> {
+        ops.push(Op::Delete(session_key(id)));
+        ops.push(Op::Delete(meta_key(id)));
+        if let Some(fp) = try!(self.kv.get(&remote_key(id)).map_err(KvStoreError::Kv)) {
+            ops.push(Op::Delete(remote_key(id)));
+            ops.push(Op::Delete(by_remote_key(&String::from_utf8_lossy(&fp), id)))
+        }
+        let current = try!(self.generation(id));
+        if current == Generation::none() {
+            return Ok(None)
+        }
+        ops.push(Op::Put(generation_key(id), try!(encode_generation(current.next()))));
+        Ok(Some(try!(encode_generation(current))))
+    }
+
+    // Removes session `id` together with the operations `extra` adds,
+    // starting over if the session is saved concurrently. Nothing is
+    // written if `extra` returns false.
+    fn remove_session<F>(&self, id: &str, extra: F) -> KvStoreResult<(), K::Error>
+        where F: Fn(&mut Vec<Op>) -> KvStoreResult<bool, K::Error>
+    {
+        let gkey = generation_key(id);
+        loop {
+            let mut ops = Vec::new();
+            if !try!(extra(&mut ops)) {
+                return Ok(())
+            }
+            let prev  = try!(self.removal_ops(id, &mut ops));
+            let guard = (&gkey[..], prev.as_ref().map(|p| &p[..]));
+            if try!(self.kv.write(Some(guard), &ops).map_err(KvStoreError::Kv)) {
+                return Ok(())
+            }
+        }
+    }
+
+    fn put(&self, key: Vec<u8>, val: Vec<u8>) -> KvStoreResult<(), K::Error> {
+        try!(self.kv.write(None, &[Op::Put(key, val)]).map_err(KvStoreError::Kv));
+        Ok(())
+    }
+}
+
+impl<K: KeyValue> Store for KvStore<K> {
+    type Error = KvStoreError<K::Error>;
+
+    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> KvStoreResult<Option<(Session<I>, Generation)>, K::Error> {
+        match try!(self.load_session_data(id)) {
+            Some((b, g)) => Ok(Some((try!(Session::deserialise(li, &b)), g))),
+            None         => Ok(None)
+        }
+    }
+
+    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation) -> KvStoreResult<Option<Generation>, K::Error> {
+        self.commit_session(id, s, expected, &[]).map(|c| c.generation())
+    }
+
+    fn delete_session(&self, id: &str) -> KvStoreResult<(), K::Error> {
+        self.remove_session(id, |_| Ok(true))
+    }
+
+    fn archive_session(&self, id: &str) -> KvStoreResult<(), K::Error> {
+        self.remove_session(id, |ops| {
+            match try!(self.kv.get(&session_key(id)).map_err(KvStoreError::Kv)) {
+                Some(b) => ops.push(Op::Put(archive_key(SESSION, id), b)),
+                None    => return Ok(false)
+            }
+            if let Some(m) = try!(self.kv.get(&meta_key(id)).map_err(KvStoreError::Kv)) {
+                ops.push(Op::Put(archive_key(META, id), m))
+            }
+            Ok(true)
+        })
+    }
+
+    fn session_ids(&self) -> KvStoreResult<Vec<String>, K::Error> {
+        KvStore::session_ids(self)
+    }
+
+    fn sessions_by_identity(&self, fingerprint: &str) -> KvStoreResult<Vec<String>, K::Error> {
+        let prefix = by_remote_key(fingerprint, "");
+        let mut ids = Vec::new();
+        for k in try!(self.kv.keys(&prefix).map_err(KvStoreError::Kv)) {
+            if let Ok(id) = String::from_utf8(k[prefix.len() ..].to_vec()) {
+                ids.push(id)
+            }
+        }
+        Ok(ids)
+    }
+
+    fn session_identity(&self, id: &str) -> KvStoreResult<Option<String>, K::Error> {
+        let fp = try!(self.kv.get(&remote_key(id)).map_err(KvStoreError::Kv));
+        Ok(fp.and_then(|b| String::from_utf8(b).ok()))
+    }
+
+    fn load_session_meta(&self, id: &str) -> KvStoreResult<Option<SessionMeta>, K::Error> {
+        match try!(self.kv.get(&meta_key(id)).map_err(KvStoreError::Kv)) {
+            Some(b) => Ok(Some(try!(SessionMeta::deserialise(&b)))),
+            None    => Ok(None)
+        }
+    }
+
+    fn save_session_meta(&self, id: &str, meta: &SessionMeta) -> KvStoreResult<(), K::Error> {
+        self.put(meta_key(id), try!(meta.serialise()))
+    }
+
+    fn load_identity<'s>(&self) -> KvStoreResult<Option<Identity<'s>>, K::Error> {
+        match try!(self.load_identity_data()) {
+            Some(b) => Ok(Some(try!(Identity::deserialise(&b)))),
+            None    => Ok(None)
+        }
+    }
+
+    fn save_identity(&self, id: &Identity) -> KvStoreResult<(), K::Error> {
+        self.save_identity_data(&try!(id.serialise()))
+    }
+
+    fn load_prekey(&self, id: PreKeyId) -> KvStoreResult<Option<PreKey>, K::Error> {
+        match try!(self.load_prekey_data(id)) {
+            Some(b) => Ok(Some(try!(PreKey::deserialise(&b)))),
+            None    => Ok(None)
+        }
+    }
+
+    fn add_prekey(&self, key: &PreKey) -> KvStoreResult<(), K::Error> {
+        self.add_prekey_data(key.key_id, &try!(key.serialise()))
+    }
+
+    fn delete_prekey(&self, id: PreKeyId) -> KvStoreResult<(), K::Error> {
+        let ops = [Op::Delete(prekey_key(id)), Op::Delete(prekey_time_key(id))];
+        try!(self.kv.write(None, &ops).map_err(KvStoreError::Kv));
+        Ok(())
+    }
+
+    fn prekey_ids(&self) -> KvStoreResult<Vec<PreKeyId>, K::Error> {
+        KvStore::prekey_ids(self)
+    }
+
+    fn prekey_created(&self, id: PreKeyId) -> KvStoreResult<Option<u64>, K::Error> {
+        match try!(self.kv.get(&prekey_time_key(id)).map_err(KvStoreError::Kv)) {
+            Some(b) => Ok(Some(try!(io::Cursor::new(&b).read_u64::<BigEndian>()))),
+            None    => Ok(None)
+        }
+    }
+
+    fn save_prekey_created(&self, id: PreKeyId, time: u64) -> KvStoreResult<(), K::Error> {
+        let mut b = Vec::with_capacity(8);
+        try!(b.write_u64::<BigEndian>(time));
+        self.put(prekey_time_key(id), b)
+    }
+
+    // Session and prekeys are written at once, so a commit is never
+    // partial and records of consumed prekeys are not needed.
+    fn commit_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation, removed: &[PreKeyId]) -> KvStoreResult<Commit<KvStoreError<K::Error>>, K::Error> {
+        let remote = s.remote_identity().fingerprint();
+        self.commit_session_data(id, &try!(s.serialise()), &remote, expected, removed)
+    }
+}
+
+impl<K: KeyValue> RawStore for KvStore<K> {
+    fn load_session_data(&self, id: &str) -> KvStoreResult<Option<(Vec<u8>, Generation)>, K::Error> {
+        match try!(self.kv.get(&session_key(id)).map_err(KvStoreError::Kv)) {
+            Some(b) => Ok(Some((b, try!(self.generation(id))))),
+            None    => Ok(None)
+        }
+    }
+
+    fn save_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation) -> KvStoreResult<Option<Generation>, K::Error> {
+        self.commit_session_data(id, data, remote, expected, &[]).map(|c| c.generation())
+    }
+
+    fn load_identity_data(&self) -> KvStoreResult<Option<Vec<u8>>, K::Error> {
+        self.kv.get(IDENTITY).map_err(KvStoreError::Kv)
+    }
+
+    fn save_identity_data(&self, data: &[u8]) -> KvStoreResult<(), K::Error> {
+        self.put(IDENTITY.to_vec(), data.to_vec())
+    }
+
+    fn load_prekey_data(&self, id: PreKeyId) -> KvStoreResult<Option<Vec<u8>>, K::Error> {
+        self.kv.get(&prekey_key(id)).map_err(KvStoreError::Kv)
+    }
+
+    fn add_prekey_data(&self, id: PreKeyId, data: &[u8]) -> KvStoreResult<(), K::Error> {
+        self.put(prekey_key(id), data.to_vec())
+    }
+
+    fn commit_session_data(&self, id: &str, session: &[u8], remote: &str, expected: Generation, removed: &[PreKeyId]) -> KvStoreResult<Commit<KvStoreError<K::Error>>, K::Error> {
+        let gkey = generation_key(id);
+        loop {
+            // A new session replaces the stored one, guarded by the stored
+            // generation in case that changes concurrently.
+            let current = if expected == Generation::none() {
+                try!(self.generation(id))
+            } else {
+                expected
+            };
+            let prev = if current == Generation::none() {
+                None
+            } else {
+                Some(try!(encode_generation(current)))
+            };
+            let next = current.next();
+            let mut ops = vec![
+                Op::Put(session_key(id), session.to_vec()),
+                Op::Put(gkey.clone(), try!(encode_generation(next)))
+            ];
+            try!(self.index_ops(id, remote, &mut ops));
+            for p in removed {
+                ops.push(Op::Delete(prekey_key(*p)));
+                ops.push(Op::Delete(prekey_time_key(*p)))
+            }
+            let guard = (&gkey[..], prev.as_ref().map(|p| &p[..]));
+            if try!(self.kv.write(Some(guard), &ops).map_err(KvStoreError::Kv)) {
+                return Ok(Commit::Saved(next))
+            }
+            if expected != Generation::none() {
+                return Ok(Commit::Conflict)
+            }
+        }
+    }
+}
+
+fn session_key(id: &str) -> Vec<u8> {
+    let mut k = SESSION.to_vec();
+    k.extend_from_slice(id.as_bytes());
+    k
+}
+
+fn generation_key(id: &str) -> Vec<u8> {
+    let mut k = GENERATION.to_vec();
+    k.extend_from_slice(id.as_bytes());
+    k
+}
+
+fn meta_key(id: &str) -> Vec<u8> {
+    let mut k = META.to_vec();
+    k.extend_from_slice(id.as_bytes());
+    k
+}
+
+fn remote_key(id: &str) -> Vec<u8> {
+    let mut k = REMOTE.to_vec();
+    k.extend_from_slice(id.as_bytes());
+    k
+}
+
+fn by_remote_key(fp: &str, id: &str) -> Vec<u8> {
+    let mut k = BY_REMOTE.to_vec();
+    k.extend_from_slice(fp.as_bytes());
+    k.push(b'/');
+    k.extend_from_slice(id.as_bytes());
+    k
+}
+
+fn archive_key(ns: &[u8], id: &str) -> Vec<u8> {
+    let mut k = ARCHIVE.to_vec();
+    k.extend_from_slice(ns);
+    k.extend_from_slice(id.as_bytes());
+    k
+}
+
+fn prekey_key(id: PreKeyId) -> Vec<u8> {
+    let mut k = PREKEY.to_vec();
+    k.write_u16::<BigEndian>(id.value()).unwrap();
+    k
+}
+
+fn prekey_time_key(id: PreKeyId) -> Vec<u8> {
+    let mut k = PREKEY_TIME.to_vec();
+    k.write_u16::<BigEndian>(id.value()).unwrap();
+    k
+}
+
+fn encode_generation(g: Generation) -> io::Result<Vec<u8>> {
+    let mut b = Vec::with_capacity(8);
+    try!(b.write_u64::<BigEndian>(g.value()));
+    Ok(b)
+}
+
+// KvStoreError /////////////////////////////////////////////////////////////
+
+pub type KvStoreResult<A, E> = Result<A, KvStoreError<E>>;
+
+#[derive(Debug)]
+pub enum KvStoreError<E> {
+    Kv(E),
+    Io(io::Error),
+    Decode(DecodeError),
+    Encode(EncodeError),
+    Import(FileStoreError)
+}
+
+impl<E: fmt::Display> fmt::Display for KvStoreError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            KvStoreError::Kv(ref e)     => write!(f, "KvStoreError: key-value error: {}", e),
+            KvStoreError::Io(ref e)     => write!(f, "KvStoreError: I/O error: {}", e),
+            KvStoreError::Decode(ref e) => write!(f, "KvStoreError: Decode error: {}", e),
+            KvStoreError::Encode(ref e) => write!(f, "KvStoreError: Encode error: {}", e),
+            KvStoreError::Import(ref e) => write!(f, "KvStoreError: Import error: {}", e)
+        }
+    }
+}
+
+impl<E: Error> Error for KvStoreError<E> {
+    fn description(&self) -> &str {
+        "KvStoreError"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            KvStoreError::Kv(ref e)     => Some(e),
+            KvStoreError::Io(ref e)     => Some(e),
+            KvStoreError::Decode(ref e) => Some(e),
+            KvStoreError::Encode(ref e) => Some(e),
+            KvStoreError::Import(ref e) => Some(e)
+        }
+    }
+}
+
+impl<E> From<io::Error> for KvStoreError<E> {
+    fn from(e: io::Error) -> KvStoreError<E> {
+        KvStoreError::Io(e)
+    }
+}
+
+impl<E> From<DecodeError> for KvStoreError<E> {
+    fn from(e: DecodeError) -> KvStoreError<E> {
+        KvStoreError::Decode(e)
+    }
+}
+
+impl<E> From<EncodeError> for KvStoreError<E> {
+    fn from(e: EncodeError) -> KvStoreError<E> {
+        KvStoreError::Encode(e)
+    }
+}
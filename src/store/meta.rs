@@ -0,0 +1,156 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cbor::{Decoder, Encoder, Config};
+use cbor::skip::Skip;
+use proteus::{DecodeError, EncodeError};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io;
+
+/// Metadata of a session, stored next to it.
+///
+/// Times are seconds since the Unix epoch. Sessions stored before
+/// metadata was recorded have no creation time.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SessionMeta {
+    pub created:      Option<u64>,
+    pub last_encrypt: Option<u64>,
+    pub last_decrypt: Option<u64>,
+    /// Number of messages encrypted.
+    pub encrypted:    u64,
+    /// Number of messages decrypted.
+    pub decrypted:    u64,
+    /// Free-form application labels.
+    pub labels:       BTreeMap<String, String>
+}
+
+impl SessionMeta {
+    pub fn new(created: u64) -> SessionMeta {
+        SessionMeta { created: Some(created), .. SessionMeta::default() }
+    }
+
+    /// The most recent of creation, encryption and decryption time.
+    pub fn last_used(&self) -> Option<u64> {
+        cmp::max(self.created, cmp::max(self.last_encrypt, self.last_decrypt))
+    }
+
+    pub fn record_encrypt(&mut self, now: u64) {
+        self.encrypted   += 1;
+        self.last_encrypt = Some(now)
+    }
+
+    pub fn record_decrypt(&mut self, now: u64) {
+        self.decrypted   += 1;
+        self.last_decrypt = Some(now)
+    }
+
+    pub fn serialise(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut e = Encoder::new(io::Cursor::new(Vec::new()));
+        try!(self.encode(&mut e));
+        Ok(e.into_writer().into_inner())
+    }
+
+    pub fn deserialise(b: &[u8]) -> Result<SessionMeta, DecodeError> {
+        SessionMeta::decode(&mut Decoder::new(Config::default(), io::Cursor::new(b)))
+    }
+
+    fn encode<W: io::Write>(&self, e: &mut Encoder<W>) -> Result<(), EncodeError> {
+        let n = 3 + self.created.is_some() as usize
+                  + self.last_encrypt.is_some() as usize
+                  + self.last_decrypt.is_some() as usize;
+        try!(e.object(n));
+        if let Some(t) = self.created      { try!(e.u8(0)); try!(e.u64(t)) }
+        if let Some(t) = self.last_encrypt { try!(e.u8(1)); try!(e.u64(t)) }
+        if let Some(t) = self.last_decrypt { try!(e.u8(2)); try!(e.u64(t)) }
+        try!(e.u8(3)); try!(e.u64(self.encrypted));
+        try!(e.u8(4)); try!(e.u64(self.decrypted));
+        try!(e.u8(5));
+        try!(e.object(self.labels.len()));
+        for (k, v) in &self.labels {
+            try!(e.text(k));
+            try!(e.text(v))
+        }
+        Ok(())
+    }
+
+    fn decode<R: io::Read + Skip>(d: &mut Decoder<R>) -> Result<SessionMeta, DecodeError> {
+        let n = try!(d.object());
+        let mut created      = None;
+        let mut last_encrypt = None;
+        let mut last_decrypt = None;
+        let mut encrypted    = None;
+        let mut decrypted    = None;
+        let mut labels       = None;
+        for _ in 0 .. n {
+            match try!(d.u8()) {
+                0 =>
+                    if created.is_some() {
+                        return Err(DecodeError::DuplicateField("session meta created"))
+                    } else {
+                        created = Some(try!(d.u64()))
+                    },
+                1 =>
+                    if last_encrypt.is_some() {
+                        return Err(DecodeError::DuplicateField("session meta last encrypt"))
+                    } else {
+                        last_encrypt = Some(try!(d.u64()))
+                    },
+                2 =>
+                    if last_decrypt.is_some() {
+                        return Err(DecodeError::DuplicateField("session meta last decrypt"))
+                    } else {
+                        last_decrypt = Some(try!(d.u64()))
+                    },
+                3 =>
+                    if encrypted.is_some() {
+                        return Err(DecodeError::DuplicateField("session meta encrypted"))
+                    } else {
+                        encrypted = Some(try!(d.u64()))
+                    },
+                4 =>
+                    if decrypted.is_some() {
+                        return Err(DecodeError::DuplicateField("session meta decrypted"))
+                    } else {
+                        decrypted = Some(try!(d.u64()))
+                    },
+                5 =>
+                    if labels.is_some() {
+                        return Err(DecodeError::DuplicateField("session meta labels"))
+                    } else {
+                        let k = try!(d.object());
+                        let mut m = BTreeMap::new();
+                        for _ in 0 .. k {
+                            let key = try!(d.text());
+                            let val = try!(d.text());
+                            if m.insert(key, val).is_some() {
+                                return Err(DecodeError::DuplicateField("session meta label"))
+                            }
+                        }
+                        labels = Some(m)
+                    },
+                _ => try!(d.skip())
+            }
+        }
+        Ok(SessionMeta {
+            created:      created,
+            last_encrypt: last_encrypt,
+            last_decrypt: last_decrypt,
+            encrypted:    try!(encrypted.ok_or(DecodeError::MissingField("session meta encrypted"))),
+            decrypted:    try!(decrypted.ok_or(DecodeError::MissingField("session meta decrypted"))),
+            labels:       try!(labels.ok_or(DecodeError::MissingField("session meta labels")))
+        })
+    }
+}
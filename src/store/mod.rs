@@ -15,22 +15,266 @@
 
 use std::borrow::Borrow;
 use identity::Identity;
+use self::meta::SessionMeta;
 use proteus::keys::{IdentityKeyPair, PreKey, PreKeyId};
 use proteus::session::Session;
 
+pub mod cache;
+pub mod container;
+pub mod encrypted;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault;
 pub mod file;
+pub mod kv;
+pub mod meta;
+pub mod stats;
 
+#[cfg(feature = "async")]
+pub mod async_store;
+#[cfg(feature = "sled")]
+pub mod sled;
+
+/// Version of a stored session which increases with every save.
+///
+/// `Generation::none()` denotes a session which is not stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Generation(u64);
+
+impl Generation {
+    pub fn new(g: u64) -> Generation {
+        Generation(g)
+    }
+
+    pub fn none() -> Generation {
+        Generation(0)
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub fn next(&self) -> Generation {
+        Generation(self.0 + 1)
+    }
+}
+
+/// Outcome of `Store::commit_session`.
+#[derive(Debug)]
+pub enum Commit<E> {
+    /// The session has been saved with this generation and the prekeys it
+    /// consumed have been deleted.
+    Saved(Generation),
+    /// The session has been saved with this generation, but deleting the
+    /// listed prekeys failed with the given error.
+    Pending(Generation, Vec<PreKeyId>, E),
+    /// The stored generation differs from the expected one. Nothing has
+    /// been written.
+    Conflict
+}
+
+impl<E> Commit<E> {
+    /// Generation of the saved session, unless there was a conflict.
+    pub fn generation(&self) -> Option<Generation> {
+        match *self {
+            Commit::Saved(g)         => Some(g),
+            Commit::Pending(g, _, _) => Some(g),
+            Commit::Conflict         => None
+        }
+    }
+
+    pub fn map_err<F, G: FnOnce(E) -> F>(self, f: G) -> Commit<F> {
+        match self {
+            Commit::Saved(g)         => Commit::Saved(g),
+            Commit::Pending(g, p, e) => Commit::Pending(g, p, f(e)),
+            Commit::Conflict         => Commit::Conflict
+        }
+    }
+}
+
+/// Prekeys consumed by a session which is about to be saved.
+///
+/// `commit_session` records them before saving the session and removes the
+/// record once they are deleted. A record left behind by an interrupted
+/// commit is resolved by comparing the stored generation of the session
+/// with `expected`: if they differ, the session has been saved and the
+/// prekeys have to be deleted; otherwise they are still needed to decrypt
+/// the message which consumed them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumedPreKeys {
+    pub session:  String,
+    pub expected: Generation,
+    pub prekeys:  Vec<PreKeyId>
+}
+
+/// Persistent storage of a box.
+///
+/// Stores keep an index from the fingerprint of each session's remote
+/// identity to the session's ID, maintained by `save_session` (and
+/// `commit_session`) and `delete_session`. Deleting a session also deletes
+/// its metadata.
 pub trait Store {
     type Error: ::std::error::Error;
 
-    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> Result<Option<Session<I>>, Self::Error>;
-    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>) -> Result<(), Self::Error>;
+    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> Result<Option<(Session<I>, Generation)>, Self::Error>;
+
+    /// Save the session if its currently stored generation is `expected`.
+    ///
+    /// Returns the generation of the saved session or `None` if the stored
+    /// generation differs from `expected`, in which case nothing is written.
+    /// A new session (`expected` is `Generation::none()`) replaces any
+    /// stored session with the same ID, e.g. after the peer has reset its
+    /// session, and is saved with the generation following the replaced
+    /// one so that holders of the old session see a conflict.
+    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation) -> Result<Option<Generation>, Self::Error>;
     fn delete_session(&self, id: &str) -> Result<(), Self::Error>;
 
+    /// Move the session and its metadata out of the set of live sessions.
+    ///
+    /// Archived sessions can no longer be loaded through the store but are
+    /// kept for inspection or manual recovery.
+    fn archive_session(&self, id: &str) -> Result<(), Self::Error>;
+
+    /// IDs of all stored sessions.
+    fn session_ids(&self) -> Result<Vec<String>, Self::Error>;
+
+    /// IDs of the stored sessions whose remote identity has the given
+    /// fingerprint.
+    fn sessions_by_identity(&self, fingerprint: &str) -> Result<Vec<String>, Self::Error>;
+
+    /// Fingerprint of the remote identity of the stored session `id`.
+    fn session_identity(&self, id: &str) -> Result<Option<String>, Self::Error>;
+
+    /// Stores without metadata support can rely on the default, which
+    /// never finds any.
+    fn load_session_meta(&self, _id: &str) -> Result<Option<SessionMeta>, Self::Error> {
+        Ok(None)
+    }
+
+    /// The default discards the metadata.
+    fn save_session_meta(&self, _id: &str, _meta: &SessionMeta) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     fn load_identity<'s>(&self) -> Result<Option<Identity<'s>>, Self::Error>;
     fn save_identity(&self, id: &Identity) -> Result<(), Self::Error>;
 
     fn load_prekey(&self, id: PreKeyId) -> Result<Option<PreKey>, Self::Error>;
     fn add_prekey(&self, key: &PreKey) -> Result<(), Self::Error>;
+
+    /// Delete the prekey and its creation time.
     fn delete_prekey(&self, id: PreKeyId) -> Result<(), Self::Error>;
+
+    /// IDs of all stored prekeys, including the last resort prekey.
+    fn prekey_ids(&self) -> Result<Vec<PreKeyId>, Self::Error>;
+
+    /// Creation time of prekey `id` in seconds since the Unix epoch.
+    /// Prekeys stored before creation times were recorded have none, as
+    /// do all prekeys of stores relying on the defaults of this method and
+    /// `save_prekey_created`.
+    fn prekey_created(&self, _id: PreKeyId) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+
+    /// The default discards the creation time.
+    fn save_prekey_created(&self, _id: PreKeyId, _time: u64) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Records of interrupted commits, see `ConsumedPreKeys`. Stores
+    /// relying on the defaults of this method, `save_consumed_prekeys` and
+    /// `delete_consumed_prekeys` keep none, so after a crash between saving
+    /// a session and deleting the prekeys it consumed those prekeys remain.
+    fn consumed_prekeys(&self) -> Result<Vec<ConsumedPreKeys>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Durably record `c`, replacing any record of the same session.
+    fn save_consumed_prekeys(&self, _c: &ConsumedPreKeys) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn delete_consumed_prekeys(&self, _session: &str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Save the session like `save_session` and delete the prekeys it
+    /// consumed.
+    ///
+    /// A failure after the session has been saved is reported as
+    /// `Commit::Pending`, together with the prekeys which still have to be
+    /// deleted. The default records them with `save_consumed_prekeys`
+    /// before saving the session, so they can be deleted after a crash.
+    /// Stores which support atomic writes of several entries should
+    /// override this so that either all or none of the changes are applied.
+    fn commit_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation, removed: &[PreKeyId]) -> Result<Commit<Self::Error>, Self::Error> {
+        try!(record_consumed(self, id, expected, removed));
+        match try!(self.save_session(id, s, expected)) {
+            Some(g) => Ok(delete_consumed(self, id, g, removed)),
+            None    => Ok(forget_consumed(self, id, removed))
+        }
+    }
+}
+
+fn record_consumed<S: Store + ?Sized>(store: &S, id: &str, expected: Generation, removed: &[PreKeyId]) -> Result<(), S::Error> {
+    if removed.is_empty() {
+        return Ok(())
+    }
+    store.save_consumed_prekeys(&ConsumedPreKeys {
+        session:  id.to_string(),
+        expected: expected,
+        prekeys:  removed.to_vec()
+    })
+}
+
+// A record which is left behind only causes the (already deleted) prekeys
+// to be deleted again.
+fn delete_consumed<S: Store + ?Sized>(store: &S, id: &str, g: Generation, removed: &[PreKeyId]) -> Commit<S::Error> {
+    for (i, p) in removed.iter().enumerate() {
+        if let Err(e) = store.delete_prekey(*p) {
+            return Commit::Pending(g, removed[i ..].to_vec(), e)
+        }
+    }
+    if !removed.is_empty() {
+        let _ = store.delete_consumed_prekeys(id);
+    }
+    Commit::Saved(g)
+}
+
+// On conflict nothing has been saved. Should removing the record fail, the
+// prekeys are deleted later on, which errs on the side of never reusing
+// them.
+fn forget_consumed<S: Store + ?Sized>(store: &S, id: &str, removed: &[PreKeyId]) -> Commit<S::Error> {
+    if !removed.is_empty() {
+        let _ = store.delete_consumed_prekeys(id);
+    }
+    Commit::Conflict
+}
+
+/// Access to the serialised entries of a `Store`.
+///
+/// Layers which work on the serialised form rather than on values, like
+/// `EncryptedStore`, wrap a `RawStore`. Entries are what `serialise` of
+/// the corresponding value returns and writing one through this interface
+/// is equivalent to writing the value through the `Store` interface.
+pub trait RawStore: Store {
+    fn load_session_data(&self, id: &str) -> Result<Option<(Vec<u8>, Generation)>, Self::Error>;
+
+    /// Like `Store::save_session`, with `remote` being the fingerprint of
+    /// the session's remote identity.
+    fn save_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation) -> Result<Option<Generation>, Self::Error>;
+
+    fn load_identity_data(&self) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn save_identity_data(&self, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn load_prekey_data(&self, id: PreKeyId) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn add_prekey_data(&self, id: PreKeyId, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Like `Store::commit_session`.
+    fn commit_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation, removed: &[PreKeyId]) -> Result<Commit<Self::Error>, Self::Error> {
+        try!(record_consumed(self, id, expected, removed));
+        match try!(self.save_session_data(id, data, remote, expected)) {
+            Some(g) => Ok(delete_consumed(self, id, g, removed)),
+            None    => Ok(forget_consumed(self, id, removed))
+        }
+    }
 }
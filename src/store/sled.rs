@@ -0,0 +1,118 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use sled;
+use sled::transaction::{TransactionError, ConflictableTransactionError};
+use std::path::Path;
+use super::kv::{KeyValue, KvStore, Op};
+
+pub type SledStore = KvStore<SledKv>;
+
+/// `KeyValue` engine backed by a sled database.
+#[derive(Debug, Clone)]
+pub struct SledKv {
+    db: sled::Db
+}
+
+impl SledKv {
+    pub fn open(path: &Path) -> sled::Result<SledKv> {
+        Ok(SledKv { db: try!(sled::open(path)) })
+    }
+
+    pub fn from_db(db: sled::Db) -> SledKv {
+        SledKv { db: db }
+    }
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> sled::Result<SledStore> {
+        Ok(KvStore::new(try!(SledKv::open(path))))
+    }
+}
+
+impl KeyValue for SledKv {
+    type Error = sled::Error;
+
+    fn get(&self, key: &[u8]) -> sled::Result<Option<Vec<u8>>> {
+        Ok(try!(self.db.get(key)).map(|v| v.to_vec()))
+    }
+
+    fn keys(&self, prefix: &[u8]) -> sled::Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for kv in self.db.scan_prefix(prefix) {
+            let (k, _) = try!(kv);
+            keys.push(k.to_vec())
+        }
+        Ok(keys)
+    }
+
+    fn write(&self, guard: Option<(&[u8], Option<&[u8]>)>, ops: &[Op]) -> sled::Result<bool> {
+        let result = self.db.transaction(|tx| {
+            if let Some((key, expected)) = guard {
+                let current = try!(tx.get(key));
+                if current.as_ref().map(|v| &v[..]) != expected {
+                    return Ok(false)
+                }
+            }
+            for op in ops {
+                match *op {
+                    Op::Put(ref k, ref v) => { try!(tx.insert(&k[..], &v[..])); }
+                    Op::Delete(ref k)     => { try!(tx.remove(&k[..])); }
+                }
+            }
+            Ok::<bool, ConflictableTransactionError<()>>(true)
+        });
+        let applied = try!(transaction_result(result));
+        if applied {
+            try!(self.db.flush());
+        }
+        Ok(applied)
+    }
+}
+
+// The transaction in `write` never aborts itself, so an abort is reported
+// as a bug rather than a regular storage error.
+fn transaction_result(r: Result<bool, TransactionError<()>>) -> sled::Result<bool> {
+    match r {
+        Ok(applied)                       => Ok(applied),
+        Err(TransactionError::Storage(e)) => Err(e),
+        Err(TransactionError::Abort(()))  => Err(sled::Error::ReportableBug("sled transaction aborted".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sled;
+    use sled::transaction::TransactionError;
+    use super::transaction_result;
+
+    #[test]
+    fn aborted_transaction() {
+        match transaction_result(Err(TransactionError::Abort(()))) {
+            Err(sled::Error::ReportableBug(_)) => (),
+            other                              => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn storage_error() {
+        let e = sled::Error::Unsupported("test".to_string());
+        match transaction_result(Err(TransactionError::Storage(e))) {
+            Err(sled::Error::Unsupported(ref m)) if m == "test" => (),
+            other                                               => panic!("{:?}", other)
+        }
+        assert_eq!(transaction_result(Ok(true)).unwrap(), true)
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use identity::Identity;
+use proteus::keys::{IdentityKeyPair, PreKey, PreKeyId};
+use proteus::session::Session;
+use std::borrow::Borrow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::*;
+use super::meta::SessionMeta;
+
+// StatsStore ///////////////////////////////////////////////////////////////
+
+/// Counts the operations performed on another `Store`.
+#[derive(Debug)]
+pub struct StatsStore<S> {
+    inner:    S,
+    counters: Counters
+}
+
+/// Snapshot of the counters of a `StatsStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StoreStats {
+    pub session_loads:    usize,
+    pub session_misses:   usize,
+    pub session_saves:    usize,
+    pub conflicts:        usize,
+    pub session_deletes:  usize,
+    pub session_archives: usize,
+    pub identity_loads:   usize,
+    pub identity_saves:   usize,
+    pub prekey_loads:     usize,
+    pub prekey_misses:    usize,
+    pub prekey_adds:      usize,
+    pub prekey_deletes:   usize,
+    pub index_lookups:    usize,
+    pub meta_loads:       usize,
+    pub meta_saves:       usize,
+    pub errors:           usize
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    session_loads:    AtomicUsize,
+    session_misses:   AtomicUsize,
+    session_saves:    AtomicUsize,
+    conflicts:        AtomicUsize,
+    session_deletes:  AtomicUsize,
+    session_archives: AtomicUsize,
+    identity_loads:   AtomicUsize,
+    identity_saves:   AtomicUsize,
+    prekey_loads:     AtomicUsize,
+    prekey_misses:    AtomicUsize,
+    prekey_adds:      AtomicUsize,
+    prekey_deletes:   AtomicUsize,
+    index_lookups:    AtomicUsize,
+    meta_loads:       AtomicUsize,
+    meta_saves:       AtomicUsize,
+    errors:           AtomicUsize
+}
+
+impl Counters {
+    fn all(&self) -> [&AtomicUsize; 16] {
+        [&self.session_loads, &self.session_misses, &self.session_saves,
+         &self.conflicts, &self.session_deletes, &self.session_archives,
+         &self.identity_loads, &self.identity_saves, &self.prekey_loads,
+         &self.prekey_misses, &self.prekey_adds, &self.prekey_deletes,
+         &self.index_lookups, &self.meta_loads, &self.meta_saves,
+         &self.errors]
+    }
+}
+
+impl<S: Store> StatsStore<S> {
+    pub fn new(inner: S) -> StatsStore<S> {
+        StatsStore {
+            inner:    inner,
+            counters: Counters::default()
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let c = &self.counters;
+        StoreStats {
+            session_loads:    get(&c.session_loads),
+            session_misses:   get(&c.session_misses),
+            session_saves:    get(&c.session_saves),
+            conflicts:        get(&c.conflicts),
+            session_deletes:  get(&c.session_deletes),
+            session_archives: get(&c.session_archives),
+            identity_loads:   get(&c.identity_loads),
+            identity_saves:   get(&c.identity_saves),
+            prekey_loads:     get(&c.prekey_loads),
+            prekey_misses:    get(&c.prekey_misses),
+            prekey_adds:      get(&c.prekey_adds),
+            prekey_deletes:   get(&c.prekey_deletes),
+            index_lookups:    get(&c.index_lookups),
+            meta_loads:       get(&c.meta_loads),
+            meta_saves:       get(&c.meta_saves),
+            errors:           get(&c.errors)
+        }
+    }
+
+    /// Reset all counters to zero.
+    pub fn reset(&self) {
+        for c in &self.counters.all() {
+            c.store(0, Ordering::Relaxed)
+        }
+    }
+
+    fn count<A>(&self, c: &AtomicUsize, r: Result<A, S::Error>) -> Result<A, S::Error> {
+        incr(c);
+        self.count_error(r)
+    }
+
+    // Count only failures, for operations without a counter of their own.
+    fn count_error<A>(&self, r: Result<A, S::Error>) -> Result<A, S::Error> {
+        if r.is_err() {
+            incr(&self.counters.errors)
+        }
+        r
+    }
+
+    fn count_save(&self, r: Result<Option<Generation>, S::Error>) -> Result<Option<Generation>, S::Error> {
+        if let Ok(None) = r {
+            incr(&self.counters.conflicts)
+        }
+        self.count(&self.counters.session_saves, r)
+    }
+
+    // A partial commit counts the prekeys it deleted and as an error.
+    fn count_commit(&self, removed: usize, r: Result<Commit<S::Error>, S::Error>) -> Result<Commit<S::Error>, S::Error> {
+        match r {
+            Ok(Commit::Saved(_)) => {
+                self.counters.prekey_deletes.fetch_add(removed, Ordering::Relaxed);
+            }
+            Ok(Commit::Pending(_, ref p, _)) => {
+                self.counters.prekey_deletes.fetch_add(removed - p.len(), Ordering::Relaxed);
+                incr(&self.counters.errors)
+            }
+            Ok(Commit::Conflict) => incr(&self.counters.conflicts),
+            Err(_)               => ()
+        }
+        self.count(&self.counters.session_saves, r)
+    }
+}
+
+fn get(c: &AtomicUsize) -> usize {
+    c.load(Ordering::Relaxed)
+}
+
+fn incr(c: &AtomicUsize) {
+    c.fetch_add(1, Ordering::Relaxed);
+}
+
+impl<S: Store> Store for StatsStore<S> {
+    type Error = S::Error;
+
+    fn load_session<I: Borrow<IdentityKeyPair>>(&self, li: I, id: &str) -> Result<Option<(Session<I>, Generation)>, S::Error> {
+        let r = self.inner.load_session(li, id);
+        if let Ok(None) = r {
+            incr(&self.counters.session_misses)
+        }
+        self.count(&self.counters.session_loads, r)
+    }
+
+    fn save_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation) -> Result<Option<Generation>, S::Error> {
+        let r = self.inner.save_session(id, s, expected);
+        self.count_save(r)
+    }
+
+    fn delete_session(&self, id: &str) -> Result<(), S::Error> {
+        let r = self.inner.delete_session(id);
+        self.count(&self.counters.session_deletes, r)
+    }
+
+    fn archive_session(&self, id: &str) -> Result<(), S::Error> {
+        let r = self.inner.archive_session(id);
+        self.count(&self.counters.session_archives, r)
+    }
+
+    fn session_ids(&self) -> Result<Vec<String>, S::Error> {
+        self.count_error(self.inner.session_ids())
+    }
+
+    fn sessions_by_identity(&self, fingerprint: &str) -> Result<Vec<String>, S::Error> {
+        let r = self.inner.sessions_by_identity(fingerprint);
+        self.count(&self.counters.index_lookups, r)
+    }
+
+    fn session_identity(&self, id: &str) -> Result<Option<String>, S::Error> {
+        let r = self.inner.session_identity(id);
+        self.count(&self.counters.index_lookups, r)
+    }
+
+    fn load_session_meta(&self, id: &str) -> Result<Option<SessionMeta>, S::Error> {
+        let r = self.inner.load_session_meta(id);
+        self.count(&self.counters.meta_loads, r)
+    }
+
+    fn save_session_meta(&self, id: &str, meta: &SessionMeta) -> Result<(), S::Error> {
+        let r = self.inner.save_session_meta(id, meta);
+        self.count(&self.counters.meta_saves, r)
+    }
+
+    fn load_identity<'s>(&self) -> Result<Option<Identity<'s>>, S::Error> {
+        let r = self.inner.load_identity();
+        self.count(&self.counters.identity_loads, r)
+    }
+
+    fn save_identity(&self, id: &Identity) -> Result<(), S::Error> {
+        let r = self.inner.save_identity(id);
+        self.count(&self.counters.identity_saves, r)
+    }
+
+    fn load_prekey(&self, id: PreKeyId) -> Result<Option<PreKey>, S::Error> {
+        let r = self.inner.load_prekey(id);
+        if let Ok(None) = r {
+            incr(&self.counters.prekey_misses)
+        }
+        self.count(&self.counters.prekey_loads, r)
+    }
+
+    fn add_prekey(&self, key: &PreKey) -> Result<(), S::Error> {
+        let r = self.inner.add_prekey(key);
+        self.count(&self.counters.prekey_adds, r)
+    }
+
+    fn delete_prekey(&self, id: PreKeyId) -> Result<(), S::Error> {
+        let r = self.inner.delete_prekey(id);
+        self.count(&self.counters.prekey_deletes, r)
+    }
+
+    fn prekey_ids(&self) -> Result<Vec<PreKeyId>, S::Error> {
+        self.count_error(self.inner.prekey_ids())
+    }
+
+    fn prekey_created(&self, id: PreKeyId) -> Result<Option<u64>, S::Error> {
+        self.count_error(self.inner.prekey_created(id))
+    }
+
+    fn save_prekey_created(&self, id: PreKeyId, time: u64) -> Result<(), S::Error> {
+        self.count_error(self.inner.save_prekey_created(id, time))
+    }
+
+    fn consumed_prekeys(&self) -> Result<Vec<ConsumedPreKeys>, S::Error> {
+        self.count_error(self.inner.consumed_prekeys())
+    }
+
+    fn save_consumed_prekeys(&self, c: &ConsumedPreKeys) -> Result<(), S::Error> {
+        self.count_error(self.inner.save_consumed_prekeys(c))
+    }
+
+    fn delete_consumed_prekeys(&self, session: &str) -> Result<(), S::Error> {
+        self.count_error(self.inner.delete_consumed_prekeys(session))
+    }
+
+    fn commit_session<I: Borrow<IdentityKeyPair>>(&self, id: &str, s: &Session<I>, expected: Generation, removed: &[PreKeyId]) -> Result<Commit<S::Error>, S::Error> {
+        let r = self.inner.commit_session(id, s, expected, removed);
+        self.count_commit(removed.len(), r)
+    }
+}
+
+impl<S: RawStore> RawStore for StatsStore<S> {
+    fn load_session_data(&self, id: &str) -> Result<Option<(Vec<u8>, Generation)>, S::Error> {
+        let r = self.inner.load_session_data(id);
+        if let Ok(None) = r {
+            incr(&self.counters.session_misses)
+        }
+        self.count(&self.counters.session_loads, r)
+    }
+
+    fn save_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation) -> Result<Option<Generation>, S::Error> {
+        let r = self.inner.save_session_data(id, data, remote, expected);
+        self.count_save(r)
+    }
+
+    fn load_identity_data(&self) -> Result<Option<Vec<u8>>, S::Error> {
+        let r = self.inner.load_identity_data();
+        self.count(&self.counters.identity_loads, r)
+    }
+
+    fn save_identity_data(&self, data: &[u8]) -> Result<(), S::Error> {
+        let r = self.inner.save_identity_data(data);
+        self.count(&self.counters.identity_saves, r)
+    }
+
+    fn load_prekey_data(&self, id: PreKeyId) -> Result<Option<Vec<u8>>, S::Error> {
+        let r = self.inner.load_prekey_data(id);
+        if let Ok(None) = r {
+            incr(&self.counters.prekey_misses)
+        }
+        self.count(&self.counters.prekey_loads, r)
+    }
+
+    fn add_prekey_data(&self, id: PreKeyId, data: &[u8]) -> Result<(), S::Error> {
+        let r = self.inner.add_prekey_data(id, data);
+        self.count(&self.counters.prekey_adds, r)
+    }
+
+    fn commit_session_data(&self, id: &str, data: &[u8], remote: &str, expected: Generation, removed: &[PreKeyId]) -> Result<Commit<S::Error>, S::Error> {
+        let r = self.inner.commit_session_data(id, data, remote, expected, removed);
+        self.count_commit(removed.len(), r)
+    }
+}
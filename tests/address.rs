@@ -0,0 +1,118 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Session addresses and their use as session IDs of a `FileStore`.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::TempDir;
+use cryptobox::{AddressError, CBox, SessionAddress};
+use cryptobox::store::file::FileStore;
+use proteus::keys::PreKeyId;
+use std::fs;
+use std::thread;
+
+#[test]
+fn round_trip() {
+    let a = SessionAddress::new("alice", "phone-1").unwrap();
+    assert_eq!(a.as_str(), "alice_phone-1");
+    assert_eq!((a.user(), a.client(), a.domain()), ("alice", "phone-1", None));
+
+    let b = SessionAddress::with_domain("Bob", "Wire.Example.COM", "7f3a").unwrap();
+    assert_eq!(b.as_str(), "Bob@wire.example.com_7f3a");
+    assert_eq!((b.user(), b.client(), b.domain()), ("Bob", "7f3a", Some("wire.example.com")));
+
+    for x in &[a, b] {
+        assert_eq!(&SessionAddress::parse(x.as_str()).unwrap(), x);
+        assert_eq!(&x.as_str().parse::<SessionAddress>().unwrap(), x);
+        assert_eq!(x.to_string(), x.as_str());
+        assert_eq!(String::from(x), x.as_str())
+    }
+}
+
+#[test]
+fn invalid() {
+    assert_eq!(SessionAddress::new("", "phone"), Err(AddressError::InvalidUser));
+    assert_eq!(SessionAddress::new("a_b", "phone"), Err(AddressError::InvalidUser));
+    assert_eq!(SessionAddress::new("alice", "ph one"), Err(AddressError::InvalidClient));
+    assert_eq!(SessionAddress::with_domain("alice", "", "phone"), Err(AddressError::InvalidDomain));
+    assert_eq!(SessionAddress::with_domain("alice", "a/b", "phone"), Err(AddressError::InvalidDomain));
+
+    let cases = [
+        ("alice",                    AddressError::Malformed),
+        ("alice_",                   AddressError::InvalidClient),
+        ("_phone",                   AddressError::InvalidUser),
+        ("a_b_phone",                AddressError::InvalidUser),
+        ("alice@_phone",             AddressError::InvalidDomain),
+        ("alice@a@b_phone",          AddressError::InvalidDomain),
+        ("alice@Example.com_phone",  AddressError::Malformed),
+        ("alice.tmp_phone",          AddressError::InvalidUser)
+    ];
+    for &(s, e) in &cases {
+        assert_eq!(SessionAddress::parse(s), Err(e), "{}", s)
+    }
+}
+
+#[test]
+fn user_sessions() {
+    let dir  = TempDir::new("address");
+    let root = dir.path().join("alice");
+    fs::create_dir_all(&root).unwrap();
+    fs::create_dir_all(dir.path().join("bob")).unwrap();
+    let alice  = CBox::file_open(&root).unwrap();
+    let bob    = CBox::file_open(&dir.path().join("bob")).unwrap();
+    let bundle = bob.new_prekey(PreKeyId::new(1)).unwrap().serialise().unwrap();
+
+    let local  = vec![SessionAddress::new("bob", "desktop").unwrap(), SessionAddress::new("bob", "phone").unwrap()];
+    let remote = vec![
+        SessionAddress::with_domain("bob", "example.com", "laptop").unwrap(),
+        SessionAddress::with_domain("bob", "example.com", "tablet").unwrap()
+    ];
+    let others = vec!["carol_phone".to_string(), "bob".to_string(), "bob@example.org_phone".to_string()];
+    for sid in local.iter().chain(&remote).map(String::from).chain(others) {
+        let mut s = alice.session_from_prekey(sid, &bundle).unwrap();
+        alice.session_save(&mut s).unwrap()
+    }
+    fs::write(root.join("sessions").join("bob_watch.tmp"), b"").unwrap();
+
+    let store = FileStore::new(&root).unwrap();
+    assert_eq!(store.user_sessions("bob", None).unwrap(), local);
+    assert_eq!(store.user_sessions("bob", Some("Example.COM")).unwrap(), remote);
+    assert!(store.user_sessions("dave", None).unwrap().is_empty());
+
+    // Addresses whose domain contains dots are saved concurrently without
+    // their temporary files getting in each other's way.
+    let threads: Vec<_> = remote.iter().cloned().map(|sid| {
+        let alice = alice.clone();
+        thread::spawn(move || {
+            for _ in 0 .. 50 {
+                alice.session_update(&sid, |s| s.encrypt(b"hello")).unwrap().unwrap();
+            }
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap()
+    }
+    for sid in &remote {
+        assert_eq!(alice.session_meta(sid).unwrap().unwrap().encrypted, 50)
+    }
+    let tmp = fs::read_dir(root.join("sessions")).unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension().map(|x| x == "tmp").unwrap_or(false))
+        .count();
+    assert_eq!(tmp, 1)
+}
@@ -0,0 +1,250 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// `AsyncCBox` over `AsyncFileStore`, driven to completion on the calling
+// thread.
+
+extern crate cryptobox;
+extern crate futures;
+extern crate proteus;
+
+mod common;
+
+use common::{DAY, ManualClock, TempDir};
+use cryptobox::{CBox, CBoxAnyError, ErrorKind, Identity};
+use cryptobox::async_box::AsyncCBox;
+use cryptobox::store::{ConsumedPreKeys, Generation, Store};
+use cryptobox::store::async_store::{AsyncStore, StoreFuture};
+use cryptobox::store::file::{AsyncFileStore, FileStore, FileStoreError};
+use cryptobox::store::meta::SessionMeta;
+use futures::executor::block_on;
+use futures::future::{self, FutureExt};
+use proteus::keys::{IdentityKeyPair, PreKey, PreKeyId};
+use proteus::session::Session;
+use std::borrow::Borrow;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+fn open(dir: &Path, clock: &ManualClock) -> AsyncCBox<AsyncFileStore> {
+    fs::create_dir_all(dir).unwrap();
+    let store = block_on(AsyncFileStore::new(dir)).unwrap();
+    block_on(AsyncCBox::open(store)).unwrap().with_clock(clock.clone())
+}
+
+fn has_prekey(dir: &Path, id: u16) -> bool {
+    FileStore::new(dir).unwrap().load_prekey(PreKeyId::new(id)).unwrap().is_some()
+}
+
+#[test]
+fn conversation() {
+    let dir       = TempDir::new("async");
+    let clock     = ManualClock::new(DAY);
+    let alice_dir = dir.path().join("alice");
+    let bob_dir   = dir.path().join("bob");
+    let alice     = open(&alice_dir, &clock);
+    let bob       = open(&bob_dir, &clock);
+
+    // Reopening finds the saved identity.
+    assert_eq!(open(&bob_dir, &clock).fingerprint(), bob.fingerprint());
+
+    let bundle = block_on(bob.new_prekey(PreKeyId::new(1))).unwrap();
+    assert_eq!(FileStore::new(&bob_dir).unwrap().prekey_created(PreKeyId::new(1)).unwrap(), Some(DAY));
+
+    clock.set(2 * DAY);
+    let mut a = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    a.set_label("device", "phone");
+    let m1 = a.encrypt(b"one").unwrap();
+    let m2 = a.encrypt(b"two").unwrap();
+    block_on(alice.session_save(&mut a)).unwrap();
+    assert_eq!(a.fingerprint_remote(), bob.fingerprint());
+
+    clock.set(3 * DAY);
+    let (mut b, plain) = block_on(bob.session_from_message("alice", &m1)).unwrap();
+    assert_eq!(plain, b"one");
+    assert_eq!(block_on(b.decrypt(&m2)).unwrap(), b"two");
+
+    // Decryption errors name the session.
+    let e = block_on(b.decrypt(&m2)).unwrap_err();
+    assert_eq!((e.session_id(), e.kind()), (Some("alice"), ErrorKind::DuplicateMessage));
+    let e = block_on(bob.session_from_message("carol", b"garbage")).err().unwrap();
+    assert_eq!((e.session_id(), e.kind()), (Some("carol"), ErrorKind::MalformedEnvelope));
+
+    block_on(bob.session_save(&mut b)).unwrap();
+    assert!(!has_prekey(&bob_dir, 1));
+
+    // Metadata is persisted and shared with `CBox`.
+    let b = block_on(bob.session_load("alice")).unwrap().unwrap();
+    assert_eq!(b.meta().decrypted, 2);
+    assert_eq!(b.meta().created, Some(3 * DAY));
+    let a = block_on(alice.session_load("bob")).unwrap().unwrap();
+    assert_eq!(a.label("device"), Some("phone"));
+    assert_eq!(a.meta().encrypted, 2);
+    let meta = CBox::file_open(&alice_dir).unwrap().session_meta("bob").unwrap().unwrap();
+    assert_eq!((meta.created, meta.last_encrypt), (Some(2 * DAY), Some(2 * DAY)));
+
+    block_on(alice.session_delete("bob")).unwrap();
+    assert!(block_on(alice.session_load("bob")).unwrap().is_none())
+}
+
+// Without per-session locks, concurrent changes are detected on save.
+#[test]
+fn conflict() {
+    let dir   = TempDir::new("async-conflict");
+    let clock = ManualClock::new(DAY);
+    let alice = open(&dir.path().join("alice"), &clock);
+    let bob   = open(&dir.path().join("bob"), &clock);
+
+    let bundle = block_on(bob.new_prekey(PreKeyId::new(1))).unwrap();
+    let mut a  = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    block_on(alice.session_save(&mut a)).unwrap();
+
+    let mut a1 = block_on(alice.session_load("bob")).unwrap().unwrap();
+    let mut a2 = block_on(alice.session_load("bob")).unwrap().unwrap();
+    a1.encrypt(b"first").unwrap();
+    block_on(alice.session_save(&mut a1)).unwrap();
+    a2.encrypt(b"second").unwrap();
+    match block_on(alice.session_save(&mut a2)) {
+        Err(CBoxAnyError::ConflictError) => (),
+        other                            => panic!("{:?}", other)
+    }
+
+    // The session saved first can be saved again.
+    a1.encrypt(b"third").unwrap();
+    block_on(alice.session_save(&mut a1)).unwrap()
+}
+
+// `AsyncFileStore` whose prekey deletions fail while `fail` is set.
+struct FailingDeletes {
+    inner: AsyncFileStore,
+    fail:  Arc<AtomicBool>
+}
+
+impl AsyncStore for FailingDeletes {
+    type Error = FileStoreError;
+
+    fn load_session<I>(&self, li: I, id: &str) -> StoreFuture<Option<(Session<I>, Generation)>, FileStoreError>
+        where I: Borrow<IdentityKeyPair> + Send + 'static
+    {
+        self.inner.load_session(li, id)
+    }
+
+    fn save_session<I>(&self, id: &str, s: &Session<I>, expected: Generation) -> StoreFuture<Option<Generation>, FileStoreError>
+        where I: Borrow<IdentityKeyPair>
+    {
+        self.inner.save_session(id, s, expected)
+    }
+
+    fn delete_session(&self, id: &str) -> StoreFuture<(), FileStoreError> {
+        self.inner.delete_session(id)
+    }
+
+    fn load_session_meta(&self, id: &str) -> StoreFuture<Option<SessionMeta>, FileStoreError> {
+        self.inner.load_session_meta(id)
+    }
+
+    fn save_session_meta(&self, id: &str, meta: &SessionMeta) -> StoreFuture<(), FileStoreError> {
+        self.inner.save_session_meta(id, meta)
+    }
+
+    fn load_identity(&self) -> StoreFuture<Option<Identity<'static>>, FileStoreError> {
+        self.inner.load_identity()
+    }
+
+    fn save_identity(&self, id: &Identity) -> StoreFuture<(), FileStoreError> {
+        self.inner.save_identity(id)
+    }
+
+    fn load_prekey(&self, id: PreKeyId) -> StoreFuture<Option<PreKey>, FileStoreError> {
+        self.inner.load_prekey(id)
+    }
+
+    fn add_prekey(&self, key: &PreKey) -> StoreFuture<(), FileStoreError> {
+        self.inner.add_prekey(key)
+    }
+
+    fn delete_prekey(&self, id: PreKeyId) -> StoreFuture<(), FileStoreError> {
+        if self.fail.load(Ordering::SeqCst) {
+            let e = io::Error::new(io::ErrorKind::Other, "delete failed");
+            return future::err(FileStoreError::Io(e)).boxed()
+        }
+        self.inner.delete_prekey(id)
+    }
+
+    fn consumed_prekeys(&self) -> StoreFuture<Vec<ConsumedPreKeys>, FileStoreError> {
+        self.inner.consumed_prekeys()
+    }
+
+    fn save_consumed_prekeys(&self, c: &ConsumedPreKeys) -> StoreFuture<(), FileStoreError> {
+        self.inner.save_consumed_prekeys(c)
+    }
+
+    fn delete_consumed_prekeys(&self, session: &str) -> StoreFuture<(), FileStoreError> {
+        self.inner.delete_consumed_prekeys(session)
+    }
+}
+
+// A prekey which can not be deleted stays pending on the saved session and
+// is deleted on the next save or, if the session is dropped, when the box
+// is opened again.
+#[test]
+fn failing_delete() {
+    let dir     = TempDir::new("async-delete");
+    let clock   = ManualClock::new(DAY);
+    let bob_dir = dir.path().join("bob");
+    let alice   = open(&dir.path().join("alice"), &clock);
+    fs::create_dir_all(&bob_dir).unwrap();
+    let fail  = Arc::new(AtomicBool::new(false));
+    let store = FailingDeletes {
+        inner: block_on(AsyncFileStore::new(&bob_dir)).unwrap(),
+        fail:  fail.clone()
+    };
+    let bob = block_on(AsyncCBox::open(store)).unwrap();
+
+    for id in 1 .. 3 {
+        let bundle = block_on(bob.new_prekey(PreKeyId::new(id))).unwrap();
+        let mut a  = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+        let m1     = a.encrypt(b"one").unwrap();
+        let m2     = a.encrypt(b"two").unwrap();
+        block_on(alice.session_save(&mut a)).unwrap();
+
+        fail.store(true, Ordering::SeqCst);
+        let (mut b, _) = block_on(bob.session_from_message("alice", &m1)).unwrap();
+        match block_on(bob.session_save(&mut b)) {
+            Err(CBoxAnyError::StorageError(_)) => (),
+            other                              => panic!("{:?}", other)
+        }
+        assert!(has_prekey(&bob_dir, id));
+        fail.store(false, Ordering::SeqCst);
+
+        if id == 1 {
+            // The saved session is not reported as a conflict on retry.
+            assert_eq!(block_on(b.decrypt(&m2)).unwrap(), b"two");
+            block_on(bob.session_save(&mut b)).unwrap();
+            assert!(!has_prekey(&bob_dir, id))
+        } else {
+            drop(b);
+            let bob = open(&bob_dir, &clock);
+            assert!(!has_prekey(&bob_dir, id));
+            assert!(block_on(bob.session_from_message("eve", &m1)).is_err());
+            let mut b = block_on(bob.session_load("alice")).unwrap().unwrap();
+            assert_eq!(block_on(b.decrypt(&m2)).unwrap(), b"two");
+            block_on(bob.session_save(&mut b)).unwrap()
+        }
+        block_on(alice.session_delete("bob")).unwrap()
+    }
+}
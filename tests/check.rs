@@ -0,0 +1,162 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Integrity check of a damaged `FileStore`.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::TempDir;
+use cryptobox::CBox;
+use cryptobox::store::Store;
+use cryptobox::store::file::{FileStore, Problem, ProblemKind};
+use proteus::keys::PreKeyId;
+use std::fs;
+use std::path::Path;
+
+// Problems as paths relative to `root`, sorted.
+fn found(root: &Path, problems: &[Problem]) -> Vec<(String, ProblemKind)> {
+    let mut v: Vec<(String, ProblemKind)> = problems.iter()
+        .map(|p| (p.path.strip_prefix(root).unwrap().to_string_lossy().into_owned(), p.kind))
+        .collect();
+    v.sort_by(|a, b| a.0.cmp(&b.0));
+    v
+}
+
+#[test]
+fn check() {
+    let dir  = TempDir::new("check");
+    let root = dir.path().join("alice");
+    let q    = dir.path().join("quarantine");
+    for name in &["alice", "bob"] {
+        fs::create_dir_all(dir.path().join(name)).unwrap()
+    }
+    let fp = {
+        let alice  = CBox::file_open(&root).unwrap();
+        let bob    = CBox::file_open(&dir.path().join("bob")).unwrap();
+        let bundle = bob.new_prekey(PreKeyId::new(1)).unwrap();
+        let mut s  = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+        alice.session_save(&mut s).unwrap();
+        alice.new_prekey(PreKeyId::new(1)).unwrap();
+        alice.new_prekey(PreKeyId::new(2)).unwrap();
+        bob.fingerprint()
+    };
+    let store = FileStore::new(&root).unwrap();
+    assert!(store.check(None).unwrap().is_empty());
+
+    let prekey = fs::read(root.join("prekeys").join("1")).unwrap();
+    fs::write(root.join("sessions").join("bob"), b"\0\0\0\0\0\0\0\x01garbage").unwrap();
+    fs::write(root.join("sessions").join("carol.tmp"), b"").unwrap();
+    fs::write(root.join("prekeys").join("2"), b"garbage").unwrap();
+    fs::write(root.join("prekeys").join("3"), &prekey).unwrap();
+    fs::write(root.join("prekeys").join("notes"), b"").unwrap();
+    fs::write(root.join("identities").join("stray"), b"").unwrap();
+    fs::write(root.join("meta").join("bob"), b"garbage").unwrap();
+    fs::write(root.join("prekeys.created").join("2"), b"short").unwrap();
+    fs::write(root.join("index").join("sessions").join("zed"), b"00ff").unwrap();
+    fs::create_dir_all(root.join("consumed")).unwrap();
+    fs::write(root.join("consumed").join("bob"), b"garbage").unwrap();
+    fs::create_dir_all(root.join("sessions.deleted")).unwrap();
+    fs::write(root.join("sessions.deleted").join("dave"), b"short").unwrap();
+
+    // The index entries of the broken session are reported, too.
+    let mut expected = vec![
+        ("consumed/bob".to_string(),             ProblemKind::ConsumedPreKeys),
+        ("identities/stray".to_string(),         ProblemKind::Unknown),
+        (format!("index/identities/{}/bob", fp), ProblemKind::Index),
+        ("index/sessions/bob".to_string(),       ProblemKind::Index),
+        ("index/sessions/zed".to_string(),       ProblemKind::Index),
+        ("meta/bob".to_string(),                 ProblemKind::Meta),
+        ("prekeys.created/2".to_string(),        ProblemKind::PreKeyCreated),
+        ("prekeys/2".to_string(),                ProblemKind::PreKey),
+        ("prekeys/3".to_string(),                ProblemKind::PreKey),
+        ("prekeys/notes".to_string(),            ProblemKind::Unknown),
+        ("sessions.deleted/dave".to_string(),    ProblemKind::Tombstone),
+        ("sessions/bob".to_string(),             ProblemKind::Session),
+        ("sessions/carol.tmp".to_string(),       ProblemKind::TempFile)
+    ];
+    expected.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Without quarantine nothing is touched.
+    let problems = store.check(None).unwrap();
+    assert_eq!(found(&root, &problems), expected);
+    assert!(problems.iter().all(|p| p.quarantined.is_none() && p.path.exists()));
+
+    let problems = store.check(Some(&q)).unwrap();
+    assert_eq!(found(&root, &problems), expected);
+    for p in &problems {
+        match p.kind {
+            ProblemKind::Unknown => {
+                assert!(p.quarantined.is_none());
+                assert!(p.path.exists())
+            }
+            _ => {
+                let dest = p.quarantined.as_ref().unwrap();
+                assert_eq!(dest, &q.join(p.path.strip_prefix(&root).unwrap()));
+                assert!(dest.exists() && !p.path.exists())
+            }
+        }
+    }
+    assert_eq!(fs::read(q.join("prekeys").join("2")).unwrap(), b"garbage");
+
+    // What remains is usable.
+    let problems = store.check(None).unwrap();
+    assert_eq!(problems.iter().filter(|p| p.kind != ProblemKind::Unknown).count(), 0);
+    assert!(store.load_prekey(PreKeyId::new(1)).unwrap().is_some());
+    let alice = CBox::file_open(&root).unwrap();
+    assert!(alice.session_load("bob").unwrap().is_none());
+    drop(alice);
+
+    // Earlier quarantined files are kept.
+    fs::write(root.join("prekeys").join("2"), b"again").unwrap();
+    let problems = store.check(Some(&q)).unwrap();
+    let p = problems.iter().find(|p| p.kind == ProblemKind::PreKey).unwrap();
+    assert_eq!(p.quarantined, Some(q.join("prekeys").join("2.1")));
+    assert_eq!(fs::read(q.join("prekeys").join("2")).unwrap(), b"garbage")
+}
+
+// Loading a session does not depend on its metadata being readable.
+#[test]
+fn corrupt_meta() {
+    let dir = TempDir::new("check-meta");
+    for name in &["alice", "bob"] {
+        fs::create_dir_all(dir.path().join(name)).unwrap()
+    }
+    let root   = dir.path().join("alice");
+    let alice  = CBox::file_open(&root).unwrap();
+    let bob    = CBox::file_open(&dir.path().join("bob")).unwrap();
+    let bundle = bob.new_prekey(PreKeyId::new(1)).unwrap();
+    let mut s  = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    s.set_label("device", "phone");
+    s.encrypt(b"hello").unwrap();
+    alice.session_save(&mut s).unwrap();
+    drop(s);
+
+    fs::write(root.join("meta").join("bob"), b"garbage").unwrap();
+    let problems = FileStore::new(&root).unwrap().check(None).unwrap();
+    assert_eq!(found(&root, &problems), vec![("meta/bob".to_string(), ProblemKind::Meta)]);
+    assert!(alice.session_meta("bob").is_err());
+
+    // The session loads with default metadata, which the next save stores.
+    let mut s = alice.session_load("bob").unwrap().unwrap();
+    assert_eq!(s.label("device"), None);
+    assert_eq!(s.meta().encrypted, 0);
+    s.encrypt(b"again").unwrap();
+    alice.session_save(&mut s).unwrap();
+    assert_eq!(alice.session_meta("bob").unwrap().unwrap().encrypted, 1);
+    assert!(FileStore::new(&root).unwrap().check(None).unwrap().is_empty())
+}
@@ -0,0 +1,87 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Fixtures shared by the integration tests. Not every test uses all of
+// them.
+#![allow(dead_code)]
+
+use cryptobox::{CBox, Clock};
+use cryptobox::store::file::FileStore;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory below the system's temporary directory, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let p = env::temp_dir().join(format!("cryptobox-test-{}-{}-{}", process::id(), name, n));
+        fs::create_dir_all(&p).unwrap();
+        TempDir(p)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Box over a `FileStore` in the directory `name` below `dir`, which is
+/// created if need be.
+pub fn open<P: AsRef<Path>>(dir: P, name: &str) -> CBox<FileStore> {
+    let path = dir.as_ref().join(name);
+    fs::create_dir_all(&path).unwrap();
+    CBox::file_open(&path).unwrap()
+}
+
+pub const DAY: u64 = 24 * 60 * 60;
+
+/// Clock which only moves when told to. Clones share the time.
+#[derive(Clone)]
+pub struct ManualClock(Arc<AtomicUsize>);
+
+impl ManualClock {
+    pub fn new(t: u64) -> ManualClock {
+        ManualClock(Arc::new(AtomicUsize::new(t as usize)))
+    }
+
+    pub fn set(&self, t: u64) {
+        self.0.store(t as usize, Ordering::SeqCst)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst) as u64
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Recovery and compaction of `ContainerFile`s.
+
+extern crate cryptobox;
+
+mod common;
+
+use common::TempDir;
+use cryptobox::store::container::ContainerFile;
+use cryptobox::store::kv::{KeyValue, Op};
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::Path;
+
+const HEADER: u64 = 10;
+
+fn put(c: &ContainerFile, k: &str, v: &[u8]) {
+    assert!(c.write(None, &[Op::Put(k.as_bytes().to_vec(), v.to_vec())]).unwrap())
+}
+
+fn get(c: &ContainerFile, k: &str) -> Option<Vec<u8>> {
+    c.get(k.as_bytes()).unwrap()
+}
+
+fn size(p: &Path) -> u64 {
+    fs::metadata(p).unwrap().len()
+}
+
+// Size of the record holding a single put of `k` and `v`.
+fn record_len(k: &str, v: &[u8]) -> u64 {
+    8 + (4 + 1 + 4 + k.len() + 4 + v.len()) as u64 + 32
+}
+
+fn flip(p: &Path, offset: u64) {
+    let mut data = fs::read(p).unwrap();
+    data[offset as usize] ^= 0xff;
+    fs::write(p, &data).unwrap()
+}
+
+// A container with records "a" and "b", closed again.
+fn two_records(p: &Path) {
+    let c = ContainerFile::open(p).unwrap();
+    put(&c, "a", b"first");
+    put(&c, "b", b"second");
+    assert_eq!(size(p), HEADER + record_len("a", b"first") + record_len("b", b"second"))
+}
+
+#[test]
+fn reopen() {
+    let dir  = TempDir::new("container-reopen");
+    let path = dir.path().join("c.box");
+    two_records(&path);
+    {
+        let c = ContainerFile::open(&path).unwrap();
+        assert_eq!(c.recovered(), 0);
+        assert!(c.write(None, &[Op::Delete(b"a".to_vec())]).unwrap());
+        assert!(!c.write(Some((b"b", Some(b"other"))), &[Op::Put(b"b".to_vec(), b"x".to_vec())]).unwrap());
+    }
+    let c = ContainerFile::open(&path).unwrap();
+    assert_eq!(get(&c, "a"), None);
+    assert_eq!(get(&c, "b"), Some(b"second".to_vec()));
+    assert_eq!(c.keys(b"").unwrap(), vec![b"b".to_vec()])
+}
+
+// The container is held exclusively while open.
+#[test]
+fn locked() {
+    let dir  = TempDir::new("container-locked");
+    let path = dir.path().join("c.box");
+    let _c   = ContainerFile::open(&path).unwrap();
+    assert!(ContainerFile::open(&path).is_err())
+}
+
+// Creating the file was interrupted while writing the header.
+#[test]
+fn torn_header() {
+    let dir  = TempDir::new("container-header");
+    let path = dir.path().join("c.box");
+    for n in 0 .. HEADER as usize {
+        fs::write(&path, &b"CBOXCTNR\x00\x01"[.. n]).unwrap();
+        let c = ContainerFile::open(&path).unwrap();
+        assert!(c.keys(b"").unwrap().is_empty());
+        put(&c, "a", b"value");
+        drop(c);
+        assert_eq!(get(&ContainerFile::open(&path).unwrap(), "a"), Some(b"value".to_vec()))
+    }
+
+    // Short files which are not containers are left alone.
+    fs::write(&path, b"hello").unwrap();
+    assert_eq!(ContainerFile::open(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(fs::read(&path).unwrap(), b"hello")
+}
+
+// An append was interrupted part way through the last record.
+#[test]
+fn torn_record() {
+    let dir  = TempDir::new("container-torn");
+    let path = dir.path().join("c.box");
+    let keep = HEADER + record_len("a", b"first");
+    let full = keep + record_len("b", b"second");
+    for len in keep .. full {
+        two_records(&path);
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len).unwrap();
+        {
+            let c = ContainerFile::open(&path).unwrap();
+            assert_eq!(c.recovered(), len - keep);
+            assert_eq!(get(&c, "a"), Some(b"first".to_vec()));
+            assert_eq!(get(&c, "b"), None);
+            put(&c, "c", b"third");
+        }
+        let c = ContainerFile::open(&path).unwrap();
+        assert_eq!(c.recovered(), 0);
+        assert_eq!(get(&c, "c"), Some(b"third".to_vec()));
+        drop(c);
+        fs::remove_file(&path).unwrap()
+    }
+}
+
+// The last record does not match its checksum.
+#[test]
+fn bad_checksum_last() {
+    let dir  = TempDir::new("container-last");
+    let path = dir.path().join("c.box");
+    two_records(&path);
+    flip(&path, size(&path) - 40);
+    let c = ContainerFile::open(&path).unwrap();
+    assert_eq!(c.recovered(), record_len("b", b"second"));
+    assert_eq!(get(&c, "a"), Some(b"first".to_vec()));
+    assert_eq!(get(&c, "b"), None)
+}
+
+// A damaged record is followed by valid ones, which are not given up.
+#[test]
+fn bad_checksum_middle() {
+    let dir  = TempDir::new("container-middle");
+    let path = dir.path().join("c.box");
+    two_records(&path);
+    let before = fs::read(&path).unwrap();
+    for &offset in &[HEADER + 20, HEADER + record_len("a", b"first") - 1] {
+        flip(&path, offset);
+        let e = ContainerFile::open(&path).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        flip(&path, offset);
+        assert_eq!(fs::read(&path).unwrap(), before)
+    }
+    let c = ContainerFile::open(&path).unwrap();
+    assert_eq!(get(&c, "b"), Some(b"second".to_vec()))
+}
+
+// The length of a record other than the last one is damaged. Even if it
+// points beyond the end of the file, the record is not mistaken for a torn
+// one and nothing is truncated.
+#[test]
+fn bad_length_middle() {
+    let dir  = TempDir::new("container-length");
+    let path = dir.path().join("c.box");
+    two_records(&path);
+    let before = fs::read(&path).unwrap();
+    for &offset in &[HEADER, HEADER + 3, HEADER + 4] {
+        flip(&path, offset);
+        let e = ContainerFile::open(&path).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        flip(&path, offset);
+        assert_eq!(fs::read(&path).unwrap(), before)
+    }
+}
+
+#[test]
+fn compaction() {
+    let dir   = TempDir::new("container-compact");
+    let path  = dir.path().join("c.box");
+    let value = vec![7; 1024];
+    {
+        let c = ContainerFile::open(&path).unwrap();
+        put(&c, "gone", b"soon");
+        assert!(c.write(None, &[Op::Delete(b"gone".to_vec())]).unwrap());
+        put(&c, "kept", b"kept");
+
+        // Overwriting the same key eventually triggers a compaction, after
+        // which only the live values remain.
+        let mut compacted = false;
+        for i in 0 .. 256 {
+            let before = size(&path);
+            put(&c, "hot", &value);
+            if size(&path) < before {
+                compacted = true;
+                assert!(i > 32);
+                break
+            }
+        }
+        assert!(compacted);
+        assert!(size(&path) < 4 * record_len("hot", &value));
+        assert_eq!(get(&c, "gone"), None);
+        assert_eq!(get(&c, "kept"), Some(b"kept".to_vec()));
+        assert_eq!(get(&c, "hot"), Some(value.clone()));
+
+        put(&c, "hot", b"cold");
+        c.compact().unwrap();
+        assert_eq!(size(&path), HEADER + record_len("hot", b"cold") + record_len("kept", b"kept"));
+        put(&c, "new", b"after");
+    }
+    assert!(!dir.path().join("c.box.tmp").exists());
+    let c = ContainerFile::open(&path).unwrap();
+    assert_eq!(c.recovered(), 0);
+    assert_eq!(get(&c, "hot"), Some(b"cold".to_vec()));
+    assert_eq!(get(&c, "kept"), Some(b"kept".to_vec()));
+    assert_eq!(get(&c, "new"), Some(b"after".to_vec()));
+    assert_eq!(c.keys(b"").unwrap().len(), 3)
+}
+
+// The temporary file of a compaction is named after the whole file name,
+// so containers differing only in their extension do not interfere.
+#[test]
+fn compaction_tmp_name() {
+    let dir   = TempDir::new("container-tmp");
+    let path  = dir.path().join("c.box");
+    let other = dir.path().join("c.tmp");
+    fs::write(&other, b"unrelated").unwrap();
+    let c = ContainerFile::open(&path).unwrap();
+    put(&c, "a", b"value");
+    c.compact().unwrap();
+    assert_eq!(fs::read(&other).unwrap(), b"unrelated");
+    assert_eq!(get(&c, "a"), Some(b"value".to_vec()))
+}
+
+// A write succeeds even if the compaction it triggers fails.
+#[test]
+fn compaction_failure() {
+    let dir   = TempDir::new("container-compact-fail");
+    let path  = dir.path().join("c.box");
+    let value = vec![7; 1024];
+    let c     = ContainerFile::open(&path).unwrap();
+
+    // The temporary file can not be created while a directory is in its
+    // place.
+    fs::create_dir(dir.path().join("c.box.tmp")).unwrap();
+    for _ in 0 .. 256 {
+        put(&c, "hot", &value)
+    }
+    assert!(size(&path) > 128 * record_len("hot", &value));
+    assert!(c.compact().is_err());
+    assert_eq!(get(&c, "hot"), Some(value.clone()));
+
+    fs::remove_dir(dir.path().join("c.box.tmp")).unwrap();
+    put(&c, "hot", b"cold");
+    assert!(size(&path) < 4 * record_len("hot", &value));
+    drop(c);
+    assert_eq!(get(&ContainerFile::open(&path).unwrap(), "hot"), Some(b"cold".to_vec()))
+}
@@ -0,0 +1,305 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Crash-consistency tests. Every scenario is run once without faults to
+// count the store operations involved and then once for every operation
+// failing on its own and for the process "crashing" at it. A failed
+// operation is retried once unless the process crashed. Afterwards the box
+// is reopened over the same directory without faults.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::{DAY, ManualClock, TempDir};
+use cryptobox::CBox;
+use cryptobox::store::Store;
+use cryptobox::store::fault::{Fault, FaultHandle, FaultStore};
+use cryptobox::store::file::FileStore;
+use proteus::keys::{MAX_PREKEY_ID, PreKeyId};
+use std::path::Path;
+use std::time::Duration;
+
+fn open_faulty(dir: &Path) -> (CBox<FaultStore<FileStore>>, FaultHandle) {
+    let store  = FaultStore::new(FileStore::new(dir).unwrap());
+    let handle = store.handle();
+    (CBox::from_store(store).unwrap(), handle)
+}
+
+fn has_prekey(dir: &Path, id: u16) -> bool {
+    FileStore::new(dir).unwrap().load_prekey(PreKeyId::new(id)).unwrap().is_some()
+}
+
+fn has_session(dir: &Path, sid: &str) -> bool {
+    FileStore::new(dir).unwrap().session_ids().unwrap().iter().any(|s| s == sid)
+}
+
+// Run `scenario` without faults and then with every possible fault.
+fn for_each_fault<F: Fn(Option<Fault>) -> usize>(scenario: F) {
+    let n = scenario(None);
+    assert!(n > 0);
+    for i in 0 .. n {
+        scenario(Some(Fault::Fail(i)));
+        scenario(Some(Fault::Crash(i)));
+    }
+}
+
+// A fresh session between alice and bob (stored in `bob_dir`), with bob
+// answering once.
+fn establish(alice: &CBox<FileStore>, bob: &CBox<FileStore>, bob_dir: &Path, prekey: u16) {
+    alice.session_delete("bob").unwrap();
+    bob.session_delete("alice").unwrap();
+    let bundle = bob.new_prekey(PreKeyId::new(prekey)).unwrap();
+    let mut a = alice.session_from_prekey("bob".to_string(), &bundle.serialise().unwrap()).unwrap();
+    let msg = a.encrypt(b"hello").unwrap();
+    alice.session_save(&mut a).unwrap();
+
+    let (mut b, plain) = bob.session_from_message("alice".to_string(), &msg).unwrap();
+    assert_eq!(&plain[..], b"hello");
+    bob.session_save(&mut b).unwrap();
+    assert!(!has_prekey(bob_dir, prekey));
+
+    let reply = b.encrypt(b"hi").unwrap();
+    bob.session_save(&mut b).unwrap();
+    assert_eq!(&a.decrypt(&reply).unwrap()[..], b"hi");
+    alice.session_save(&mut a).unwrap();
+}
+
+// Opening a box creates and saves its identity.
+#[test]
+fn open() {
+    for_each_fault(|fault| {
+        let dir   = TempDir::new("open");
+        let store = FaultStore::new(FileStore::new(dir.path()).unwrap());
+        let h     = store.handle();
+        h.set_fault(fault);
+        let fp = CBox::from_store(store).ok().map(|b| b.fingerprint());
+        let ops = h.ops();
+
+        let b = CBox::file_open(dir.path()).unwrap();
+        if let Some(fp) = fp {
+            assert_eq!(fp, b.fingerprint())
+        }
+        assert_eq!(b.fingerprint(), CBox::file_open(dir.path()).unwrap().fingerprint());
+        ops
+    })
+}
+
+// Bob creates a session from alice's first message, consuming a prekey.
+#[test]
+fn session_from_message() {
+    for_each_fault(|fault| {
+        let alice_dir = TempDir::new("alice");
+        let bob_dir   = TempDir::new("bob");
+        let alice     = CBox::file_open(alice_dir.path()).unwrap();
+        let (bob, h)  = open_faulty(bob_dir.path());
+
+        let bundle = bob.new_prekey(PreKeyId::new(1)).unwrap();
+        let mut a  = alice.session_from_prekey("bob".to_string(), &bundle.serialise().unwrap()).unwrap();
+        let m1     = a.encrypt(b"one").unwrap();
+        let m2     = a.encrypt(b"two").unwrap();
+        alice.session_save(&mut a).unwrap();
+        drop(a);
+
+        h.set_fault(fault);
+        let mut s = bob.session_from_message("alice".to_string(), &m1).ok().map(|(s, p)| {
+            assert_eq!(&p[..], b"one");
+            s
+        });
+        let ok = match s {
+            Some(ref mut s) => bob.session_save(s).is_ok(),
+            None            => false
+        };
+        let ops = h.ops();
+        assert!(!ok || (has_session(bob_dir.path(), "alice") && !has_prekey(bob_dir.path(), 1)));
+
+        // Retry once unless the process is gone.
+        if !h.crashed() {
+            h.set_fault(None);
+            if let Some(ref mut s) = s {
+                if !ok {
+                    bob.session_save(s).unwrap()
+                }
+            }
+        }
+        drop(s);
+        drop(bob);
+
+        // The session is saved before its prekey is deleted, so the first
+        // message is never lost, and the prekey is gone once the session
+        // is stored.
+        let bob    = CBox::file_open(bob_dir.path()).unwrap();
+        let stored = has_session(bob_dir.path(), "alice");
+        assert!(stored || has_prekey(bob_dir.path(), 1), "first message lost");
+        if stored {
+            assert!(!has_prekey(bob_dir.path(), 1), "consumed prekey left behind");
+            assert!(bob.session_from_message("eve".to_string(), &m1).is_err());
+            let mut s = bob.session_load("alice".to_string()).unwrap().unwrap();
+            assert_eq!(&s.decrypt(&m2).unwrap()[..], b"two");
+            bob.session_save(&mut s).unwrap();
+        } else {
+            // Redelivery of the first message succeeds.
+            let (mut s, p) = bob.session_from_message("alice".to_string(), &m1).unwrap();
+            assert_eq!(&p[..], b"one");
+            bob.session_save(&mut s).unwrap();
+            assert!(!has_prekey(bob_dir.path(), 1))
+        }
+
+        establish(&alice, &bob, bob_dir.path(), 2);
+        ops
+    })
+}
+
+// Bob's existing session receives a message for a new session of alice,
+// which consumes another prekey.
+#[test]
+fn decrypt_with_prekey() {
+    for_each_fault(|fault| {
+        let alice_dir = TempDir::new("alice");
+        let bob_dir   = TempDir::new("bob");
+        let alice     = CBox::file_open(alice_dir.path()).unwrap();
+        {
+            let bob = CBox::file_open(bob_dir.path()).unwrap();
+            establish(&alice, &bob, bob_dir.path(), 1);
+        }
+        let (bob, h) = open_faulty(bob_dir.path());
+
+        // Alice lost her session and starts over with prekey 2.
+        alice.session_delete("bob").unwrap();
+        let bundle = bob.new_prekey(PreKeyId::new(2)).unwrap();
+        let mut a  = alice.session_from_prekey("bob".to_string(), &bundle.serialise().unwrap()).unwrap();
+        let m1     = a.encrypt(b"again").unwrap();
+        alice.session_save(&mut a).unwrap();
+        drop(a);
+
+        h.set_fault(fault);
+        let mut s = bob.session_load("alice".to_string()).ok().and_then(|s| {
+            let mut s = s.unwrap();
+            s.decrypt(&m1).ok().map(|p| {
+                assert_eq!(&p[..], b"again");
+                s
+            })
+        });
+        let ok = match s {
+            Some(ref mut s) => bob.session_save(s).is_ok(),
+            None            => false
+        };
+        let ops = h.ops();
+        assert!(!ok || !has_prekey(bob_dir.path(), 2));
+
+        // Retry once unless the process is gone.
+        if !h.crashed() {
+            h.set_fault(None);
+            if let Some(ref mut s) = s {
+                if !ok {
+                    bob.session_save(s).unwrap()
+                }
+            }
+        }
+        drop(s);
+        drop(bob);
+
+        // The message can be decrypted again if and only if its prekey
+        // has not been consumed.
+        let bob    = CBox::file_open(bob_dir.path()).unwrap();
+        let prekey = has_prekey(bob_dir.path(), 2);
+        let mut s  = bob.session_load("alice".to_string()).unwrap().unwrap();
+        match s.decrypt(&m1) {
+            Ok(p) => {
+                assert!(prekey, "consumed prekey used again");
+                assert_eq!(&p[..], b"again");
+                bob.session_save(&mut s).unwrap();
+                assert!(!has_prekey(bob_dir.path(), 2))
+            }
+            // The session has been saved with the message decrypted.
+            Err(_) => {
+                assert!(!prekey, "consumed prekey left behind");
+                assert!(bob.session_from_message("eve".to_string(), &m1).is_err())
+            }
+        }
+        drop(s);
+
+        establish(&alice, &bob, bob_dir.path(), 3);
+        ops
+    })
+}
+
+// Generating prekeys either stores them or fails.
+#[test]
+fn new_prekey() {
+    for_each_fault(|fault| {
+        let alice_dir = TempDir::new("alice");
+        let bob_dir   = TempDir::new("bob");
+        let alice     = CBox::file_open(alice_dir.path()).unwrap();
+        let (bob, h)  = open_faulty(bob_dir.path());
+
+        h.set_fault(fault);
+        let bundle = bob.new_prekey(PreKeyId::new(1)).ok();
+        let ops = h.ops();
+        drop(bob);
+
+        assert_eq!(bundle.is_some(), has_prekey(bob_dir.path(), 1));
+
+        let bob = CBox::file_open(bob_dir.path()).unwrap();
+        if let Some(bundle) = bundle {
+            let mut a = alice.session_from_prekey("bob".to_string(), &bundle.serialise().unwrap()).unwrap();
+            let msg   = a.encrypt(b"hello").unwrap();
+            alice.session_save(&mut a).unwrap();
+            let (mut s, p) = bob.session_from_message("alice".to_string(), &msg).unwrap();
+            assert_eq!(&p[..], b"hello");
+            bob.session_save(&mut s).unwrap()
+        }
+        establish(&alice, &bob, bob_dir.path(), 2);
+        ops
+    })
+}
+
+// A failed rotation reports the replacements it stored and the prekeys it
+// deleted, and nothing else has changed.
+#[test]
+fn rotate_prekeys() {
+    for_each_fault(|fault| {
+        let dir      = TempDir::new("rotate");
+        let clock    = ManualClock::new(DAY);
+        let (bob, h) = open_faulty(dir.path());
+        let bob      = bob.with_clock(clock.clone());
+        bob.new_prekey(MAX_PREKEY_ID).unwrap();
+        bob.new_prekey(PreKeyId::new(1)).unwrap();
+        bob.new_prekey(PreKeyId::new(2)).unwrap();
+
+        clock.set(40 * DAY);
+        h.set_fault(fault);
+        let r = match bob.rotate_prekeys(Duration::from_secs(30 * DAY)) {
+            Ok(r)  => {
+                assert_eq!(r.new.len(), 2);
+                assert_eq!(r.removed.len(), 2);
+                r
+            }
+            Err(e) => e.into_rotation()
+        };
+        let ops = h.ops();
+        drop(bob);
+
+        for b in &r.new {
+            assert!(has_prekey(dir.path(), b.prekey_id.value()))
+        }
+        for id in 1 .. 3 {
+            assert_eq!(has_prekey(dir.path(), id), !r.removed.contains(&PreKeyId::new(id)))
+        }
+        assert!(has_prekey(dir.path(), MAX_PREKEY_ID.value()));
+        ops
+    })
+}
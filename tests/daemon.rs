@@ -0,0 +1,164 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Bob's box served by a daemon, talking to alice's local box.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::{TempDir, open};
+use cryptobox::ErrorKind;
+use cryptobox::daemon::client::{Client, ClientError};
+use cryptobox::daemon::protocol::{self, MAX_FRAME, Request, Response};
+use cryptobox::daemon::server::{self, Server};
+use proteus::keys::PreKeyId;
+use std::fs;
+use std::io::{self, Cursor};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+// Serve bob's box in the background and return the socket path.
+fn serve(dir: &Path) -> PathBuf {
+    let socket   = dir.join("bob.sock");
+    let bob      = open(dir, "bob");
+    let listener = server::bind(&socket).unwrap();
+    thread::spawn(move || Server::new(bob).serve(listener));
+    socket
+}
+
+fn remote_code<A: ::std::fmt::Debug>(r: Result<A, ClientError>) -> u16 {
+    match r {
+        Err(ClientError::Remote(c, _)) => c,
+        other                          => panic!("{:?}", other)
+    }
+}
+
+#[test]
+fn conversation() {
+    let dir    = TempDir::new("daemon");
+    let socket = serve(dir.path());
+    let alice  = open(dir.path(), "alice");
+    let mut c  = Client::connect(&socket).unwrap();
+
+    let bundle = c.new_prekey(PreKeyId::new(1)).unwrap();
+    let mut a  = alice.session_from_prekey("bob", &bundle).unwrap();
+    let msg    = a.encrypt(b"hello").unwrap();
+    alice.session_save(&mut a).unwrap();
+
+    // The first message creates bob's session.
+    assert_eq!(c.decrypt("alice", &msg).unwrap(), b"hello");
+    assert_eq!(c.fingerprint_remote("alice").unwrap(), alice.fingerprint());
+
+    // Another connection sees the same box.
+    let mut c2 = Client::connect(&socket).unwrap();
+    assert_eq!(c2.fingerprint().unwrap(), c.fingerprint().unwrap());
+    let reply = c2.encrypt("alice", b"hi").unwrap();
+    assert_eq!(a.decrypt(&reply).unwrap(), b"hi");
+
+    c.session_delete("alice").unwrap();
+    let missing = protocol::SESSION_NOT_FOUND;
+    assert_eq!(remote_code(c.encrypt("alice", b"gone")), missing);
+    assert_eq!(remote_code(c.fingerprint_remote("alice")), missing);
+    assert_eq!(remote_code(c.decrypt("alice", &reply)), missing);
+    assert_eq!(remote_code(c.decrypt("alice", b"garbage")), ErrorKind::MalformedEnvelope.code())
+}
+
+#[test]
+fn session_from_prekey() {
+    let dir    = TempDir::new("daemon-prekey");
+    let socket = serve(dir.path());
+    let alice  = open(dir.path(), "alice");
+    let mut c  = Client::connect(&socket).unwrap();
+
+    let bundle = alice.new_prekey(PreKeyId::new(1)).unwrap();
+    c.session_from_prekey("alice", &bundle.serialise().unwrap()).unwrap();
+    let msg = c.encrypt("alice", b"hello").unwrap();
+    let (_, plain) = alice.session_from_message("bob", &msg).unwrap();
+    assert_eq!(plain, b"hello");
+    assert!(c.session_from_prekey("carol", b"not a bundle").is_err())
+}
+
+#[test]
+fn bind() {
+    let dir    = TempDir::new("daemon-bind");
+    let socket = serve(dir.path());
+    let mode   = fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    match server::bind(&socket) {
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => (),
+        other => panic!("{:?}", other.map(|_| ()))
+    }
+
+    // A socket file without a listener is stale and replaced.
+    let stale = dir.path().join("stale.sock");
+    drop(UnixListener::bind(&stale).unwrap());
+    assert!(stale.exists());
+    server::bind(&stale).unwrap();
+}
+
+// Protocol /////////////////////////////////////////////////////////////////
+
+#[test]
+fn messages() {
+    let requests = vec![
+        Request::Fingerprint,
+        Request::NewPreKey(7),
+        Request::SessionFromPreKey("s".to_string(), vec![1, 2]),
+        Request::Encrypt("s".to_string(), vec![]),
+        Request::Decrypt("s".to_string(), vec![3]),
+        Request::SessionDelete("s".to_string()),
+        Request::RemoteFingerprint("s".to_string())
+    ];
+    for r in requests {
+        assert_eq!(Request::deserialise(&r.serialise().unwrap()).unwrap(), r)
+    }
+    let responses = vec![
+        Response::Done,
+        Response::Bytes(vec![1]),
+        Response::Text("t".to_string()),
+        Response::Error(2, "e".to_string())
+    ];
+    for r in responses {
+        assert_eq!(Response::deserialise(&r.serialise().unwrap()).unwrap(), r)
+    }
+    assert!(Request::deserialise(&[]).is_err());
+    assert!(Response::deserialise(&Request::Fingerprint.serialise().unwrap()[.. 1]).is_err())
+}
+
+#[test]
+fn frames() {
+    let mut b = Vec::new();
+    protocol::write_frame(&mut b, b"one").unwrap();
+    protocol::write_frame(&mut b, b"").unwrap();
+    assert_eq!(&b[.. 4], &[0, 0, 0, 3]);
+
+    let mut r = Cursor::new(&b[..]);
+    assert_eq!(protocol::read_frame(&mut r).unwrap(), Some(b"one".to_vec()));
+    assert_eq!(protocol::read_frame(&mut r).unwrap(), Some(vec![]));
+    assert_eq!(protocol::read_frame(&mut r).unwrap(), None);
+
+    for n in 1 .. b.len() - 4 {
+        let mut r = Cursor::new(&b[.. n]);
+        assert!(protocol::read_frame(&mut r).is_err(), "{}", n)
+    }
+
+    let big = ((MAX_FRAME + 1) as u32).to_be_bytes();
+    assert_eq!(protocol::read_frame(&mut Cursor::new(&big[..])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert!(protocol::write_frame(&mut Vec::new(), &vec![0; MAX_FRAME + 1]).is_err())
+}
@@ -0,0 +1,184 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Classification of errors.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::{TempDir, open};
+use cryptobox::{CBox, CBoxAnyError, CBoxError, CBoxSession, DecryptError, ErrorKind};
+use cryptobox::store::Store;
+use cryptobox::store::file::{FileStore, FileStoreError};
+use cryptobox::store::stats::StatsStore;
+use proteus::keys::PreKeyId;
+use std::collections::HashSet;
+use std::fs;
+use std::thread;
+
+// The first message of a new session from `from` to `to`, using prekey
+// `prekey` of `to`.
+fn first_message(from: &CBox<FileStore>, to: &CBox<FileStore>, sid: &str, prekey: u16) -> Vec<u8> {
+    let bundle = to.new_prekey(PreKeyId::new(prekey)).unwrap();
+    let mut s  = from.session_from_prekey(sid, &bundle.serialise().unwrap()).unwrap();
+    let msg    = s.encrypt(b"hello").unwrap();
+    from.session_save(&mut s).unwrap();
+    msg
+}
+
+fn check<A>(r: Result<A, DecryptError<FileStore>>, sid: &str, kind: ErrorKind) {
+    match r {
+        Err(e) => {
+            assert_eq!(e.kind(), kind);
+            assert_eq!(e.error().kind(), kind);
+            assert_eq!(e.session_id(), sid);
+            let msg = format!("{}", e);
+            assert!(msg.contains(sid) && msg.contains(&format!("{}", kind)), "{}", msg)
+        }
+        Ok(_) => panic!("expected {:?}", kind)
+    }
+}
+
+#[test]
+fn decrypt_errors() {
+    let dir   = TempDir::new("errors");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    let carol = open(&dir, "carol");
+
+    let m1 = first_message(&alice, &bob, "bob", 1);
+    let (mut b, _) = bob.session_from_message("alice", &m1).unwrap();
+    bob.session_save(&mut b).unwrap();
+
+    let mut a = alice.session_load("bob").unwrap().unwrap();
+    let m2 = a.encrypt(b"again").unwrap();
+    alice.session_save(&mut a).unwrap();
+    assert_eq!(b.decrypt(&m2).unwrap(), b"again");
+    check(b.decrypt(&m2), "alice", ErrorKind::DuplicateMessage);
+    check(b.decrypt(b"garbage"), "alice", ErrorKind::MalformedEnvelope);
+
+    // Carol's first message arrives on alice's session.
+    let c1 = first_message(&carol, &bob, "bob", 2);
+    check(b.decrypt(&c1), "alice", ErrorKind::RemoteIdentityChanged);
+
+    // Prekey 1 has been consumed by alice's session.
+    check(bob.session_from_message("alice2", &m1), "alice2", ErrorKind::UnknownPreKey);
+    check(bob.session_from_message("alice2", b""), "alice2", ErrorKind::MalformedEnvelope);
+
+    // The session still works after all these failures.
+    let m3 = a.encrypt(b"still there").unwrap();
+    assert_eq!(b.decrypt(&m3).unwrap(), b"still there")
+}
+
+#[test]
+fn conflict() {
+    let dir   = TempDir::new("errors-conflict");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    first_message(&alice, &bob, "bob", 1);
+
+    let alice2 = CBox::file_open(&dir.path().join("alice")).unwrap();
+    let mut s1 = alice.session_load("bob").unwrap().unwrap();
+    let mut s2 = alice2.session_load("bob").unwrap().unwrap();
+    s1.encrypt(b"one").unwrap();
+    s2.encrypt(b"two").unwrap();
+    alice.session_save(&mut s1).unwrap();
+    match alice2.session_save(&mut s2) {
+        Err(e @ CBoxError::ConflictError) => assert_eq!(e.kind(), ErrorKind::Conflict),
+        other                             => panic!("{:?}", other)
+    }
+}
+
+// Codes must never change.
+#[test]
+fn codes() {
+    let kinds = [
+        (ErrorKind::StorageFailure,        1),
+        (ErrorKind::MalformedEnvelope,     3),
+        (ErrorKind::RemoteIdentityChanged, 4),
+        (ErrorKind::InvalidSignature,      5),
+        (ErrorKind::CorruptedSession,      6),
+        (ErrorKind::DuplicateMessage,      7),
+        (ErrorKind::TooDistantFuture,      8),
+        (ErrorKind::OutdatedMessage,       9),
+        (ErrorKind::EncodeFailure,         12),
+        (ErrorKind::IdentityMismatch,      13),
+        (ErrorKind::UnknownPreKey,         14),
+        (ErrorKind::InitFailure,           16),
+        (ErrorKind::Conflict,              18),
+        (ErrorKind::Other,                 255)
+    ];
+    let mut names = HashSet::new();
+    for &(k, c) in &kinds {
+        assert_eq!(k.code(), c);
+        assert!(names.insert(format!("{}", k)))
+    }
+}
+
+// Errors of boxes over different stores ////////////////////////////////////
+
+fn is_send_sync<T: Send + Sync + 'static>(_: &T) {}
+
+// Application code using one error type for every backend.
+fn decrypt_any<S: Store>(s: &mut CBoxSession<S>, msg: &[u8]) -> Result<Vec<u8>, CBoxAnyError>
+    where S::Error: Send + Sync + 'static
+{
+    Ok(try!(s.decrypt(msg)))
+}
+
+#[test]
+fn any_error() {
+    let dir   = TempDir::new("errors-any");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    let m1    = first_message(&alice, &bob, "bob", 1);
+    let (mut b, _) = bob.session_from_message("alice", &m1).unwrap();
+    let mut a = alice.session_load("bob").unwrap().unwrap();
+    let m2    = a.encrypt(b"again").unwrap();
+    assert_eq!(decrypt_any(&mut b, &m2).unwrap(), b"again");
+
+    // A box over another store type, with a truncated session.
+    let root  = dir.path().join("carol");
+    fs::create_dir_all(&root).unwrap();
+    let carol = CBox::from_store(StatsStore::new(FileStore::new(&root).unwrap())).unwrap();
+    fs::write(root.join("sessions").join("broken"), b"abc").unwrap();
+
+    let mut errors: Vec<CBoxAnyError> = Vec::new();
+    errors.push(decrypt_any(&mut b, &m2).unwrap_err());
+    errors.push(CBoxAnyError::from(carol.session_load("broken").err().unwrap()));
+    errors.push(CBoxAnyError::from(CBoxError::<FileStore>::ConflictError));
+    errors.push(CBoxAnyError::from(bob.derive_key("k", 0).unwrap_err()));
+
+    let kinds: Vec<ErrorKind> = errors.iter().map(|e| e.kind()).collect();
+    assert_eq!(kinds, vec![ErrorKind::DuplicateMessage, ErrorKind::StorageFailure, ErrorKind::Conflict, ErrorKind::Other]);
+    assert_eq!(errors[0].session_id(), Some("alice"));
+    assert!(errors[1..].iter().all(|e| e.session_id().is_none()));
+    match errors[1] {
+        CBoxAnyError::StorageError(ref e) => assert!(e.downcast_ref::<FileStoreError>().is_some()),
+        ref other                         => panic!("{:?}", other)
+    }
+    match errors[3] {
+        CBoxAnyError::KeyLengthError(0) => (),
+        ref other                       => panic!("{:?}", other)
+    }
+
+    // Errors can be moved across threads.
+    is_send_sync(&errors);
+    let shown = thread::spawn(move || errors.iter().map(|e| format!("{}", e)).collect::<Vec<_>>()).join().unwrap();
+    assert!(shown[0].starts_with("DecryptError: session alice (duplicate message): CBoxError: proteus error"));
+    assert!(shown[1].starts_with("CBoxError: storage error"))
+}
@@ -0,0 +1,143 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Session garbage collection driven by a manually advanced clock.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::{DAY, ManualClock, TempDir};
+use cryptobox::{CBox, GcMode};
+use cryptobox::store::file::FileStore;
+use proteus::keys::PreKeyId;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+// Alice opens sessions to bob and carol on day 1 and only keeps using the
+// one to carol.
+fn setup(dir: &Path, clock: &ManualClock) -> CBox<FileStore> {
+    for name in &["alice", "bob", "carol"] {
+        fs::create_dir_all(dir.join(name)).unwrap()
+    }
+    let alice = CBox::file_open(&dir.join("alice")).unwrap().with_clock(clock.clone());
+    let bob   = CBox::file_open(&dir.join("bob")).unwrap();
+    let carol = CBox::file_open(&dir.join("carol")).unwrap();
+
+    clock.set(DAY);
+    for (sid, remote) in vec![("bob", &bob), ("carol", &carol)] {
+        let bundle = remote.new_prekey(PreKeyId::new(1)).unwrap();
+        let mut s = alice.session_from_prekey(sid, &bundle.serialise().unwrap()).unwrap();
+        alice.session_save(&mut s).unwrap();
+    }
+
+    clock.set(10 * DAY);
+    let mut s = alice.session_load("carol").unwrap().unwrap();
+    s.encrypt(b"hello").unwrap();
+    alice.session_save(&mut s).unwrap();
+    alice
+}
+
+#[test]
+fn dry_run() {
+    let dir   = TempDir::new("dry-run");
+    let clock = ManualClock::new(0);
+    let alice = setup(dir.path(), &clock);
+
+    clock.set(12 * DAY);
+    let r = alice.session_gc(Duration::from_secs(5 * DAY), GcMode::DryRun).unwrap();
+    assert_eq!(r.expired, vec!["bob".to_string()]);
+    assert!(r.unknown.is_empty());
+    assert!(alice.session_load("bob").unwrap().is_some());
+
+    let r = alice.session_gc(Duration::from_secs(20 * DAY), GcMode::DryRun).unwrap();
+    assert!(r.expired.is_empty())
+}
+
+#[test]
+fn delete() {
+    let dir   = TempDir::new("delete");
+    let clock = ManualClock::new(0);
+    let alice = setup(dir.path(), &clock);
+
+    clock.set(12 * DAY);
+    let r = alice.session_gc(Duration::from_secs(5 * DAY), GcMode::Delete).unwrap();
+    assert_eq!(r.expired, vec!["bob".to_string()]);
+    assert!(alice.session_load("bob").unwrap().is_none());
+    assert!(alice.session_meta("bob").unwrap().is_none());
+    assert!(alice.session_load("carol").unwrap().is_some());
+}
+
+#[test]
+fn archive() {
+    let dir   = TempDir::new("archive");
+    let clock = ManualClock::new(0);
+    let alice = setup(dir.path(), &clock);
+
+    clock.set(12 * DAY);
+    let r = alice.session_gc(Duration::from_secs(5 * DAY), GcMode::Archive).unwrap();
+    assert_eq!(r.expired, vec!["bob".to_string()]);
+    assert!(alice.session_load("bob").unwrap().is_none());
+    assert!(dir.path().join("alice/archive/sessions/bob").is_file());
+    assert!(dir.path().join("alice/archive/meta/bob").is_file());
+}
+
+// Loaded sessions are in use and left alone.
+#[test]
+fn skips_loaded() {
+    let dir   = TempDir::new("loaded");
+    let clock = ManualClock::new(0);
+    let alice = setup(dir.path(), &clock);
+
+    clock.set(12 * DAY);
+    let s = alice.session_load("bob").unwrap().unwrap();
+    let r = alice.session_gc(Duration::from_secs(5 * DAY), GcMode::Delete).unwrap();
+    assert!(r.expired.is_empty());
+    drop(s);
+    assert!(alice.session_load("bob").unwrap().is_some())
+}
+
+// Sessions without metadata are stamped on the first collection and
+// expire `max_age` afterwards.
+#[test]
+fn stamps_unknown() {
+    let dir   = TempDir::new("unknown");
+    let clock = ManualClock::new(0);
+    let alice = setup(dir.path(), &clock);
+    fs::remove_file(dir.path().join("alice/meta/bob")).unwrap();
+    let max_age = Duration::from_secs(5 * DAY);
+
+    clock.set(12 * DAY);
+    let r = alice.session_gc(max_age, GcMode::DryRun).unwrap();
+    assert_eq!(r.unknown, vec!["bob".to_string()]);
+    assert!(alice.session_meta("bob").unwrap().is_none());
+
+    let r = alice.session_gc(max_age, GcMode::Delete).unwrap();
+    assert_eq!(r.unknown, vec!["bob".to_string()]);
+    assert_eq!(alice.session_meta("bob").unwrap().unwrap().created, Some(12 * DAY));
+
+    // Carol's session expires in between.
+    clock.set(16 * DAY);
+    let r = alice.session_gc(max_age, GcMode::Delete).unwrap();
+    assert_eq!(r.expired, vec!["carol".to_string()]);
+    assert!(r.unknown.is_empty());
+
+    clock.set(18 * DAY);
+    let r = alice.session_gc(max_age, GcMode::Delete).unwrap();
+    assert_eq!(r.expired, vec!["bob".to_string()]);
+    assert!(alice.session_load("bob").unwrap().is_none())
+}
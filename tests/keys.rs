@@ -0,0 +1,113 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Sealed boxes and application keys derived from the identity.
+
+extern crate cryptobox;
+
+mod common;
+
+use common::{TempDir, open};
+use cryptobox::{CBoxError, MAX_DERIVED_KEY_LENGTH};
+
+// Sealed boxes /////////////////////////////////////////////////////////////
+
+#[test]
+fn seal_round_trip() {
+    let dir   = TempDir::new("seal");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    let bob_key = &bob.identity().public_key;
+    for plain in &[&b""[..], b"hello", &[0xaa; 4096][..]] {
+        let sealed = alice.seal(bob_key, plain).unwrap();
+        assert_eq!(&bob.open(&sealed).unwrap()[..], *plain)
+    }
+    // Every box uses a fresh ephemeral key.
+    assert!(alice.seal(bob_key, b"hello").unwrap() != alice.seal(bob_key, b"hello").unwrap())
+}
+
+#[test]
+fn seal_wrong_recipient() {
+    let dir    = TempDir::new("seal-recipient");
+    let alice  = open(&dir, "alice");
+    let bob    = open(&dir, "bob");
+    let carol  = open(&dir, "carol");
+    let sealed = alice.seal(&bob.identity().public_key, b"for bob").unwrap();
+    for b in &[&alice, &carol] {
+        match b.open(&sealed) {
+            Err(CBoxError::SealedBoxError) => (),
+            other                          => panic!("{:?}", other)
+        }
+    }
+}
+
+#[test]
+fn seal_tampered() {
+    let dir    = TempDir::new("seal-tampered");
+    let alice  = open(&dir, "alice");
+    let bob    = open(&dir, "bob");
+    let sealed = alice.seal(&bob.identity().public_key, b"hello").unwrap();
+
+    // The cipher text comes last.
+    for i in 1 .. 22 {
+        let mut t = sealed.clone();
+        let n = t.len();
+        t[n - i] ^= 1;
+        match bob.open(&t) {
+            Err(CBoxError::SealedBoxError) => (),
+            other                          => panic!("{}: {:?}", i, other)
+        }
+    }
+    match bob.open(&sealed[.. sealed.len() - 1]) {
+        Err(CBoxError::DecodeError(_)) => (),
+        other                          => panic!("{:?}", other)
+    }
+    assert_eq!(&bob.open(&sealed).unwrap()[..], b"hello")
+}
+
+// Derived keys /////////////////////////////////////////////////////////////
+
+#[test]
+fn derive_key() {
+    let dir   = TempDir::new("derive");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+
+    let k = alice.derive_key("backup", 32).unwrap();
+    assert_eq!(k.len(), 32);
+    assert_eq!(alice.derive_key("backup", 32).unwrap(), k);
+    assert_eq!(&alice.derive_key("backup", 16).unwrap()[..], &k[.. 16]);
+    assert!(alice.derive_key("backup2", 32).unwrap() != k);
+    assert!(alice.derive_key("", 32).unwrap() != k);
+    assert!(bob.derive_key("backup", 32).unwrap() != k);
+
+    // Keys survive reopening the box.
+    drop(alice);
+    assert_eq!(open(&dir, "alice").derive_key("backup", 32).unwrap(), k)
+}
+
+#[test]
+fn derive_key_length() {
+    let dir   = TempDir::new("derive-length");
+    let alice = open(&dir, "alice");
+    assert_eq!(alice.derive_key("k", 1).unwrap().len(), 1);
+    assert_eq!(alice.derive_key("k", MAX_DERIVED_KEY_LENGTH).unwrap().len(), MAX_DERIVED_KEY_LENGTH);
+    for &len in &[0, MAX_DERIVED_KEY_LENGTH + 1] {
+        match alice.derive_key("k", len) {
+            Err(CBoxError::KeyLengthError(n)) => assert_eq!(n, len),
+            other                             => panic!("{}: {:?}", len, other)
+        }
+    }
+}
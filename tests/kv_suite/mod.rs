@@ -0,0 +1,107 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Tests of `KvStore` which are run against every `KeyValue` engine.
+
+use common::{DAY, ManualClock, TempDir};
+use cryptobox::CBox;
+use cryptobox::store::{Generation, RawStore, Store};
+use cryptobox::store::file::FileStore;
+use cryptobox::store::kv::{KeyValue, KvStore};
+use proteus::keys::PreKeyId;
+use std::fs;
+use std::path::Path;
+
+// Moving a `FileStore` into a `KvStore` opened at the given path.
+pub fn import<K, F>(open: F)
+    where K: KeyValue,
+          F: FnOnce(&Path) -> KvStore<K>
+{
+    let dir       = TempDir::new("import");
+    let clock     = ManualClock::new(DAY);
+    let alice_dir = dir.path().join("alice");
+    let bob_dir   = dir.path().join("bob");
+    fs::create_dir_all(&alice_dir).unwrap();
+    fs::create_dir_all(&bob_dir).unwrap();
+    let alice = CBox::file_open(&alice_dir).unwrap().with_clock(clock.clone());
+    let bob   = CBox::file_open(&bob_dir).unwrap();
+
+    alice.new_prekey(PreKeyId::new(1)).unwrap();
+    alice.new_prekey(PreKeyId::new(2)).unwrap();
+    let bundle = bob.new_prekey(PreKeyId::new(1)).unwrap();
+    let mut a  = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    a.set_label("device", "phone");
+    let m1 = a.encrypt(b"one").unwrap();
+    alice.session_save(&mut a).unwrap();
+    let m2 = a.encrypt(b"two").unwrap();
+    alice.session_save(&mut a).unwrap();
+    drop(a);
+    let fingerprint = alice.fingerprint();
+    drop(alice);
+
+    let files = FileStore::new(&alice_dir).unwrap();
+    let store = open(&dir.path().join("alice.kv"));
+    store.import(&files).unwrap();
+    // Importing again overwrites the same entries.
+    store.import(&files).unwrap();
+
+    let (data, g) = files.load_session_data("bob").unwrap().unwrap();
+    assert_eq!(store.load_session_data("bob").unwrap(), Some((data, g)));
+    assert_eq!(store.session_ids().unwrap(), vec!["bob".to_string()]);
+    assert_eq!(store.sessions_by_identity(&bob.fingerprint()).unwrap(), vec!["bob".to_string()]);
+    assert_eq!(store.prekey_ids().unwrap(), vec![PreKeyId::new(1), PreKeyId::new(2)]);
+    assert_eq!(store.prekey_created(PreKeyId::new(2)).unwrap(), Some(DAY));
+
+    // The imported box carries on where the file store left off.
+    let alice = CBox::from_store(store).unwrap();
+    assert_eq!(alice.fingerprint(), fingerprint);
+    let mut a = alice.session_load("bob").unwrap().unwrap();
+    assert_eq!(a.label("device"), Some("phone"));
+    assert_eq!(a.meta().encrypted, 2);
+    let m3 = a.encrypt(b"three").unwrap();
+    alice.session_save(&mut a).unwrap();
+
+    let (mut b, plain) = bob.session_from_message("alice", &m1).unwrap();
+    assert_eq!(plain, b"one");
+    assert_eq!(b.decrypt(&m2).unwrap(), b"two");
+    assert_eq!(b.decrypt(&m3).unwrap(), b"three")
+}
+
+// Generations continue across removing and recreating a session, so saves
+// based on the removed session conflict with the new one.
+pub fn recreate<K, F>(open: F)
+    where K: KeyValue,
+          F: FnOnce(&Path) -> KvStore<K>
+{
+    let dir   = TempDir::new("recreate");
+    let store = open(&dir.path().join("alice.kv"));
+    let none  = Generation::none();
+
+    let g1 = store.save_session_data("bob", b"one", "00ff", none).unwrap().unwrap();
+    store.delete_session("bob").unwrap();
+    assert_eq!(store.load_session_data("bob").unwrap(), None);
+    assert!(store.session_ids().unwrap().is_empty());
+    assert_eq!(store.save_session_data("bob", b"stale", "00ff", g1).unwrap(), None);
+
+    let g2 = store.save_session_data("bob", b"two", "00ff", none).unwrap().unwrap();
+    assert!(g2 > g1);
+    assert_eq!(store.save_session_data("bob", b"stale", "00ff", g1).unwrap(), None);
+
+    store.archive_session("bob").unwrap();
+    let g3 = store.save_session_data("bob", b"three", "00ff", none).unwrap().unwrap();
+    assert!(g3 > g2);
+    assert_eq!(store.save_session_data("bob", b"stale", "00ff", g2).unwrap(), None);
+    assert_eq!(store.load_session_data("bob").unwrap(), Some((b"three".to_vec(), g3)))
+}
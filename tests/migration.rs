@@ -0,0 +1,104 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Migration of `FileStore`s written by older versions.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::TempDir;
+use cryptobox::CBox;
+use cryptobox::store::file::FileStore;
+use proteus::keys::PreKeyId;
+use std::fs;
+use std::path::Path;
+
+// Alice (in `dir/alice`) has a session with bob and one with carol.
+fn setup(dir: &Path) -> (CBox<FileStore>, CBox<FileStore>) {
+    for name in &["alice", "bob", "carol"] {
+        fs::create_dir_all(dir.join(name)).unwrap()
+    }
+    let alice = CBox::file_open(&dir.join("alice")).unwrap();
+    let bob   = CBox::file_open(&dir.join("bob")).unwrap();
+    let carol = CBox::file_open(&dir.join("carol")).unwrap();
+    for &(sid, remote) in &[("bob", &bob), ("carol", &carol)] {
+        let bundle = remote.new_prekey(PreKeyId::new(1)).unwrap();
+        let mut s = alice.session_from_prekey(sid, &bundle.serialise().unwrap()).unwrap();
+        alice.session_save(&mut s).unwrap();
+    }
+    (bob, carol)
+}
+
+// Turn the store into version 1: sessions without generation prefix and
+// neither index nor metadata.
+fn downgrade(root: &Path) {
+    for entry in fs::read_dir(root.join("sessions")).unwrap() {
+        let path = entry.unwrap().path();
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[8 ..]).unwrap()
+    }
+    fs::remove_dir_all(root.join("index")).unwrap();
+    let _ = fs::remove_dir_all(root.join("meta"));
+    fs::write(root.join("version"), &[0, 1]).unwrap()
+}
+
+// Alice's session `sid` still works with `remote`.
+fn check_session(alice: &CBox<FileStore>, remote: &CBox<FileStore>, sid: &str) {
+    let mut s = alice.session_load(sid).unwrap().unwrap();
+    let msg   = s.encrypt(b"still there").unwrap();
+    alice.session_save(&mut s).unwrap();
+    let (_, plain) = remote.session_from_message("alice", &msg).unwrap();
+    assert_eq!(&plain[..], b"still there")
+}
+
+#[test]
+fn from_v1() {
+    let dir = TempDir::new("v1");
+    let (bob, carol) = setup(dir.path());
+    let root = dir.path().join("alice");
+    downgrade(&root);
+
+    let alice = CBox::file_open(&root).unwrap();
+    assert_eq!(FileStore::new(&root).unwrap().version().unwrap(), Some(3));
+    check_session(&alice, &bob, "bob");
+    check_session(&alice, &carol, "carol");
+    assert_eq!(alice.sessions_by_identity(&bob.fingerprint()).unwrap(), vec!["bob".to_string()])
+}
+
+// A migration interrupted after prefixing some sessions is resumed
+// without prefixing those again.
+#[test]
+fn interrupted_v1() {
+    let dir = TempDir::new("v1-interrupted");
+    let (bob, carol) = setup(dir.path());
+    let root = dir.path().join("alice");
+    downgrade(&root);
+
+    let path = root.join("sessions").join("bob");
+    let mut data = vec![0, 0, 0, 0, 0, 0, 0, 1];
+    data.extend_from_slice(&fs::read(&path).unwrap());
+    fs::write(&path, &data).unwrap();
+
+    let alice = CBox::file_open(&root).unwrap();
+    check_session(&alice, &bob, "bob");
+    check_session(&alice, &carol, "carol");
+
+    // Reopening after the migration finished changes nothing.
+    drop(alice);
+    let alice = CBox::file_open(&root).unwrap();
+    check_session(&alice, &bob, "bob")
+}
@@ -0,0 +1,96 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// OTR messages from alice to several clients of several users.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::{TempDir, open};
+use cryptobox::CBox;
+use cryptobox::otr::{OtrBuilder, OtrMessage};
+use cryptobox::payload::base64_decode;
+use cryptobox::store::file::FileStore;
+use proteus::keys::PreKeyId;
+use std::collections::{BTreeMap, BTreeSet};
+
+fn cipher(msg: &OtrMessage, user: &str, client: &str) -> Vec<u8> {
+    base64_decode(&msg.recipients[user][client]).unwrap()
+}
+
+#[test]
+fn multi_recipient() {
+    let dir     = TempDir::new("otr");
+    let alice   = open(&dir, "alice");
+    let clients = [("bob", "phone"), ("bob", "desktop"), ("carol", "laptop")];
+    let boxes: Vec<CBox<FileStore>> = clients.iter().map(|&(u, c)| open(&dir, &format!("{}-{}", u, c))).collect();
+    for (&(u, c), b) in clients.iter().zip(&boxes) {
+        let bundle = b.new_prekey(PreKeyId::new(1)).unwrap();
+        let mut s  = alice.session_from_prekey(format!("{}-{}", u, c), &bundle.serialise().unwrap()).unwrap();
+        alice.session_save(&mut s).unwrap();
+    }
+
+    let builder = OtrBuilder::new(&alice)
+        .recipient("bob", "phone", "wrong")
+        .recipient("bob", "phone", "bob-phone")
+        .recipients(vec![
+            (("bob", "desktop"),  "bob-desktop"),
+            (("carol", "laptop"), "carol-laptop"),
+            (("dave", "tablet"),  "dave-tablet")
+        ]);
+    let first  = builder.encrypt(b"hello").unwrap();
+    let second = builder.encrypt(b"again").unwrap();
+
+    for m in &[&first, &second] {
+        let users: Vec<&String> = m.recipients.keys().collect();
+        assert_eq!(users, vec!["bob", "carol"]);
+        let bob: Vec<&String> = m.recipients["bob"].keys().collect();
+        assert_eq!(bob, vec!["desktop", "phone"]);
+        let mut missing = BTreeSet::new();
+        missing.insert(("dave".to_string(), "tablet".to_string()));
+        assert_eq!(m.missing, missing)
+    }
+
+    // Every client decrypts its own envelopes and no other.
+    for (&(u, c), b) in clients.iter().zip(&boxes) {
+        let (mut s, plain) = b.session_from_message("alice", &cipher(&first, u, c)).unwrap();
+        assert_eq!(plain, b"hello");
+        assert_eq!(s.decrypt(&cipher(&second, u, c)).unwrap(), b"again");
+        for &(u2, c2) in clients.iter().filter(|&&x| x != (u, c)) {
+            assert!(s.decrypt(&cipher(&second, u2, c2)).is_err())
+        }
+    }
+
+    let json = format!(r#"{{"bob":{{"desktop":"{}","phone":"{}"}},"carol":{{"laptop":"{}"}}}}"#,
+                       first.recipients["bob"]["desktop"],
+                       first.recipients["bob"]["phone"],
+                       first.recipients["carol"]["laptop"]);
+    assert_eq!(first.recipients_json(), json)
+}
+
+#[test]
+fn recipients_json() {
+    let mut clients = BTreeMap::new();
+    clients.insert("a\"b".to_string(), "Zm9v".to_string());
+    clients.insert("c\\d".to_string(), "YmFy".to_string());
+    let mut recipients = BTreeMap::new();
+    recipients.insert("\u{e9}\n".to_string(), clients);
+    recipients.insert("empty".to_string(), BTreeMap::new());
+    let msg = OtrMessage { recipients: recipients, missing: BTreeSet::new() };
+    assert_eq!(msg.recipients_json(), r#"{"empty":{},"é\n":{"a\"b":"Zm9v","c\\d":"YmFy"}}"#);
+    assert_eq!(OtrMessage { recipients: BTreeMap::new(), missing: BTreeSet::new() }.recipients_json(), "{}")
+}
@@ -0,0 +1,121 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// A box shared between threads.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::{TempDir, open};
+use cryptobox::{CBox, CBoxError, ErrorKind};
+use cryptobox::store::file::FileStore;
+use proteus::keys::PreKeyId;
+use std::thread;
+
+fn is_send_sync<T: Send + Sync>() {}
+
+// `n` messages from `from` to bob, the first of which sets up the session
+// with bob's prekey `prekey`.
+fn messages(from: &CBox<FileStore>, bob: &CBox<FileStore>, prekey: u16, n: usize) -> Vec<Vec<u8>> {
+    let bundle = bob.new_prekey(PreKeyId::new(prekey)).unwrap();
+    let mut s  = from.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    let msgs   = (0 .. n).map(|i| s.encrypt(format!("{}", i).as_bytes()).unwrap()).collect();
+    from.session_save(&mut s).unwrap();
+    msgs
+}
+
+#[test]
+fn decrypt_parallel() {
+    is_send_sync::<CBox<FileStore>>();
+    let dir = TempDir::new("parallel");
+    let bob = open(&dir, "bob");
+
+    let mut first  = Vec::new();
+    let mut halves = Vec::new();
+    for i in 0 .. 6 {
+        let sender = open(&dir, &format!("sender{}", i));
+        let mut msgs = messages(&sender, &bob, i as u16 + 1, 21);
+        let second = msgs.split_off(11);
+        let rest   = msgs.split_off(1);
+        first.push((format!("s{}", i), msgs));
+        halves.push((format!("s{}", i), rest));
+        halves.push((format!("s{}", i), second));
+    }
+
+    // The sessions are initialised from their first envelope.
+    for r in bob.decrypt_parallel(first, 4) {
+        assert_eq!(r.unwrap()[0].as_ref().unwrap(), b"0")
+    }
+
+    // The rest of every session is split into two batches, which compete
+    // for the same session.
+    let all = halves.clone();
+    let results = bob.decrypt_parallel(halves, 4);
+    assert_eq!(results.len(), all.len());
+    for (k, r) in results.iter().enumerate() {
+        let plain: Vec<String> = r.as_ref().unwrap().iter()
+            .map(|p| String::from_utf8(p.as_ref().unwrap().clone()).unwrap())
+            .collect();
+        let offset = if k % 2 == 0 { 1 } else { 11 };
+        let expected: Vec<String> = (offset .. offset + 10).map(|i| format!("{}", i)).collect();
+        assert_eq!(plain, expected)
+    }
+
+    // Both halves of every session have been saved.
+    for (sid, msgs) in all {
+        let mut s = bob.session_load(sid).unwrap().unwrap();
+        assert_eq!(s.meta().decrypted, 21);
+        for m in &msgs {
+            assert_eq!(s.decrypt(m).unwrap_err().kind(), ErrorKind::DuplicateMessage)
+        }
+    }
+}
+
+// Concurrent updates of one session are serialised and none is lost.
+#[test]
+fn session_update_contention() {
+    let dir   = TempDir::new("contention");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    messages(&alice, &bob, 1, 1);
+
+    let threads: Vec<_> = (0 .. 8).map(|_| {
+        let alice = alice.clone();
+        thread::spawn(move || {
+            for _ in 0 .. 10 {
+                let r = alice.session_update("bob", |s| s.encrypt(b"hello")).unwrap();
+                assert!(r.is_some())
+            }
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap()
+    }
+    let meta = alice.session_meta("bob").unwrap().unwrap();
+    assert_eq!(meta.encrypted, 1 + 80);
+
+    // A second box over the same directory does not share the locks and
+    // runs into conflicts instead.
+    let other = CBox::file_open(&dir.path().join("alice")).unwrap();
+    let mut s = other.session_load("bob").unwrap().unwrap();
+    alice.session_update("bob", |s| s.encrypt(b"hello")).unwrap();
+    s.encrypt(b"stale").unwrap();
+    match other.session_save(&mut s) {
+        Err(CBoxError::ConflictError) => (),
+        r                             => panic!("{:?}", r)
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Base64 and JSON encoding of backend payloads.
+
+extern crate cryptobox;
+extern crate proteus;
+
+use cryptobox::payload::*;
+use proteus::keys::{IdentityKeyPair, MAX_PREKEY_ID, PreKey, PreKeyBundle, PreKeyId};
+
+fn bundle(ident: &IdentityKeyPair, id: u16) -> PreKeyBundle {
+    PreKeyBundle::new(ident.public_key.clone(), &PreKey::new(PreKeyId::new(id)))
+}
+
+// Field names are decoded like any other string, which makes them show
+// up in the error for an unknown field.
+fn unknown_field(json: &str) -> String {
+    match prekey_from_json(json) {
+        Err(PayloadError::UnknownField(n)) => n,
+        other                              => panic!("{}: {:?}", json, other.err())
+    }
+}
+
+fn syntax_error(json: &str) {
+    match prekey_from_json(json) {
+        Err(PayloadError::Syntax(_)) => (),
+        other                        => panic!("{}: {:?}", json, other.err())
+    }
+}
+
+// Base64 ///////////////////////////////////////////////////////////////////
+
+// RFC 4648, section 10.
+const VECTORS: &'static [(&'static str, &'static str)] = &[
+    ("",       ""),
+    ("f",      "Zg=="),
+    ("fo",     "Zm8="),
+    ("foo",    "Zm9v"),
+    ("foob",   "Zm9vYg=="),
+    ("fooba",  "Zm9vYmE="),
+    ("foobar", "Zm9vYmFy")
+];
+
+#[test]
+fn base64_vectors() {
+    for &(plain, encoded) in VECTORS {
+        assert_eq!(base64_encode(plain.as_bytes()), encoded);
+        assert_eq!(base64_decode(encoded).unwrap(), plain.as_bytes())
+    }
+}
+
+#[test]
+fn base64_all_bytes() {
+    let b: Vec<u8> = (0 .. 256).map(|x| x as u8).collect();
+    for n in 0 .. b.len() {
+        assert_eq!(base64_decode(&base64_encode(&b[.. n])).unwrap(), &b[.. n])
+    }
+    assert!(base64_encode(&[0xfb, 0xff]).contains('+') && base64_encode(&[0xff, 0xff]).contains('/'))
+}
+
+#[test]
+fn base64_invalid() {
+    let invalid = [
+        "Zg",        // missing padding
+        "Zg=",       // short padding
+        "Z===",      // too much padding
+        "Zg==Zg==",  // padding before the end
+        "Zh==",      // non-zero trailing bits
+        "Zm9=",      // non-zero trailing bits
+        "Zm9v\n",    // whitespace
+        "Zm 9v",     // whitespace
+        "Zm9-",      // URL-safe alphabet
+        "Zm9_"
+    ];
+    for s in &invalid {
+        match base64_decode(s) {
+            Err(PayloadError::Base64) => (),
+            other                     => panic!("{}: {:?}", s, other)
+        }
+    }
+}
+
+// JSON /////////////////////////////////////////////////////////////////////
+
+#[test]
+fn json_escapes() {
+    assert_eq!(unknown_field(r#"{"\"\\\/\b\f\n\r\t": 1}"#), "\"\\/\u{8}\u{c}\n\r\t");
+    assert_eq!(unknown_field(r#"{"\u0041\u00e9\u20ac": 1}"#), "A\u{e9}\u{20ac}");
+    assert_eq!(unknown_field("{\"\u{e9}\u{20ac}\": 1}"), "\u{e9}\u{20ac}");
+    // An escaped name is still matched against the expected fields.
+    match prekey_from_json(r#"{"\u0069d": 1}"#) {
+        Err(PayloadError::MissingField("key")) => (),
+        other                                  => panic!("{:?}", other.err())
+    }
+}
+
+#[test]
+fn json_surrogate_pairs() {
+    assert_eq!(unknown_field(r#"{"\ud83d\ude00": 1}"#), "\u{1f600}");
+    assert_eq!(unknown_field(r#"{"\uD834\uDD1E": 1}"#), "\u{1d11e}");
+    syntax_error(r#"{"\ud83d": 1}"#);          // high surrogate alone
+    syntax_error(r#"{"\ud83dx": 1}"#);
+    syntax_error(r#"{"\ude00": 1}"#);          // low surrogate alone
+    syntax_error(r#"{"\ud83d\u0041": 1}"#);    // not followed by a low surrogate
+    syntax_error(r#"{"\ud83d\ud83d": 1}"#)
+}
+
+#[test]
+fn json_nesting() {
+    let nested = |depth: usize| {
+        format!("{{\"x\": {}0{}}}", "[".repeat(depth), "]".repeat(depth))
+    };
+    assert_eq!(unknown_field(&nested(15)), "x");
+    syntax_error(&nested(16));
+    syntax_error(&nested(10000));
+    assert_eq!(unknown_field(r#"{"x": {"y": [1, {"z": null}, true, false, "s", -1.5e3]}}"#), "x")
+}
+
+#[test]
+fn json_malformed() {
+    let malformed = [
+        "",
+        "{",
+        "}",
+        r#"{"id": 1,}"#,
+        r#"{"id": 1 "key": "x"}"#,
+        r#"{"id" 1}"#,
+        r#"{id: 1}"#,
+        r#"{'id': 1}"#,
+        r#"{"id": 01}"#,
+        r#"{"id": 1.}"#,
+        r#"{"id": .5}"#,
+        r#"{"id": 1e}"#,
+        r#"{"id": +1}"#,
+        r#"{"id": nul}"#,
+        r#"{"id": True}"#,
+        r#"{"id": [1, 2}"#,
+        r#"{"id": "x}"#,
+        r#"{"id": "\x"}"#,
+        r#"{"id": "\u00g0"}"#,
+        r#"{"id": "\u00"}"#,
+        "{\"id\": \"a\nb\"}",
+        r#"{"id": 1} {}"#,
+        r#"{"id": 1} x"#
+    ];
+    for s in &malformed {
+        syntax_error(s)
+    }
+}
+
+#[test]
+fn json_fields() {
+    match prekey_from_json(r#"{"id": 1, "id": 2}"#) {
+        Err(PayloadError::DuplicateField(ref n)) if n == "id" => (),
+        other => panic!("{:?}", other.err())
+    }
+    for id in &["-1", "1.0", "1e2", "65536", "\"1\"", "null", "true", "[]"] {
+        match prekey_from_json(&format!("{{\"id\": {}, \"key\": \"\"}}", id)) {
+            Err(PayloadError::InvalidField("id")) => (),
+            other => panic!("{}: {:?}", id, other.err())
+        }
+    }
+    match prekeys_from_json("{}") {
+        Err(PayloadError::InvalidField("prekeys")) => (),
+        other                                      => panic!("{:?}", other.err())
+    }
+}
+
+// Prekeys //////////////////////////////////////////////////////////////////
+
+#[test]
+fn prekey_round_trip() {
+    assert!(proteus::init());
+    let ident = IdentityKeyPair::new();
+    let b     = bundle(&ident, 7);
+    let json  = prekey_to_json(&b).unwrap();
+    assert_eq!(prekey_from_json(&json).unwrap().prekey_id, PreKeyId::new(7));
+    let spaced = json.replace(":", " : ").replace(",", " ,\n\t");
+    assert_eq!(prekey_from_json(&spaced).unwrap().prekey_id, PreKeyId::new(7));
+
+    let key = prekey_to_base64(&b).unwrap();
+    match prekey_from_json(&format!("{{\"id\": 8, \"key\": \"{}\"}}", key)) {
+        Err(PayloadError::IdMismatch(8, 7)) => (),
+        other                               => panic!("{:?}", other.err())
+    }
+
+    let all = vec![bundle(&ident, 1), bundle(&ident, 2)];
+    let ids: Vec<PreKeyId> = prekeys_from_json(&prekeys_to_json(&all).unwrap()).unwrap()
+        .iter().map(|b| b.prekey_id).collect();
+    assert_eq!(ids, vec![PreKeyId::new(1), PreKeyId::new(2)]);
+    match prekeys_from_json(&prekeys_to_json(&[bundle(&ident, 1), bundle(&ident, 1)]).unwrap()) {
+        Err(PayloadError::DuplicatePreKey(1)) => (),
+        other                                 => panic!("{:?}", other.err())
+    }
+}
+
+#[test]
+fn upload_round_trip() {
+    assert!(proteus::init());
+    let ident  = IdentityKeyPair::new();
+    let upload = PreKeyUpload::new(vec![bundle(&ident, 1)], bundle(&ident, MAX_PREKEY_ID.value())).unwrap();
+    let parsed = PreKeyUpload::from_json(&upload.to_json().unwrap()).unwrap();
+    assert_eq!(parsed.prekeys.len(), 1);
+    assert_eq!(parsed.last_resort.prekey_id, MAX_PREKEY_ID);
+
+    assert!(PreKeyUpload::new(vec![], bundle(&ident, 1)).is_err());
+    assert!(PreKeyUpload::new(vec![bundle(&ident, MAX_PREKEY_ID.value())], bundle(&ident, MAX_PREKEY_ID.value())).is_err())
+}
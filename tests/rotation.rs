@@ -0,0 +1,84 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Prekey rotation driven by a manually advanced clock.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::{DAY, ManualClock, TempDir};
+use cryptobox::CBox;
+use cryptobox::store::Store;
+use cryptobox::store::file::FileStore;
+use proteus::keys::{MAX_PREKEY_ID, PreKeyId};
+use std::path::Path;
+use std::time::Duration;
+
+fn prekey_ids(dir: &Path) -> Vec<u16> {
+    let mut ids: Vec<u16> = FileStore::new(dir).unwrap().prekey_ids().unwrap().iter().map(|i| i.value()).collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn rotate() {
+    let dir   = TempDir::new("rotate");
+    let clock = ManualClock::new(0);
+    let bob   = CBox::file_open(dir.path()).unwrap().with_clock(clock.clone());
+
+    clock.set(DAY);
+    bob.new_prekey(MAX_PREKEY_ID).unwrap();
+    bob.new_prekey(PreKeyId::new(1)).unwrap();
+    bob.new_prekey(PreKeyId::new(2)).unwrap();
+    clock.set(20 * DAY);
+    bob.new_prekey(PreKeyId::new(3)).unwrap();
+
+    clock.set(40 * DAY);
+    let r = bob.rotate_prekeys(Duration::from_secs(30 * DAY)).unwrap();
+    assert_eq!(r.removed, vec![PreKeyId::new(1), PreKeyId::new(2)]);
+    let new: Vec<u16> = r.new.iter().map(|b| b.prekey_id.value()).collect();
+    assert_eq!(new, vec![4, 5]);
+    assert_eq!(prekey_ids(dir.path()), vec![3, 4, 5, MAX_PREKEY_ID.value()]);
+
+    let store = FileStore::new(dir.path()).unwrap();
+    assert_eq!(store.prekey_created(PreKeyId::new(4)).unwrap(), Some(40 * DAY));
+    assert_eq!(store.prekey_created(PreKeyId::new(1)).unwrap(), None);
+
+    // Nothing is old enough any more.
+    let r = bob.rotate_prekeys(Duration::from_secs(30 * DAY)).unwrap();
+    assert!(r.new.is_empty() && r.removed.is_empty())
+}
+
+// Replacement IDs wrap around below the last resort prekey.
+#[test]
+fn wrap_around() {
+    let dir   = TempDir::new("wrap");
+    let clock = ManualClock::new(DAY);
+    let bob   = CBox::file_open(dir.path()).unwrap().with_clock(clock.clone());
+
+    let last = MAX_PREKEY_ID.value();
+    bob.new_prekey(MAX_PREKEY_ID).unwrap();
+    bob.new_prekey(PreKeyId::new(0)).unwrap();
+    bob.new_prekey(PreKeyId::new(last - 1)).unwrap();
+
+    clock.set(10 * DAY);
+    let r = bob.rotate_prekeys(Duration::from_secs(DAY)).unwrap();
+    assert_eq!(r.removed, vec![PreKeyId::new(0), PreKeyId::new(last - 1)]);
+    let new: Vec<u16> = r.new.iter().map(|b| b.prekey_id.value()).collect();
+    assert_eq!(new, vec![1, 2]);
+    assert_eq!(prekey_ids(dir.path()), vec![1, 2, last])
+}
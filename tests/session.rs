@@ -0,0 +1,273 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Session handling of `CBox` between two parties, alice and bob.
+
+extern crate cryptobox;
+extern crate proteus;
+
+mod common;
+
+use common::{TempDir, open};
+use cryptobox::{CBox, CBoxError, ErrorKind};
+use cryptobox::store::Store;
+use cryptobox::store::file::FileStore;
+use proteus::keys::PreKeyId;
+use std::fs;
+
+// Alice starts a session with bob using bob's prekey `prekey` and sends
+// `msg`, which bob receives. Both sessions are saved.
+fn establish(alice: &CBox<FileStore>, bob: &CBox<FileStore>, prekey: u16, msg: &[u8]) {
+    let bundle = bob.new_prekey(PreKeyId::new(prekey)).unwrap();
+    let mut a  = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    let cipher = a.encrypt(msg).unwrap();
+    alice.session_save(&mut a).unwrap();
+    let (mut b, plain) = bob.session_from_message("alice", &cipher).unwrap();
+    assert_eq!(&plain[..], msg);
+    bob.session_save(&mut b).unwrap()
+}
+
+// A peer which lost its session starts a new one, which replaces the
+// stored session without deleting it first.
+#[test]
+fn new_session_replaces_stored() {
+    let dir   = TempDir::new("replace");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    establish(&alice, &bob, 1, b"first");
+
+    // Another box over bob's directory, standing in for another process,
+    // holds on to the old session.
+    let bob2 = CBox::file_open(&dir.path().join("bob")).unwrap();
+    let mut old = bob2.session_load("alice").unwrap().unwrap();
+
+    alice.session_delete("bob").unwrap();
+    establish(&alice, &bob, 2, b"second");
+
+    let mut b = bob.session_load("alice").unwrap().unwrap();
+    let reply = b.encrypt(b"reply").unwrap();
+    bob.session_save(&mut b).unwrap();
+    let mut a = alice.session_load("bob").unwrap().unwrap();
+    assert_eq!(&a.decrypt(&reply).unwrap()[..], b"reply");
+
+    old.encrypt(b"stale").unwrap();
+    match bob2.session_save(&mut old) {
+        Err(CBoxError::ConflictError) => (),
+        other                         => panic!("expected conflict, got {:?}", other.err())
+    }
+}
+
+// Stores created before metadata was recorded have no `meta/` directory.
+#[test]
+fn meta_without_directory() {
+    let dir   = TempDir::new("meta-dir");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    fs::remove_dir_all(dir.path().join("bob").join("meta")).ok();
+    establish(&alice, &bob, 1, b"hello");
+    let meta = bob.session_meta("alice").unwrap().unwrap();
+    assert_eq!(meta.decrypted, 1);
+    assert!(meta.created.is_some())
+}
+
+// Messages from alice to bob over their existing session.
+fn send<M: AsRef<[u8]>>(alice: &CBox<FileStore>, msgs: &[M]) -> Vec<Vec<u8>> {
+    let mut a = alice.session_load("bob").unwrap().unwrap();
+    let out = msgs.iter().map(|m| a.encrypt(m.as_ref()).unwrap()).collect();
+    alice.session_save(&mut a).unwrap();
+    out
+}
+
+fn has_prekey(dir: &TempDir, name: &str, id: u16) -> bool {
+    FileStore::new(&dir.path().join(name)).unwrap().load_prekey(PreKeyId::new(id)).unwrap().is_some()
+}
+
+// Transactions /////////////////////////////////////////////////////////////
+
+#[test]
+fn transaction_commit() {
+    let dir   = TempDir::new("tx-commit");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    let bundle = bob.new_prekey(PreKeyId::new(1)).unwrap();
+    let mut a  = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    let m1 = a.encrypt(b"one").unwrap();
+    let m2 = a.encrypt(b"two").unwrap();
+    alice.session_save(&mut a).unwrap();
+    drop(a);
+
+    // The prekey consumed by the first message is deleted on commit.
+    let (mut b, _) = bob.session_from_message("alice", &m1).unwrap();
+    {
+        let mut tx = bob.session_transaction(&mut b).unwrap();
+        assert_eq!(tx.decrypt(&m2).unwrap(), b"two");
+        assert!(has_prekey(&dir, "bob", 1));
+        tx.commit().unwrap();
+    }
+    assert!(!has_prekey(&dir, "bob", 1));
+    assert_eq!(b.meta().decrypted, 2);
+    match b.decrypt(&m2) {
+        Err(ref e) if e.kind() == ErrorKind::DuplicateMessage => (),
+        other => panic!("{:?}", other)
+    }
+    drop(b);
+
+    let mut b = bob.session_load("alice").unwrap().unwrap();
+    assert_eq!(b.decrypt(&m2).unwrap_err().kind(), ErrorKind::DuplicateMessage);
+    let m3 = send(&alice, &[b"three"]);
+    assert_eq!(b.decrypt(&m3[0]).unwrap(), b"three")
+}
+
+#[test]
+fn transaction_rollback() {
+    let dir   = TempDir::new("tx-rollback");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    establish(&alice, &bob, 1, b"hello");
+    let m = send(&alice, &[b"one", b"two"]);
+
+    let mut b = bob.session_load("alice").unwrap().unwrap();
+    {
+        let mut tx = bob.session_transaction(&mut b).unwrap();
+        assert_eq!(tx.decrypt(&m[0]).unwrap(), b"one");
+        tx.rollback()
+    }
+    {
+        // Dropping a transaction rolls it back, too.
+        let mut tx = bob.session_transaction(&mut b).unwrap();
+        assert_eq!(tx.decrypt(&m[0]).unwrap(), b"one");
+        tx.encrypt(b"reply").unwrap();
+    }
+    assert_eq!(b.meta().decrypted, 1);
+    drop(b);
+
+    // Neither the stored nor the loaded session has advanced.
+    let mut b = bob.session_load("alice").unwrap().unwrap();
+    let mut tx = bob.session_transaction(&mut b).unwrap();
+    assert_eq!(tx.decrypt(&m[0]).unwrap(), b"one");
+    assert_eq!(tx.decrypt(&m[1]).unwrap(), b"two");
+    tx.commit().unwrap();
+    assert_eq!(b.meta().decrypted, 3)
+}
+
+// Batches //////////////////////////////////////////////////////////////////
+
+#[test]
+fn decrypt_batch() {
+    let dir   = TempDir::new("batch");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    establish(&alice, &bob, 1, b"hello");
+    let m = send(&alice, &[b"one", b"two", b"thr"]);
+
+    // Failures in between do not stop the batch.
+    let batch = vec![m[0].clone(), b"garbage".to_vec(), m[1].clone(), m[1].clone(), m[2].clone()];
+    let mut b = bob.session_load("alice").unwrap().unwrap();
+    let results = bob.session_decrypt_batch(&mut b, &batch).unwrap();
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0].as_ref().unwrap(), b"one");
+    assert_eq!(results[2].as_ref().unwrap(), b"two");
+    assert_eq!(results[4].as_ref().unwrap(), b"thr");
+    let e1 = results[1].as_ref().unwrap_err();
+    assert_eq!((e1.kind(), e1.session_id()), (ErrorKind::MalformedEnvelope, "alice"));
+    assert_eq!(results[3].as_ref().unwrap_err().kind(), ErrorKind::DuplicateMessage);
+    assert_eq!(b.meta().decrypted, 4);
+    assert!(bob.session_decrypt_batch(&mut b, &Vec::<Vec<u8>>::new()).unwrap().is_empty());
+    drop(b);
+
+    // The batch has been saved.
+    let mut b = bob.session_load("alice").unwrap().unwrap();
+    for msg in &m {
+        assert_eq!(b.decrypt(msg).unwrap_err().kind(), ErrorKind::DuplicateMessage)
+    }
+    assert_eq!(b.meta().decrypted, 4)
+}
+
+// Updates //////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+enum UpdateError {
+    Box(CBoxError<FileStore>),
+    Refused
+}
+
+impl From<CBoxError<FileStore>> for UpdateError {
+    fn from(e: CBoxError<FileStore>) -> UpdateError {
+        UpdateError::Box(e)
+    }
+}
+
+#[test]
+fn session_update() {
+    let dir   = TempDir::new("update");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    establish(&alice, &bob, 1, b"hello");
+
+    let m1 = alice.session_update("bob", |s| s.encrypt(b"one")).unwrap().unwrap();
+    assert_eq!(alice.session_meta("bob").unwrap().unwrap().encrypted, 2);
+
+    // A failing closure leaves the stored session as it was.
+    let r = alice.session_update("bob", |s| -> Result<(), UpdateError> {
+        try!(s.encrypt(b"lost"));
+        Err(UpdateError::Refused)
+    });
+    match r {
+        Err(UpdateError::Refused) => (),
+        Err(UpdateError::Box(e))  => panic!("{:?}", e),
+        Ok(_)                     => panic!("update not refused")
+    }
+    assert_eq!(alice.session_meta("bob").unwrap().unwrap().encrypted, 2);
+    let m2 = alice.session_update("bob", |s| s.encrypt(b"two")).unwrap().unwrap();
+
+    let mut b = bob.session_load("alice").unwrap().unwrap();
+    assert_eq!(b.decrypt(&m1).unwrap(), b"one");
+    assert_eq!(b.decrypt(&m2).unwrap(), b"two");
+    drop(b);
+
+    // Nothing to update.
+    let mut called = false;
+    let r = alice.session_update("carol", |_| -> Result<(), UpdateError> { called = true; Ok(()) });
+    assert!(r.unwrap().is_none());
+    assert!(!called);
+    assert!(alice.session_load("carol").unwrap().is_none())
+}
+
+// A session deleted and established again continues the generations of
+// the deleted one, so a box holding on to that can not overwrite it.
+#[test]
+fn recreated_session_conflicts() {
+    let dir   = TempDir::new("recreate");
+    let alice = open(&dir, "alice");
+    let bob   = open(&dir, "bob");
+    establish(&alice, &bob, 1, b"first");
+
+    let bob2 = CBox::file_open(&dir.path().join("bob")).unwrap();
+    let mut old = bob2.session_load("alice").unwrap().unwrap();
+
+    alice.session_delete("bob").unwrap();
+    bob.session_delete("alice").unwrap();
+    establish(&alice, &bob, 2, b"second");
+
+    old.encrypt(b"stale").unwrap();
+    match bob2.session_save(&mut old) {
+        Err(CBoxError::ConflictError) => (),
+        other                         => panic!("expected conflict, got {:?}", other.err())
+    }
+    let mut b = bob.session_load("alice").unwrap().unwrap();
+    let reply = b.encrypt(b"reply").unwrap();
+    let mut a = alice.session_load("bob").unwrap().unwrap();
+    assert_eq!(&a.decrypt(&reply).unwrap()[..], b"reply")
+}
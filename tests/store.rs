@@ -0,0 +1,139 @@
+// Copyright (C) 2015 Wire Swiss GmbH <support@wire.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Store layers stacked over a `FileStore`.
+
+extern crate cryptobox;
+extern crate proteus;
+extern crate sodiumoxide;
+
+mod common;
+
+use common::TempDir;
+use cryptobox::{CBox, Identity};
+use cryptobox::store::{RawStore, Store};
+use cryptobox::store::cache::CacheStore;
+use cryptobox::store::encrypted::{EncryptedError, EncryptedStore};
+use cryptobox::store::file::FileStore;
+use cryptobox::store::stats::StatsStore;
+use proteus::keys::{PreKey, PreKeyId};
+use sodiumoxide::crypto::secretbox;
+use std::fs;
+use std::path::Path;
+
+fn encrypted(dir: &Path, key: &secretbox::Key) -> CacheStore<EncryptedStore<StatsStore<FileStore>>> {
+    let store = StatsStore::new(FileStore::new(dir).unwrap());
+    CacheStore::new(EncryptedStore::new(store, key.clone()), 8)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+// A box over a stacked, encrypted store works like one over the plain
+// store, but nothing readable reaches the disk.
+#[test]
+fn encrypted_stack() {
+    let dir = TempDir::new("encrypted");
+    for name in &["alice", "bob"] {
+        fs::create_dir_all(dir.path().join(name)).unwrap()
+    }
+    assert!(proteus::init());
+    let key    = secretbox::gen_key();
+    let bob    = dir.path().join("bob");
+    let alice  = CBox::file_open(&dir.path().join("alice")).unwrap();
+    let bundle = {
+        let bob = CBox::from_store(encrypted(&bob, &key)).unwrap();
+        bob.new_prekey(PreKeyId::new(1)).unwrap()
+    };
+    let mut a = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    let msg   = a.encrypt(b"hello").unwrap();
+
+    let bob_box = CBox::from_store(encrypted(&bob, &key)).unwrap();
+    let (mut s, plain) = bob_box.session_from_message("alice", &msg).unwrap();
+    assert_eq!(&plain[..], b"hello");
+    bob_box.session_save(&mut s).unwrap();
+    let reply = s.encrypt(b"hi").unwrap();
+    bob_box.session_save(&mut s).unwrap();
+    assert_eq!(&a.decrypt(&reply).unwrap()[..], b"hi");
+    drop(s);
+
+    // The plain store sees only ciphertext, but still indexes sessions.
+    let plain  = FileStore::new(&bob).unwrap();
+    let opened = EncryptedStore::new(FileStore::new(&bob).unwrap(), key.clone());
+    let sealed = plain.load_session_data("alice").unwrap().unwrap().0;
+    let clear  = opened.load_session_data("alice").unwrap().unwrap().0;
+    assert!(sealed.len() > clear.len() && !contains(&sealed, &clear[.. 16]));
+    let sealed = plain.load_identity_data().unwrap().unwrap();
+    let clear  = opened.load_identity_data().unwrap().unwrap();
+    assert!(sealed.len() > clear.len() && !contains(&sealed, &clear[.. 16]));
+    assert_eq!(plain.session_identity("alice").unwrap(), Some(alice.fingerprint()));
+
+    // A different key does not decrypt anything.
+    let other = EncryptedStore::new(FileStore::new(&bob).unwrap(), secretbox::gen_key());
+    match other.load_identity() {
+        Err(EncryptedError::Decrypt) => (),
+        r                            => panic!("expected decryption failure, got {:?}", r.err())
+    }
+
+    // Reopened with the right key, bob continues the session.
+    drop(bob_box);
+    let bob_box = CBox::from_store(encrypted(&bob, &key)).unwrap();
+    let mut s   = bob_box.session_load("alice").unwrap().unwrap();
+    assert_eq!(&s.decrypt(&a.encrypt(b"again").unwrap()).unwrap()[..], b"again");
+    bob_box.session_save(&mut s).unwrap()
+}
+
+// A prekey consumed through another store is gone for the cache, too.
+#[test]
+fn cache_prekeys() {
+    assert!(proteus::init());
+    let dir   = TempDir::new("cache-prekeys");
+    let cache = CacheStore::new(FileStore::new(dir.path()).unwrap(), 8);
+    cache.add_prekey(&PreKey::new(PreKeyId::new(1))).unwrap();
+    assert!(cache.load_prekey(PreKeyId::new(1)).unwrap().is_some());
+
+    FileStore::new(dir.path()).unwrap().delete_prekey(PreKeyId::new(1)).unwrap();
+    assert!(cache.load_prekey(PreKeyId::new(1)).unwrap().is_none())
+}
+
+// With capacity 0 every session load goes to the inner store.
+#[test]
+fn cache_capacity() {
+    let dir = TempDir::new("cache-capacity");
+    for name in &["alice", "bob"] {
+        fs::create_dir_all(dir.path().join(name)).unwrap()
+    }
+    let alice  = CBox::file_open(&dir.path().join("alice")).unwrap();
+    let bob    = CBox::file_open(&dir.path().join("bob")).unwrap();
+    let bundle = bob.new_prekey(PreKeyId::new(1)).unwrap();
+    let mut a  = alice.session_from_prekey("bob", &bundle.serialise().unwrap()).unwrap();
+    let msg    = a.encrypt(b"hello").unwrap();
+    let (mut b, _) = bob.session_from_message("alice", &msg).unwrap();
+    bob.session_save(&mut b).unwrap();
+    drop(b);
+
+    for &(capacity, inner_loads) in &[(0, 3), (1, 1)] {
+        let cache = CacheStore::new(StatsStore::new(FileStore::new(&dir.path().join("bob")).unwrap()), capacity);
+        let ident = match cache.load_identity().unwrap() {
+            Some(Identity::Sec(i)) => i.into_owned(),
+            _                      => panic!("no identity")
+        };
+        for _ in 0 .. 3 {
+            assert!(cache.load_session(&ident, "alice").unwrap().is_some())
+        }
+        assert_eq!(cache.inner().stats().session_loads, inner_loads)
+    }
+}